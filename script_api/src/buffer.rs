@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+use std::alloc::{alloc, dealloc, Layout};
+
+use crate::FrameError;
+
+///Where a block of bytes the script allocated is in its memory, the runner reads and writes these directly
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GuestBuffer {
+    pub pointer: u32,
+    pub length: u32,
+}
+
+impl GuestBuffer {
+    pub const EMPTY: GuestBuffer = GuestBuffer {
+        pointer: 0,
+        length: 0,
+    };
+
+    ///Copies bytes into a new allocation, failing if the allocator has no room for them
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self, FrameError> {
+        if bytes.is_empty() {
+            return Ok(Self::EMPTY);
+        }
+        let pointer = allocate_buffer(bytes.len() as u32);
+        if pointer == 0 {
+            return Err(FrameError::AllocationFailed(bytes.len()));
+        }
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), pointer as *mut u8, bytes.len()) };
+        Ok(Self {
+            pointer,
+            length: bytes.len() as u32,
+        })
+    }
+
+    pub(crate) fn as_slice(&self) -> &[u8] {
+        if self.pointer == 0 {
+            return &[];
+        }
+        unsafe { std::slice::from_raw_parts(self.pointer as *const u8, self.length as usize) }
+    }
+
+    ///Frees the allocation, leaving the buffer empty
+    pub(crate) fn free(&mut self) {
+        free_buffer(self.pointer, self.length);
+        *self = Self::EMPTY;
+    }
+}
+
+///Allocates bytes for the runner to write into, returns 0 for an empty buffer
+///
+///The memory isn't zeroed since the runner overwrites all of it
+#[no_mangle]
+pub fn allocate_buffer(size: u32) -> u32 {
+    if size == 0 {
+        return 0;
+    }
+    let layout = Layout::array::<u8>(size as usize).unwrap();
+    unsafe { alloc(layout) as u32 }
+}
+
+///Frees bytes from allocate_buffer, the size has to be the same one they were allocated with
+#[no_mangle]
+pub fn free_buffer(pointer: u32, size: u32) {
+    if pointer == 0 || size == 0 {
+        return;
+    }
+    let layout = Layout::array::<u8>(size as usize).unwrap();
+    unsafe { dealloc(pointer as *mut u8, layout) };
+}
@@ -1,6 +1,9 @@
+use crate::GuestBuffer;
+
+static mut TEXT: Vec<u8> = Vec::new();
+///Where TEXT is in memory so the runner can read it, kept up to date on every write
 #[no_mangle]
-static mut TEXT_BUFFER: [u8; 2048] = [0; 2048];
-static mut TEXT_SIZE: u32 = 0;
+static mut TEXT_BUFFER: GuestBuffer = GuestBuffer::EMPTY;
 
 ///Writes text to the text buffer to be output (hopefully) onto a screen
 #[macro_export]
@@ -13,25 +16,26 @@ macro_rules! debug {
 #[no_mangle]
 fn erase_text() {
     unsafe {
-        TEXT_BUFFER = [0; 2048];
-        TEXT_SIZE = 0;
+        TEXT.clear();
+        TEXT_BUFFER.length = 0;
     };
 }
 
 #[no_mangle]
 fn get_text_size() -> i32 {
-    unsafe { TEXT_SIZE as i32 }
+    unsafe { TEXT.len() as i32 }
 }
 
 pub fn write(args: std::fmt::Arguments) {
     let mut str_buf = String::new();
     let _ = std::fmt::write(&mut str_buf, args).unwrap();
     str_buf.push('\n');
-    let starting_point = unsafe { TEXT_SIZE };
-    let str_buf = str_buf.as_bytes().to_vec();
 
-    for i in starting_point..starting_point + str_buf.len() as u32 {
-        unsafe { TEXT_BUFFER[i as usize] = str_buf[(i - starting_point) as usize] };
+    unsafe {
+        TEXT.extend_from_slice(str_buf.as_bytes());
+        TEXT_BUFFER = GuestBuffer {
+            pointer: TEXT.as_ptr() as u32,
+            length: TEXT.len() as u32,
+        };
     }
-    unsafe { TEXT_SIZE += str_buf.len() as u32 }
 }
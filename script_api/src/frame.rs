@@ -0,0 +1,245 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt;
+
+pub const FRAME_MAGIC: [u8; 4] = *b"WSPF";
+///Bumped whenever the header or payload encoding changes, frames from other versions are rejected
+pub const FRAME_VERSION: u16 = 1;
+///Everything passed through the buffers between the runner and scripts is a frame, a little endian header
+///of the magic, format version (u16), payload length (u32) and payload checksum (u32), then a bincode payload
+pub const FRAME_HEADER_SIZE: usize = 14;
+
+///Why a frame couldn't be read or written
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FrameError {
+    /// Fewer bytes than a header needs
+    TooShort(usize),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u16),
+    /// The header's payload length doesn't match the bytes after it
+    LengthMismatch {
+        declared: u32,
+        actual: usize,
+    },
+    ChecksumMismatch {
+        declared: u32,
+        actual: u32,
+    },
+    /// Payload is bigger than the u32 length field can hold
+    TooLarge(usize),
+    /// The script's allocator had no room for a buffer of this many bytes
+    AllocationFailed(usize),
+    Encode(String),
+    Decode(String),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooShort(length) => {
+                write!(f, "frame is {} bytes, shorter than its header", length)
+            }
+            FrameError::BadMagic(magic) => write!(f, "frame starts with {:?} not the magic", magic),
+            FrameError::UnsupportedVersion(version) => write!(
+                f,
+                "frame is version {} but only version {} is supported",
+                version, FRAME_VERSION
+            ),
+            FrameError::LengthMismatch { declared, actual } => write!(
+                f,
+                "frame says its payload is {} bytes but it is {}",
+                declared, actual
+            ),
+            FrameError::ChecksumMismatch { declared, actual } => write!(
+                f,
+                "frame checksum is {:#010x} but the payload's is {:#010x}",
+                declared, actual
+            ),
+            FrameError::TooLarge(length) => write!(f, "payload of {} bytes is too large", length),
+            FrameError::AllocationFailed(length) => {
+                write!(f, "failed to allocate a buffer of {} bytes", length)
+            }
+            FrameError::Encode(message) => write!(f, "failed to encode payload: {}", message),
+            FrameError::Decode(message) => write!(f, "failed to decode payload: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+///Serializes a value and puts it in a frame
+pub fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, FrameError> {
+    let payload = bincode::serialize(value).map_err(|e| FrameError::Encode(e.to_string()))?;
+    frame_payload(&payload)
+}
+
+///Checks a frame and deserializes its payload
+pub fn decode<T: DeserializeOwned>(frame: &[u8]) -> Result<T, FrameError> {
+    bincode::deserialize(payload(frame)?).map_err(|e| FrameError::Decode(e.to_string()))
+}
+
+///Puts an already serialized payload in a frame
+pub fn frame_payload(payload: &[u8]) -> Result<Vec<u8>, FrameError> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+    frame.resize(FRAME_HEADER_SIZE, 0);
+    frame.extend_from_slice(payload);
+    write_header(&mut frame)?;
+    Ok(frame)
+}
+
+///Fills in the header of a frame whose payload was written straight after room left for it
+pub fn write_header(frame: &mut [u8]) -> Result<(), FrameError> {
+    if frame.len() < FRAME_HEADER_SIZE {
+        return Err(FrameError::TooShort(frame.len()));
+    }
+    let (header, payload) = frame.split_at_mut(FRAME_HEADER_SIZE);
+    let length = u32::try_from(payload.len()).map_err(|_| FrameError::TooLarge(payload.len()))?;
+
+    header[..4].copy_from_slice(&FRAME_MAGIC);
+    header[4..6].copy_from_slice(&FRAME_VERSION.to_le_bytes());
+    header[6..10].copy_from_slice(&length.to_le_bytes());
+    header[10..].copy_from_slice(&checksum(payload).to_le_bytes());
+    Ok(())
+}
+
+///Checks a frame's header and checksum and returns the payload without deserializing it
+pub fn payload(frame: &[u8]) -> Result<&[u8], FrameError> {
+    if frame.len() < FRAME_HEADER_SIZE {
+        return Err(FrameError::TooShort(frame.len()));
+    }
+    let (header, payload) = frame.split_at(FRAME_HEADER_SIZE);
+
+    let magic = [header[0], header[1], header[2], header[3]];
+    if magic != FRAME_MAGIC {
+        return Err(FrameError::BadMagic(magic));
+    }
+    let version = u16::from_le_bytes([header[4], header[5]]);
+    if version != FRAME_VERSION {
+        return Err(FrameError::UnsupportedVersion(version));
+    }
+    let declared = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+    if declared as usize != payload.len() {
+        return Err(FrameError::LengthMismatch {
+            declared,
+            actual: payload.len(),
+        });
+    }
+    let declared = u32::from_le_bytes([header[10], header[11], header[12], header[13]]);
+    let actual = checksum(payload);
+    if declared != actual {
+        return Err(FrameError::ChecksumMismatch { declared, actual });
+    }
+    Ok(payload)
+}
+
+///Fast checksum that catches truncated or overwritten payloads, not tampering
+///
+///Works through 8 bytes at a time since scripts pay instructions for every frame they read and write
+pub fn checksum(bytes: &[u8]) -> u32 {
+    const SEED: u64 = 0x51_7c_c1_b7_27_22_0a_95;
+    let mix = |hash: u64, word: u64| (hash.rotate_left(5) ^ word).wrapping_mul(SEED);
+
+    let mut chunks = bytes.chunks_exact(8);
+    let mut hash = mix(0, bytes.len() as u64);
+    for chunk in &mut chunks {
+        let mut word = [0; 8];
+        word.copy_from_slice(chunk);
+        hash = mix(hash, u64::from_le_bytes(word));
+    }
+    for &byte in chunks.remainder() {
+        hash = mix(hash, byte as u64);
+    }
+    (hash ^ (hash >> 32)) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame() -> Vec<u8> {
+        encode(&vec![1_u32, 2, 3]).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(decode::<Vec<u32>>(&frame()).unwrap(), vec![1, 2, 3]);
+        assert_eq!(
+            decode::<Vec<u32>>(&encode(&Vec::<u32>::new()).unwrap()).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn header_written_in_place_matches() {
+        let payload = bincode::serialize(&vec![1_u32, 2, 3]).unwrap();
+        let mut in_place = vec![0; FRAME_HEADER_SIZE];
+        in_place.extend_from_slice(&payload);
+        write_header(&mut in_place).unwrap();
+        assert_eq!(in_place, frame());
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut frame = frame();
+        frame[0] = b'X';
+        assert_eq!(payload(&frame), Err(FrameError::BadMagic(*b"XSPF")));
+    }
+
+    #[test]
+    fn wrong_version() {
+        let mut frame = frame();
+        frame[4..6].copy_from_slice(&(FRAME_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            payload(&frame),
+            Err(FrameError::UnsupportedVersion(FRAME_VERSION + 1))
+        );
+    }
+
+    #[test]
+    fn truncated_payload() {
+        let frame = frame();
+        let truncated = &frame[..frame.len() - 1];
+        assert!(matches!(
+            payload(truncated),
+            Err(FrameError::LengthMismatch { declared, actual }) if declared as usize == actual + 1
+        ));
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut frame = frame();
+        frame.push(0);
+        assert!(matches!(
+            payload(&frame),
+            Err(FrameError::LengthMismatch { declared, actual }) if declared as usize + 1 == actual
+        ));
+    }
+
+    #[test]
+    fn flipped_payload_byte() {
+        let mut frame = frame();
+        frame[FRAME_HEADER_SIZE + 8] ^= 1;
+        assert!(matches!(
+            payload(&frame),
+            Err(FrameError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn shorter_than_header() {
+        let frame = frame();
+        assert_eq!(
+            payload(&frame[..FRAME_HEADER_SIZE - 1]),
+            Err(FrameError::TooShort(FRAME_HEADER_SIZE - 1))
+        );
+        assert_eq!(payload(&[]), Err(FrameError::TooShort(0)));
+        assert_eq!(write_header(&mut [0; 3]), Err(FrameError::TooShort(3)));
+    }
+
+    #[test]
+    fn payload_of_the_wrong_type() {
+        assert!(matches!(
+            decode::<Vec<String>>(&encode(&vec![7_u8]).unwrap()),
+            Err(FrameError::Decode(_))
+        ));
+    }
+}
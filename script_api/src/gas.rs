@@ -0,0 +1,14 @@
+#[cfg(target_arch = "wasm32")]
+#[link(wasm_import_module = "env")]
+extern "C" {
+    //Provided by the runner, it can only read the instruction counter
+    fn __runner_remaining_instructions() -> i32;
+}
+
+///Instructions the script has left to run this tick
+///
+///Lets long searches like iterative deepening stop on their own before the tick runs out
+#[cfg(target_arch = "wasm32")]
+pub fn remaining_instructions() -> u32 {
+    unsafe { __runner_remaining_instructions() }.max(0) as u32
+}
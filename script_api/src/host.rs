@@ -0,0 +1,25 @@
+///Declares a function the runner provides, so the script can call it like any other function
+///
+///The module defaults to "host", the same as wasm_runner::DEFAULT_HOST_MODULE, and the function has to be
+///registered on the runner with the same name and types. Only numbers can be passed, send strings and
+///buffers as a pointer and length for the host to read through its HostContext
+///
+///```ignore
+///host_function!(fn log_value(value: i32));
+///host_function!("physics", fn raycast(x: f32, y: f32) -> i32);
+///```
+#[macro_export]
+macro_rules! host_function {
+    (fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?) => {
+        $crate::host_function!("host", fn $name($($arg: $ty),*) $(-> $ret)?);
+    };
+    ($module:literal, fn $name:ident($($arg:ident: $ty:ty),* $(,)?) $(-> $ret:ty)?) => {
+        pub fn $name($($arg: $ty),*) $(-> $ret)? {
+            #[link(wasm_import_module = $module)]
+            extern "C" {
+                fn $name($($arg: $ty),*) $(-> $ret)?;
+            }
+            unsafe { $name($($arg),*) }
+        }
+    };
+}
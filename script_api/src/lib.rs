@@ -1,42 +1,133 @@
-use data::Data;
+pub use buffer::{allocate_buffer, free_buffer, GuestBuffer};
+pub use data::Data;
 pub use debug::*;
+pub use frame::FrameError;
+#[cfg(target_arch = "wasm32")]
+pub use gas::remaining_instructions;
+pub use reload::ScriptState;
 pub use script_action::ScriptAction;
+use serde::de::DeserializeOwned;
+pub use serde::{Deserialize, Serialize};
 
+mod buffer;
 mod data;
 mod debug;
+pub mod frame;
+mod gas;
+mod host;
 pub mod panic;
+pub mod preempt;
+pub mod reload;
 mod script_action;
 
-pub const MAX_INPUT_SIZE: usize = 2048;
-pub const MAX_OUTPUT_SIZE: usize = 2048;
-
 ///External VM runner cann pull data out of this buffer to figure out what the script wants to do
+///
+///Points into PENDING_OUTPUT once finish_output has framed it, the script keeps ownership of the bytes
 #[no_mangle]
-static mut SCRIPT_OUTPUT_BUFFER: [u8; MAX_OUTPUT_SIZE] = [0; MAX_OUTPUT_SIZE];
+static mut SCRIPT_OUTPUT_BUFFER: GuestBuffer = GuestBuffer::EMPTY;
+
+///Actions emitted so far, after room for the frame header so finishing the tick doesn't copy them
+static mut PENDING_OUTPUT: Vec<u8> = Vec::new();
+
+///Whether PENDING_OUTPUT was handed to the runner, the next action starts a new payload
+static mut OUTPUT_FINISHED: bool = false;
 
 ///External VM runner can put data into this buffer for the script to act on
+///
+///The runner allocates it with allocate_buffer to fit each tick's inputs and frees the last one
+#[no_mangle]
+static mut DATA_INPUT_BUFFER: GuestBuffer = GuestBuffer::EMPTY;
+
+///Why the inputs couldn't be read, the runner fails the tick with VMError::MalformedInput when it's set
+#[no_mangle]
+static mut DATA_INPUT_ERROR: GuestBuffer = GuestBuffer::EMPTY;
+
+///Why an action couldn't be written, the runner fails the tick with VMError::MalformedOutput when it's set
 #[no_mangle]
-static mut DATA_INPUT_BUFFER: [u8; MAX_INPUT_SIZE] = [0; MAX_INPUT_SIZE];
+static mut SCRIPT_OUTPUT_ERROR: GuestBuffer = GuestBuffer::EMPTY;
 
 pub type Inputs = Vec<Data>;
 pub type Outputs = Vec<ScriptAction>;
 
+///Adds an action to the output buffer for the runner to read back at the end of the tick
+///
+///The type has to serialize the same way as the runner's action type and every action in a tick has to be the same type
+pub fn emit<O: Serialize>(action: O) {
+    write_action_to_buffer(action);
+}
+
 ///Writes data to the output buffer, should NOT be used by the script directly, though that depends on your usecase
-pub(crate) fn write_action_to_buffer(action: ScriptAction) {
-    //Parse the actions already in the buffer and append the new one onto it
-    let mut actions = read_output_buffer();
-    actions.push(action);
+pub(crate) fn write_action_to_buffer<O: Serialize>(action: O) {
+    if let Err(e) = append_action(action) {
+        record_error(unsafe { &mut SCRIPT_OUTPUT_ERROR }, e);
+    }
+}
 
-    let mut encoded_actions = bincode::serialize::<Outputs>(&actions).unwrap();
+///Appends the action to the tick's payload, which is only framed once the tick is over
+fn append_action<O: Serialize>(action: O) -> Result<(), FrameError> {
+    let encoded_action =
+        bincode::serialize(&action).map_err(|e| FrameError::Encode(e.to_string()))?;
 
-    //Creates a new body with the size at the start and the actual content appended at the end
-    let mut body = (encoded_actions.len() as u32).to_le_bytes().to_vec();
-    body.append(&mut encoded_actions);
+    let pending = unsafe { &mut PENDING_OUTPUT };
+    if unsafe { std::mem::replace(&mut OUTPUT_FINISHED, false) } {
+        pending.clear();
+    }
+    //A bincode Vec is its length as a u64 followed by each element
+    if pending.is_empty() {
+        pending.resize(frame::FRAME_HEADER_SIZE + 8, 0);
+    }
+    let count_bytes = &mut pending[frame::FRAME_HEADER_SIZE..frame::FRAME_HEADER_SIZE + 8];
+    let mut count = [0; 8];
+    count.copy_from_slice(count_bytes);
+    count_bytes.copy_from_slice(&(u64::from_le_bytes(count) + 1).to_le_bytes());
+    pending.extend_from_slice(&encoded_action);
+    Ok(())
+}
 
-    //Takes the body created and loads it into the fixed buffer
-    let system_state = unsafe { &mut SCRIPT_OUTPUT_BUFFER };
-    system_state[..body.len()].copy_from_slice(&body);
+///Frames the actions emitted so far in place and points the output buffer at them
+///
+///Called by the runner after export_run returns or the script is suspended, never by the script
+#[no_mangle]
+pub fn finish_output() {
+    unsafe {
+        OUTPUT_FINISHED = true;
+        if PENDING_OUTPUT.is_empty() {
+            return;
+        }
+        match frame::write_header(&mut PENDING_OUTPUT) {
+            Ok(()) => {
+                SCRIPT_OUTPUT_BUFFER = GuestBuffer {
+                    pointer: PENDING_OUTPUT.as_ptr() as u32,
+                    length: PENDING_OUTPUT.len() as u32,
+                }
+            }
+            Err(e) => record_error(&mut SCRIPT_OUTPUT_ERROR, e),
+        }
+    }
+}
+
+///Drops the actions of a tick that failed before they were finished
+///
+///Called by the runner before every tick the script doesn't resume part way through
+#[no_mangle]
+pub fn discard_output() {
+    unsafe { PENDING_OUTPUT.clear() };
+}
+
+///Keeps the first error of the tick for the runner to report
+///
+///If even the message can't be allocated the buffer is left with its length and no pointer,
+///which the runner still treats as an error
+pub(crate) fn record_error(error_buffer: &mut GuestBuffer, error: FrameError) {
+    if error_buffer.length == 0 {
+        let message = error.to_string();
+        *error_buffer = GuestBuffer::from_bytes(message.as_bytes()).unwrap_or(GuestBuffer {
+            pointer: 0,
+            length: message.len() as u32,
+        });
+    }
 }
+
 pub fn action_one() {
     write_action_to_buffer(ScriptAction::ActionOne);
 }
@@ -49,35 +140,27 @@ pub fn action_three() {
     write_action_to_buffer(ScriptAction::ActionThree);
 }
 
-fn read_output_buffer() -> Outputs {
-    let script_output = unsafe { &mut SCRIPT_OUTPUT_BUFFER };
-    read_buffer(script_output.as_mut())
-}
-
 ///Gets all data that has been put in the script's buffer
-pub fn read_input_buffer() -> Vec<Inputs> {
-    let script_input = unsafe { &mut DATA_INPUT_BUFFER };
-    read_buffer(script_input.as_mut())
+pub fn read_input_buffer() -> Inputs {
+    read_inputs()
 }
 
-fn read_buffer<'a, T>(buffer: &'a mut [u8]) -> Vec<T>
-where
-    T: serde::de::Deserialize<'a>,
-{
-    //Split array between the size u32 and the rest of the body
-    let (size_bytes, body) = buffer.split_at(4);
-
-    //Get size
-    let mut u32_buffer: [u8; 4] = [0; 4];
-    u32_buffer.copy_from_slice(size_bytes);
-    let size = u32::from_le_bytes(u32_buffer);
+///Gets the inputs the runner passed to this tick as the script's own input type
+///
+///The type has to serialize the same way as the runner's input type. If they can't be read the script gets
+///no inputs and the runner fails the tick with VMError::MalformedInput, use try_read_inputs to handle it instead
+pub fn read_inputs<I: DeserializeOwned>() -> Vec<I> {
+    try_read_inputs().unwrap_or_else(|e| {
+        record_error(unsafe { &mut DATA_INPUT_ERROR }, e);
+        Vec::new()
+    })
+}
 
-    if size == 0 {
-        return Vec::new();
+///Gets the inputs the runner passed to this tick, or why they couldn't be read
+pub fn try_read_inputs<I: DeserializeOwned>() -> Result<Vec<I>, FrameError> {
+    let script_input = unsafe { &DATA_INPUT_BUFFER };
+    match script_input.as_slice() {
+        [] => Ok(Vec::new()),
+        bytes => frame::decode(bytes),
     }
-
-    //Find the actual content of buffer and parse
-    let content: &[u8] = &body[0..size as usize];
-
-    bincode::deserialize(content).unwrap()
 }
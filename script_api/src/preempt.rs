@@ -0,0 +1,7 @@
+pub const ASYNCIFY_BUFFER_SIZE: usize = 16384;
+
+///Scratch space the runner uses to save the script's stack when it is paused for running out of instructions
+///
+///Only used when the script is loaded as preemptible, the first 8 bytes are the asyncify header
+#[no_mangle]
+pub static mut ASYNCIFY_BUFFER: [u8; ASYNCIFY_BUFFER_SIZE] = [0; ASYNCIFY_BUFFER_SIZE];
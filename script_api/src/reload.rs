@@ -0,0 +1,82 @@
+use crate::{frame, record_error, FrameError, GuestBuffer};
+
+///State handed between the old and new version of a script when the runner reloads it
+#[no_mangle]
+static mut SCRIPT_STATE_BUFFER: GuestBuffer = GuestBuffer::EMPTY;
+
+///Why the new script couldn't load the state, the runner fails the reload with VMError::StateLoadFailed when it's set
+#[no_mangle]
+static mut SCRIPT_STATE_ERROR: GuestBuffer = GuestBuffer::EMPTY;
+
+///Lets a script keep its state when the runner reloads it with new code
+///
+///Both versions of the script have to implement it and call script_api::reload_hooks!()
+pub trait ScriptState: Sized {
+    ///Serializes whatever the new version of the script should carry over
+    fn save_state(&self) -> Vec<u8>;
+
+    ///Builds the script from what the old version saved, None fails the reload and the old version keeps running
+    fn load_state(state: &[u8]) -> Option<Self>;
+}
+
+///Exports the save_state and load_state hooks the runner calls when reloading a script
+///
+///Goes in script.rs after Script implements script_api::ScriptState
+#[macro_export]
+macro_rules! reload_hooks {
+    () => {
+        #[no_mangle]
+        pub fn save_state() {
+            if let Some(script) = unsafe { crate::SCRIPT.as_ref() } {
+                $crate::reload::store_state(&$crate::ScriptState::save_state(script));
+            }
+        }
+
+        #[no_mangle]
+        pub fn load_state() {
+            match $crate::reload::take_state() {
+                Ok(state) => unsafe {
+                    $crate::panic::install();
+                    crate::SCRIPT = $crate::ScriptState::load_state(&state);
+                    if crate::SCRIPT.is_none() {
+                        $crate::reload::state_rejected();
+                    }
+                },
+                Err(e) => $crate::reload::record_state_error(e),
+            }
+        }
+    };
+}
+
+///Puts the saved state in the state buffer for the runner to pick up
+#[doc(hidden)]
+pub fn store_state(state: &[u8]) {
+    let buffer = unsafe { &mut SCRIPT_STATE_BUFFER };
+    buffer.free();
+    if let Ok(frame) = frame::frame_payload(state) {
+        *buffer = GuestBuffer::from_bytes(&frame).unwrap_or(GuestBuffer::EMPTY);
+    }
+}
+
+///Takes the state the runner put in the state buffer, or why it isn't a valid frame
+#[doc(hidden)]
+pub fn take_state() -> Result<Vec<u8>, FrameError> {
+    let buffer = unsafe { &mut SCRIPT_STATE_BUFFER };
+    let state = frame::payload(buffer.as_slice()).map(<[u8]>::to_vec);
+    buffer.free();
+    state
+}
+
+///Tells the runner the state couldn't be loaded
+#[doc(hidden)]
+pub fn record_state_error(error: FrameError) {
+    record_error(unsafe { &mut SCRIPT_STATE_ERROR }, error);
+}
+
+///Tells the runner the script's load_state turned the state down
+#[doc(hidden)]
+pub fn state_rejected() {
+    record_state_error(FrameError::Decode(
+        "load_state returned None for the saved state".to_string(),
+    ));
+}
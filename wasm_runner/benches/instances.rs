@@ -0,0 +1,51 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use wasm_runner::{compile, ScriptTemplate, VMConfig, WasmVM};
+
+/// Script with some state so each instance has a Script of its own to set up
+const BENCH_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    seen: Vec<u64>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { seen: Vec::with_capacity(64) }
+    }
+
+    pub fn run(&mut self) {
+        self.seen.push(self.seen.len() as u64);
+        if self.seen.len() % 2 == 0 {
+            action_one();
+        }
+    }
+}
+"#;
+
+fn instance_benchmarks(c: &mut Criterion) {
+    let wasm = compile(BENCH_SCRIPT.to_string()).expect("Compile benchmark script");
+    let template = ScriptTemplate::from_wasm(&wasm, VMConfig::default()).unwrap();
+
+    let mut group = c.benchmark_group("create_vm");
+    group.sample_size(10);
+    //Rewriting and compiling the module for every VM
+    group.bench_function("from_wasm", |b| {
+        b.iter(|| <WasmVM>::from_wasm(&wasm, VMConfig::default()).unwrap())
+    });
+    //Only instantiating the already compiled module
+    group.bench_function("from_template", |b| {
+        b.iter(|| <WasmVM>::from_template(&template).unwrap())
+    });
+    //Instantiating and running the first tick, which is when Script::new runs
+    group.bench_function("from_template_first_tick", |b| {
+        b.iter(|| {
+            let mut vm: WasmVM = WasmVM::from_template(&template).unwrap();
+            vm.run_tick(Vec::default()).into_result().unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, instance_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use wasm_runner::{compile, MeteringBackend, VMConfig, WasmVM};
+
+/// Script that does a fixed amount of arithmetic and branching every tick
+const BENCH_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    total: u64,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { total: 0 }
+    }
+
+    pub fn run(&mut self) {
+        for i in 0..10_000u64 {
+            if i % 3 == 0 {
+                self.total = self.total.wrapping_add(i * 7);
+            } else {
+                self.total = self.total.wrapping_sub(i);
+            }
+        }
+        if self.total == 0 {
+            action_one();
+        }
+    }
+}
+"#;
+
+const BACKENDS: [(&str, MeteringBackend); 2] = [
+    ("injector", MeteringBackend::Injector),
+    ("middleware", MeteringBackend::Middleware),
+];
+
+fn metering_benchmarks(c: &mut Criterion) {
+    let wasm = compile(BENCH_SCRIPT.to_string()).expect("Compile benchmark script");
+
+    //Rewriting and compiling the module with each backend
+    let mut group = c.benchmark_group("load");
+    group.sample_size(10);
+    for (name, backend) in BACKENDS {
+        let config = VMConfig::default().with_metering(backend);
+        group.bench_function(name, |b| {
+            b.iter(|| <WasmVM>::from_wasm(&wasm, config.clone()).unwrap())
+        });
+    }
+    group.finish();
+
+    //Running a tick of the script with each backend
+    let mut group = c.benchmark_group("run_tick");
+    for (name, backend) in BACKENDS {
+        let config = VMConfig::default().with_metering(backend);
+        let mut vm: WasmVM = WasmVM::from_wasm(&wasm, config).unwrap();
+        group.bench_function(name, |b| {
+            b.iter(|| vm.run_tick(Vec::default()).into_result().unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, metering_benchmarks);
+criterion_main!(benches);
@@ -0,0 +1,45 @@
+#![no_main]
+//! Rewrites valid random modules with random settings, the rewrite has to accept them and produce a valid module
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use wasm_runner::{instrument, FloatDeterminism, MeteringBackend, VMConfig, VMError};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    module: wasm_smith::Module,
+    canonicalize_nans: bool,
+    coverage: bool,
+    interruptible: bool,
+    middleware: bool,
+    memory_limit_pages: Option<u16>,
+}
+
+fuzz_target!(|input: Input| {
+    let wasm = input.module.to_bytes();
+
+    let mut config = VMConfig {
+        max_memory_pages: input.memory_limit_pages.map(u32::from),
+        ..VMConfig::default()
+    }
+    .with_coverage(input.coverage)
+    .with_interrupts(input.interruptible && !input.middleware);
+    if input.canonicalize_nans {
+        config = config.with_float_determinism(FloatDeterminism::CanonicalizeNaN);
+    }
+    if input.middleware {
+        config = config.with_metering(MeteringBackend::Middleware);
+    }
+
+    let instrumented = match instrument(&wasm, &config) {
+        Ok(instrumented) => instrumented,
+        //Refusing a module is fine when it is for a reason the config asked for
+        Err(e) => match e.downcast_ref::<VMError>() {
+            Some(VMError::MemoryLimitExceeded | VMError::FeatureNotAllowed(_)) => return,
+            _ => panic!("rewrite refused a valid module: {}", e),
+        },
+    };
+    wasmer::wasmparser::Validator::new()
+        .validate_all(&instrumented)
+        .expect("rewrite produced an invalid module");
+});
@@ -0,0 +1,9 @@
+#![no_main]
+//! Feeds arbitrary bytes to the rewrite, it can refuse them but must never panic
+
+use libfuzzer_sys::fuzz_target;
+use wasm_runner::{instrument, VMConfig};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = instrument(data, &VMConfig::default());
+});
@@ -0,0 +1,224 @@
+use std::time::Duration;
+
+/// Default amount of instructions a script gets every tick, run `wasm_runner calibrate` to see what it means in time
+pub const INSTRUCTIONS_PER_TICK: i32 = 1_000_000;
+
+///Decides how many instructions a script is allowed to run each tick
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BudgetPolicy {
+    /// The budget is refilled to `per_tick` every tick, unused instructions are lost
+    Fixed { per_tick: i32 },
+    /// Unused instructions carry over to later ticks, but the balance never goes above `cap`
+    TokenBucket { per_tick: i32, cap: i32 },
+    /// A tick can overrun by up to `max_debt` instructions, the overrun is taken out of the next tick
+    Debt { per_tick: i32, max_debt: i32 },
+}
+
+impl BudgetPolicy {
+    ///Amount of instructions added every tick
+    pub fn per_tick(&self) -> i32 {
+        match *self {
+            BudgetPolicy::Fixed { per_tick }
+            | BudgetPolicy::TokenBucket { per_tick, .. }
+            | BudgetPolicy::Debt { per_tick, .. } => per_tick,
+        }
+    }
+
+    ///Most instructions a single tick can ever be allowed to run
+    pub fn max_allowance(&self) -> i64 {
+        match *self {
+            BudgetPolicy::Fixed { per_tick } => per_tick as i64,
+            BudgetPolicy::TokenBucket { cap, .. } => cap as i64,
+            BudgetPolicy::Debt { per_tick, max_debt } => per_tick as i64 + max_debt as i64,
+        }
+    }
+}
+
+impl Default for BudgetPolicy {
+    fn default() -> Self {
+        BudgetPolicy::Fixed {
+            per_tick: INSTRUCTIONS_PER_TICK,
+        }
+    }
+}
+
+///Tracks a script's instruction balance between ticks
+#[derive(Clone, Debug)]
+pub struct Budget {
+    policy: BudgetPolicy,
+    /// Instructions left over after the last tick, negative when the script is in debt
+    balance: i64,
+    /// Instructions the script was allowed to use in the current or last tick
+    allowance: i32,
+    /// Instructions the script used in the last tick
+    last_used: i32,
+}
+
+impl Budget {
+    pub fn new(policy: BudgetPolicy) -> Self {
+        Self {
+            policy,
+            balance: 0,
+            allowance: 0,
+            last_used: 0,
+        }
+    }
+
+    pub fn policy(&self) -> &BudgetPolicy {
+        &self.policy
+    }
+
+    ///Instructions carried over after the last tick, negative when the script owes instructions
+    pub fn balance(&self) -> i64 {
+        self.balance
+    }
+
+    ///Instructions the script was allowed to run in the current or last tick
+    pub fn allowance(&self) -> i32 {
+        self.allowance
+    }
+
+    ///Instructions the script used in the last tick
+    pub fn last_used(&self) -> i32 {
+        self.last_used
+    }
+
+    ///Refills the balance for a new tick and returns how many instructions the script may run
+    pub(crate) fn start_tick(&mut self) -> i32 {
+        let allowance = match self.policy {
+            BudgetPolicy::Fixed { per_tick } => {
+                self.balance = per_tick as i64;
+                self.balance
+            }
+            BudgetPolicy::TokenBucket { per_tick, cap } => {
+                self.balance = (self.balance + per_tick as i64).min(cap as i64);
+                self.balance
+            }
+            BudgetPolicy::Debt { per_tick, max_debt } => {
+                self.balance = self.balance.min(0) + per_tick as i64;
+                self.balance + max_debt as i64
+            }
+        };
+        self.allowance = allowance.clamp(0, i32::MAX as i64) as i32;
+        self.allowance
+    }
+
+    ///Puts the balance back to what it was when a snapshot was taken
+    pub(crate) fn restore_balance(&mut self, balance: i64) {
+        self.balance = balance;
+    }
+
+    ///Takes the instructions used in a tick out of the balance
+    pub(crate) fn end_tick(&mut self, used: i32) {
+        self.last_used = used;
+        self.balance -= used as i64;
+        if let BudgetPolicy::Fixed { .. } = self.policy {
+            self.balance = self.balance.max(0);
+        }
+    }
+}
+
+///Per tick limits on what a script can use besides instructions, None means no limit
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Bytes the script can add to its memory with memory.grow in one tick, rounded down to whole wasm pages
+    /// the same way as VMConfig::with_memory_limit_bytes
+    pub memory_grow_bytes: Option<u64>,
+    /// Actions the script can emit in one tick
+    pub actions: Option<usize>,
+    /// Bytes of debug text the script can write in one tick
+    pub debug_text_bytes: Option<usize>,
+    /// Calls the script can make into host functions in one tick
+    pub host_calls: Option<u32>,
+}
+
+///What a script used in its last tick, next to the limits it was held to
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TickUsage {
+    pub instructions: i32,
+    /// Instructions the script was allowed to run in the tick
+    pub instruction_allowance: i32,
+    pub memory_grow_bytes: u64,
+    pub actions: usize,
+    pub debug_text_bytes: usize,
+    pub host_calls: u32,
+    /// Wall time spent in the script's export_run, without the runner's setup and read back around it
+    pub run_time: Duration,
+    pub limits: ResourceLimits,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_refills_and_drops_leftovers() {
+        let mut budget = Budget::new(BudgetPolicy::Fixed { per_tick: 100 });
+        assert_eq!(budget.start_tick(), 100);
+        budget.end_tick(30);
+        assert_eq!(budget.balance(), 70);
+        assert_eq!(budget.last_used(), 30);
+        assert_eq!(budget.start_tick(), 100);
+        //Running over never leaves the script owing anything
+        budget.end_tick(150);
+        assert_eq!(budget.balance(), 0);
+        assert_eq!(budget.start_tick(), 100);
+    }
+
+    #[test]
+    fn token_bucket_carries_over_up_to_cap() {
+        let mut budget = Budget::new(BudgetPolicy::TokenBucket {
+            per_tick: 100,
+            cap: 250,
+        });
+        assert_eq!(budget.start_tick(), 100);
+        budget.end_tick(0);
+        assert_eq!(budget.start_tick(), 200);
+        budget.end_tick(0);
+        assert_eq!(budget.start_tick(), 250);
+        budget.end_tick(250);
+        assert_eq!(budget.balance(), 0);
+        assert_eq!(budget.start_tick(), 100);
+        assert_eq!(budget.allowance(), 100);
+    }
+
+    #[test]
+    fn token_bucket_never_allows_more_than_cap() {
+        let policy = BudgetPolicy::TokenBucket {
+            per_tick: 300,
+            cap: 200,
+        };
+        assert_eq!(policy.max_allowance(), 200);
+        let mut budget = Budget::new(policy);
+        assert_eq!(budget.start_tick(), 200);
+        budget.end_tick(0);
+        assert_eq!(budget.start_tick(), 200);
+    }
+
+    #[test]
+    fn debt_is_taken_from_the_next_tick() {
+        let mut budget = Budget::new(BudgetPolicy::Debt {
+            per_tick: 100,
+            max_debt: 50,
+        });
+        assert_eq!(budget.start_tick(), 150);
+        budget.end_tick(140);
+        assert_eq!(budget.balance(), -40);
+        //The 40 owed comes out of the next refill, leftovers don't carry over
+        assert_eq!(budget.start_tick(), 110);
+        budget.end_tick(10);
+        assert_eq!(budget.balance(), 50);
+        assert_eq!(budget.start_tick(), 150);
+    }
+
+    #[test]
+    fn allowance_is_never_negative() {
+        let mut budget = Budget::new(BudgetPolicy::Debt {
+            per_tick: 10,
+            max_debt: 0,
+        });
+        budget.start_tick();
+        budget.end_tick(1_000);
+        assert_eq!(budget.start_tick(), 0);
+    }
+}
@@ -0,0 +1,294 @@
+use std::time::Duration;
+
+use crate::{budget::BudgetPolicy, compiler::compile, vm_config::VMConfig, wasm_vm::WasmVM, Error};
+
+///A script that does the same amount of one kind of work every tick
+#[derive(Clone, Debug)]
+pub struct CalibrationScript {
+    pub name: String,
+    pub code: String,
+}
+
+impl CalibrationScript {
+    pub fn new(name: &str, code: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            code: code.to_string(),
+        }
+    }
+}
+
+///How fast one calibration script ran
+#[derive(Clone, Debug)]
+pub struct MixResult {
+    pub name: String,
+    /// Instructions counted over all measured ticks
+    pub instructions: u64,
+    /// Wall time the measured ticks spent running the script, not counting the runner's own work
+    pub elapsed: Duration,
+}
+
+impl MixResult {
+    pub fn instructions_per_micro(&self) -> f64 {
+        self.instructions as f64 / (self.elapsed.as_secs_f64() * 1_000_000.0).max(f64::EPSILON)
+    }
+
+    ///Instructions this mix gets through in the given time
+    pub fn budget_for(&self, tick: Duration) -> i32 {
+        let budget = self.instructions_per_micro() * tick.as_secs_f64() * 1_000_000.0;
+        budget.clamp(0.0, i32::MAX as f64) as i32
+    }
+}
+
+///Measured speed of every instruction mix on this machine
+#[derive(Clone, Debug, Default)]
+pub struct Calibration {
+    pub mixes: Vec<MixResult>,
+}
+
+impl Calibration {
+    ///Budget that keeps a tick under the target time whatever mix of instructions the script uses,
+    ///worked out from the slowest mix
+    pub fn recommended_budget(&self, tick: Duration) -> i32 {
+        self.mixes
+            .iter()
+            .map(|mix| mix.budget_for(tick))
+            .min()
+            .unwrap_or_default()
+    }
+
+    ///Recommended budget as a fixed policy, ready to go into VMConfig::budget
+    pub fn recommended_policy(&self, tick: Duration) -> BudgetPolicy {
+        BudgetPolicy::Fixed {
+            per_tick: self.recommended_budget(tick),
+        }
+    }
+}
+
+///Runs each script through a WasmVM built from the config and measures instructions per microsecond
+///
+///The config's budget is replaced with one big enough for the scripts to finish every tick.
+///The first tick of each script is not measured since it sets the script up. Only the time spent in export_run
+///counts, passing inputs, reading actions and debug text back is the same for every mix and would hide
+///the differences between them
+pub fn calibrate(
+    scripts: &[CalibrationScript],
+    ticks: u32,
+    config: VMConfig,
+) -> Result<Calibration, Error> {
+    let config = config.with_budget(BudgetPolicy::Fixed { per_tick: i32::MAX });
+    let mut mixes = vec![];
+
+    for script in scripts {
+        let wasm = compile(script.code.clone())?;
+        let mut vm: WasmVM = WasmVM::from_wasm(&wasm, config.clone())?;
+        vm.run_tick(Vec::default()).into_result()?;
+
+        let mut instructions: u64 = 0;
+        let mut elapsed = Duration::ZERO;
+        for _ in 0..ticks {
+            vm.run_tick(Vec::default()).into_result()?;
+            let usage = vm.last_tick_usage();
+            elapsed += usage.run_time;
+            instructions += usage.instructions.max(0) as u64;
+        }
+
+        mixes.push(MixResult {
+            name: script.name.clone(),
+            instructions,
+            elapsed,
+        });
+    }
+
+    Ok(Calibration { mixes })
+}
+
+///Scripts covering the kinds of work scripts usually do: integer math, memory, branches, calls and floats
+pub fn default_calibration_scripts() -> Vec<CalibrationScript> {
+    vec![
+        CalibrationScript::new("arithmetic", ARITHMETIC_SCRIPT),
+        CalibrationScript::new("memory", MEMORY_SCRIPT),
+        CalibrationScript::new("branches", BRANCHES_SCRIPT),
+        CalibrationScript::new("calls", CALLS_SCRIPT),
+        CalibrationScript::new("floats", FLOATS_SCRIPT),
+    ]
+}
+
+const ARITHMETIC_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    state: u64,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { state: 1 }
+    }
+
+    pub fn run(&mut self) {
+        let mut x = std::hint::black_box(self.state);
+        for i in 0..100_000u64 {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(i) ^ (x >> 17);
+        }
+        self.state = x;
+    }
+}
+"#;
+
+const MEMORY_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    cells: Vec<u32>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { cells: vec![0; 16384] }
+    }
+
+    pub fn run(&mut self) {
+        let len = self.cells.len();
+        let mut index = std::hint::black_box(7usize);
+        for i in 0..100_000u32 {
+            index = (index * 31 + 17) % len;
+            let value = self.cells[index];
+            self.cells[(index + 1) % len] = value.wrapping_add(i);
+        }
+    }
+}
+"#;
+
+const BRANCHES_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    state: u32,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { state: 1 }
+    }
+
+    pub fn run(&mut self) {
+        let mut x = std::hint::black_box(self.state);
+        for i in 0..100_000u32 {
+            x = match x % 7 {
+                0 => x.wrapping_add(i),
+                1 => x ^ (i << 3),
+                2 => x.rotate_left(5),
+                3 => x.wrapping_sub(i >> 1),
+                4 if i % 2 == 0 => x.wrapping_mul(3),
+                5 => !x,
+                _ => x.wrapping_add(1),
+            };
+        }
+        self.state = x;
+    }
+}
+"#;
+
+const CALLS_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    state: u64,
+}
+
+#[inline(never)]
+fn step(x: u64, i: u64) -> u64 {
+    std::hint::black_box(x.wrapping_add(i).rotate_left(7))
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { state: 1 }
+    }
+
+    pub fn run(&mut self) {
+        let mut x = self.state;
+        for i in 0..50_000u64 {
+            x = step(x, i);
+        }
+        self.state = x;
+    }
+}
+"#;
+
+const FLOATS_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    state: f64,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { state: 0.5 }
+    }
+
+    pub fn run(&mut self) {
+        let mut x = std::hint::black_box(self.state);
+        for i in 0..100_000u32 {
+            x = (x * 1.000001 + i as f64).sqrt() - x.floor() * 0.5;
+        }
+        self.state = x;
+    }
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mix(name: &str, instructions: u64, elapsed: Duration) -> MixResult {
+        MixResult {
+            name: name.to_string(),
+            instructions,
+            elapsed,
+        }
+    }
+
+    #[test]
+    fn budget_scales_with_speed() {
+        //100 instructions a microsecond
+        let mix = mix("arithmetic", 100_000, Duration::from_millis(1));
+        assert_eq!(mix.instructions_per_micro(), 100.0);
+        assert_eq!(mix.budget_for(Duration::from_millis(2)), 200_000);
+        assert_eq!(mix.budget_for(Duration::ZERO), 0);
+    }
+
+    #[test]
+    fn budget_is_clamped() {
+        let fast = mix("arithmetic", u64::MAX, Duration::from_micros(1));
+        assert_eq!(fast.budget_for(Duration::from_secs(1)), i32::MAX);
+        //Nothing measured doesn't divide by zero
+        let instant = mix("arithmetic", 1_000, Duration::ZERO);
+        assert_eq!(instant.budget_for(Duration::from_secs(1)), i32::MAX);
+    }
+
+    #[test]
+    fn slowest_mix_sets_the_budget() {
+        let calibration = Calibration {
+            mixes: vec![
+                mix("arithmetic", 100_000, Duration::from_millis(1)),
+                mix("floats", 25_000, Duration::from_millis(1)),
+                mix("calls", 50_000, Duration::from_millis(1)),
+            ],
+        };
+        let tick = Duration::from_millis(4);
+        assert_eq!(calibration.recommended_budget(tick), 100_000);
+        assert_eq!(
+            calibration.recommended_policy(tick),
+            BudgetPolicy::Fixed { per_tick: 100_000 }
+        );
+    }
+
+    #[test]
+    fn empty_calibration_recommends_nothing() {
+        let calibration = Calibration::default();
+        assert_eq!(calibration.recommended_budget(Duration::from_millis(16)), 0);
+    }
+}
@@ -4,11 +4,11 @@ use std::{fs, path::Path, process::Command};
 
 use tempdir::TempDir;
 
-use crate::{limitation_injector::rewrite, wasm_vm::VMError, Error};
+use crate::{wasm_vm::VMError, Error};
 
 /// Compiles script into webassembly
 ///
-/// This function compiles the code and then uses the limitation injector to put limits on it
+/// The output has no limits on it yet, WasmVM::from_wasm runs the limitation injector when loading it
 pub fn compile(code: String) -> Result<Vec<u8>, Error> {
     let tmp_dir = TempDir::new("wasm-compiler").unwrap();
     let tmp_path = tmp_dir.path();
@@ -37,13 +37,41 @@ pub fn compile(code: String) -> Result<Vec<u8>, Error> {
         )?)));
     }
 
-    let wasm_script = rewrite(&fs::read(
-        tmp_path.join("pkg/wasm32-unknown-unknown/release/wasm_script.wasm"),
-    )?)?;
+    let wasm_script =
+        fs::read(tmp_path.join("pkg/wasm32-unknown-unknown/release/wasm_script.wasm"))?;
 
     Ok(wasm_script)
 }
 
+/// Runs binaryen's asyncify pass so a script can be paused when it calls the out_of_gas import and resumed later
+///
+/// Needs wasm-opt from binaryen to be installed
+pub(crate) fn asyncify(wasm: &[u8]) -> Result<Vec<u8>, Error> {
+    let tmp_dir = TempDir::new("wasm-asyncify")?;
+    let input_path = tmp_dir.path().join("input.wasm");
+    let output_path = tmp_dir.path().join("output.wasm");
+    fs::write(&input_path, wasm)?;
+
+    let output = Command::new("wasm-opt")
+        .arg(&input_path)
+        .args([
+            "--all-features",
+            "--asyncify",
+            "--pass-arg=asyncify-imports@env.__runner_out_of_gas",
+            "-o",
+        ])
+        .arg(&output_path)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Box::new(VMError::VMCompileFail(String::from_utf8(
+            output.stderr,
+        )?)));
+    }
+
+    Ok(fs::read(output_path)?)
+}
+
 ///Note, you will have to add any new files or directories you make into these functions
 
 /// Creates the base directory structure in the tempdir
@@ -88,6 +116,18 @@ fn copy_api_to_tmpdir(tmp_path: &Path) -> Result<(), Error> {
         tmp_path.join("script_api/src/panic.rs"),
         include_bytes!("../../script_api/src/panic.rs"),
     )?;
+    fs::write(
+        tmp_path.join("script_api/src/preempt.rs"),
+        include_bytes!("../../script_api/src/preempt.rs"),
+    )?;
+    fs::write(
+        tmp_path.join("script_api/src/gas.rs"),
+        include_bytes!("../../script_api/src/gas.rs"),
+    )?;
+    fs::write(
+        tmp_path.join("script_api/src/host.rs"),
+        include_bytes!("../../script_api/src/host.rs"),
+    )?;
     fs::write(
         tmp_path.join("script_api/src/script_action.rs"),
         include_bytes!("../../script_api/src/script_action.rs"),
@@ -96,6 +136,18 @@ fn copy_api_to_tmpdir(tmp_path: &Path) -> Result<(), Error> {
         tmp_path.join("script_api/src/debug.rs"),
         include_bytes!("../../script_api/src/debug.rs"),
     )?;
+    fs::write(
+        tmp_path.join("script_api/src/buffer.rs"),
+        include_bytes!("../../script_api/src/buffer.rs"),
+    )?;
+    fs::write(
+        tmp_path.join("script_api/src/frame.rs"),
+        include_bytes!("../../script_api/src/frame.rs"),
+    )?;
+    fs::write(
+        tmp_path.join("script_api/src/reload.rs"),
+        include_bytes!("../../script_api/src/reload.rs"),
+    )?;
     fs::write(
         tmp_path.join("script_api/src/data.rs"),
         include_bytes!("../../script_api/src/data.rs"),
@@ -0,0 +1,384 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
+
+use gimli::{EndianSlice, LittleEndian};
+
+use crate::Error;
+
+///Where the coverage bitmap lives and which block each byte of it belongs to
+#[derive(Clone, Debug)]
+pub(crate) struct CoverageMap {
+    /// Address of the bitmap in linear memory, one byte per block
+    pub(crate) pointer: u32,
+    pub(crate) blocks: Vec<CoverageBlock>,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct CoverageBlock {
+    pub(crate) function: String,
+    /// Index of the block inside its function
+    pub(crate) block: u32,
+    /// File offset of the block's first instruction in the original module
+    pub(crate) code_offset: Option<u32>,
+}
+
+///Whether a block was run, with the source line it came from when the script has debug info
+#[derive(Clone, Debug)]
+pub struct BlockCoverage {
+    pub function: String,
+    pub block: u32,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub hit: bool,
+}
+
+///Which blocks of a script have run since coverage was last reset
+#[derive(Clone, Debug, Default)]
+pub struct CoverageReport {
+    pub blocks: Vec<BlockCoverage>,
+}
+
+impl CoverageReport {
+    ///Number of blocks that have been run
+    pub fn blocks_hit(&self) -> usize {
+        self.blocks.iter().filter(|block| block.hit).count()
+    }
+
+    ///Formats the report as an lcov tracefile
+    ///
+    ///Only blocks with a source line are included, so scripts need to be built with debug info
+    pub fn to_lcov(&self) -> String {
+        //file -> line -> hit, and file -> function -> (first line, hit)
+        let mut lines: BTreeMap<&str, BTreeMap<u32, bool>> = BTreeMap::new();
+        let mut functions: BTreeMap<&str, BTreeMap<&str, (u32, bool)>> = BTreeMap::new();
+
+        for block in &self.blocks {
+            let (Some(file), Some(line)) = (&block.file, block.line) else {
+                continue;
+            };
+            let line_hit = lines
+                .entry(file.as_str())
+                .or_default()
+                .entry(line)
+                .or_default();
+            *line_hit |= block.hit;
+
+            //The entry block is always block 0, so it decides if the function ran
+            let function = functions
+                .entry(file.as_str())
+                .or_default()
+                .entry(block.function.as_str())
+                .or_insert((line, false));
+            function.0 = function.0.min(line);
+            if block.block == 0 {
+                function.1 |= block.hit;
+            }
+        }
+
+        let mut lcov = String::new();
+        for (file, file_lines) in &lines {
+            let _ = writeln!(lcov, "TN:");
+            let _ = writeln!(lcov, "SF:{}", file);
+
+            let file_functions = functions.get(file).cloned().unwrap_or_default();
+            for (function, (line, _)) in &file_functions {
+                let _ = writeln!(lcov, "FN:{},{}", line, function);
+            }
+            for (function, (_, hit)) in &file_functions {
+                let _ = writeln!(lcov, "FNDA:{},{}", *hit as u32, function);
+            }
+            let _ = writeln!(lcov, "FNF:{}", file_functions.len());
+            let _ = writeln!(
+                lcov,
+                "FNH:{}",
+                file_functions.values().filter(|(_, hit)| *hit).count()
+            );
+
+            for (line, hit) in file_lines {
+                let _ = writeln!(lcov, "DA:{},{}", line, *hit as u32);
+            }
+            let _ = writeln!(lcov, "LF:{}", file_lines.len());
+            let _ = writeln!(
+                lcov,
+                "LH:{}",
+                file_lines.values().filter(|hit| **hit).count()
+            );
+            let _ = writeln!(lcov, "end_of_record");
+        }
+        lcov
+    }
+}
+
+///Coverage state the VM keeps for a script loaded with coverage turned on
+pub(crate) struct Coverage {
+    pub(crate) map: CoverageMap,
+    /// Source location of each block, in the same order as map.blocks
+    lines: Vec<Option<(String, u32)>>,
+}
+
+impl Coverage {
+    ///Resolves each block to a source line using the DWARF info in the original module, if it has any
+    pub(crate) fn new(map: CoverageMap, original_wasm: &[u8]) -> Self {
+        let line_table = line_table(original_wasm).unwrap_or_default();
+        let lines = map
+            .blocks
+            .iter()
+            .map(|block| {
+                let (code_start, rows) = line_table.as_ref()?;
+                let address = (block.code_offset? as u64).checked_sub(*code_start as u64)?;
+                //Last row at or before the block's address
+                let index = rows.partition_point(|(row_address, _, _)| *row_address <= address);
+                let (_, file, line) = rows.get(index.checked_sub(1)?)?;
+                Some((file.clone(), *line))
+            })
+            .collect();
+        Self { map, lines }
+    }
+
+    ///Builds a report from the bitmap read out of the script's memory
+    pub(crate) fn report(&self, bitmap: &[u8]) -> CoverageReport {
+        let blocks = self
+            .map
+            .blocks
+            .iter()
+            .zip(&self.lines)
+            .zip(bitmap)
+            .map(|((block, line), hit)| BlockCoverage {
+                function: block.function.clone(),
+                block: block.block,
+                file: line.as_ref().map(|(file, _)| file.clone()),
+                line: line.as_ref().map(|(_, line)| *line),
+                hit: *hit != 0,
+            })
+            .collect();
+        CoverageReport { blocks }
+    }
+}
+
+/// Rows of the DWARF line programs sorted by address, and where the code section's contents start
+type LineTable = (usize, Vec<(u64, String, u32)>);
+
+///Reads the DWARF line programs out of a module's custom sections
+fn line_table(wasm: &[u8]) -> Result<Option<LineTable>, Error> {
+    let (custom_sections, code_start) = parse_sections(wasm);
+    let Some(code_start) = code_start else {
+        return Ok(None);
+    };
+    if !custom_sections.contains_key(".debug_line") {
+        return Ok(None);
+    }
+
+    let dwarf = gimli::Dwarf::load(|id| {
+        let data = custom_sections.get(id.name()).copied().unwrap_or(&[]);
+        Ok::<_, gimli::Error>(EndianSlice::new(data, LittleEndian))
+    })?;
+
+    let mut rows = vec![];
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let Some(program) = unit.line_program.clone() else {
+            continue;
+        };
+        let mut program_rows = program.rows();
+        while let Some((header, row)) = program_rows.next_row()? {
+            if row.end_sequence() {
+                continue;
+            }
+            let (Some(file), Some(line)) = (row.file(header), row.line()) else {
+                continue;
+            };
+
+            let mut path = String::new();
+            if let Some(directory) = file.directory(header) {
+                path.push_str(&dwarf.attr_string(&unit, directory)?.to_string_lossy());
+                path.push('/');
+            }
+            path.push_str(
+                &dwarf
+                    .attr_string(&unit, file.path_name())?
+                    .to_string_lossy(),
+            );
+            rows.push((row.address(), path, line.get() as u32));
+        }
+    }
+    rows.sort_by_key(|(address, _, _)| *address);
+
+    Ok(Some((code_start, rows)))
+}
+
+///Finds the custom sections in a wasm binary and where the code section's contents start
+///
+///DWARF addresses in wasm are offsets from the start of the code section's contents
+fn parse_sections(wasm: &[u8]) -> (HashMap<&str, &[u8]>, Option<usize>) {
+    let mut custom_sections = HashMap::new();
+    let mut code_start = None;
+
+    //Skip the magic number and version
+    let mut position = 8;
+    while position < wasm.len() {
+        let id = wasm[position];
+        position += 1;
+        let Some((size, size_len)) = read_leb128(&wasm[position..]) else {
+            break;
+        };
+        position += size_len;
+        let Some(contents) = wasm.get(position..position + size as usize) else {
+            break;
+        };
+
+        match id {
+            0 => {
+                if let Some((name_len, name_len_size)) = read_leb128(contents) {
+                    let name_end = name_len_size + name_len as usize;
+                    if let (Some(name), Some(data)) = (
+                        contents.get(name_len_size..name_end),
+                        contents.get(name_end..),
+                    ) {
+                        if let Ok(name) = std::str::from_utf8(name) {
+                            custom_sections.insert(name, data);
+                        }
+                    }
+                }
+            }
+            10 => code_start = Some(position),
+            _ => {}
+        }
+        position += size as usize;
+    }
+
+    (custom_sections, code_start)
+}
+
+///Reads an unsigned LEB128 number, returning it and how many bytes it took up
+fn read_leb128(bytes: &[u8]) -> Option<(u32, usize)> {
+    let mut result: u32 = 0;
+    for (i, byte) in bytes.iter().take(5).enumerate() {
+        result |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((result, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(function: &str, block: u32, line: Option<(&str, u32)>, hit: bool) -> BlockCoverage {
+        BlockCoverage {
+            function: function.to_string(),
+            block,
+            file: line.map(|(file, _)| file.to_string()),
+            line: line.map(|(_, line)| line),
+            hit,
+        }
+    }
+
+    fn section(id: u8, contents: &[u8]) -> Vec<u8> {
+        let mut section = vec![id, contents.len() as u8];
+        section.extend_from_slice(contents);
+        section
+    }
+
+    fn custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut contents = vec![name.len() as u8];
+        contents.extend_from_slice(name.as_bytes());
+        contents.extend_from_slice(data);
+        section(0, &contents)
+    }
+
+    const HEADER: &[u8] = b"\0asm\x01\0\0\0";
+
+    #[test]
+    fn lcov_groups_blocks_by_file_line_and_function() {
+        let report = CoverageReport {
+            blocks: vec![
+                block("main", 0, Some(("a.rs", 3)), true),
+                block("main", 1, Some(("a.rs", 5)), false),
+                block("helper", 0, Some(("a.rs", 10)), false),
+                block("helper", 1, Some(("a.rs", 5)), true),
+                block("other", 0, Some(("b.rs", 1)), true),
+                block("no_debug_info", 0, None, true),
+            ],
+        };
+        assert_eq!(report.blocks_hit(), 4);
+
+        let expected = "\
+TN:
+SF:a.rs
+FN:5,helper
+FN:3,main
+FNDA:0,helper
+FNDA:1,main
+FNF:2
+FNH:1
+DA:3,1
+DA:5,1
+DA:10,0
+LF:3
+LH:2
+end_of_record
+TN:
+SF:b.rs
+FN:1,other
+FNDA:1,other
+FNF:1
+FNH:1
+DA:1,1
+LF:1
+LH:1
+end_of_record
+";
+        assert_eq!(report.to_lcov(), expected);
+    }
+
+    #[test]
+    fn lcov_without_lines_is_empty() {
+        let report = CoverageReport {
+            blocks: vec![block("main", 0, None, true)],
+        };
+        assert_eq!(report.to_lcov(), "");
+    }
+
+    #[test]
+    fn parse_sections_finds_custom_sections_and_code() {
+        let mut wasm = HEADER.to_vec();
+        wasm.extend(custom_section(".debug_line", &[1, 2, 3]));
+        wasm.extend(section(1, &[0]));
+        let code_start = wasm.len() + 2;
+        wasm.extend(section(10, &[0, 0, 0]));
+        wasm.extend(custom_section("name", &[]));
+
+        let (custom_sections, code) = parse_sections(&wasm);
+        assert_eq!(custom_sections.get(".debug_line"), Some(&&[1u8, 2, 3][..]));
+        assert_eq!(custom_sections.get("name"), Some(&&[0u8; 0][..]));
+        assert_eq!(custom_sections.len(), 2);
+        assert_eq!(code, Some(code_start));
+    }
+
+    #[test]
+    fn parse_sections_stops_at_truncated_sections() {
+        let mut wasm = HEADER.to_vec();
+        wasm.extend(custom_section("kept", &[7]));
+        //Says it is 100 bytes long but only 2 are there
+        wasm.extend([10, 100, 0, 0]);
+
+        let (custom_sections, code) = parse_sections(&wasm);
+        assert_eq!(custom_sections.get("kept"), Some(&&[7u8][..]));
+        assert_eq!(code, None);
+
+        assert_eq!(parse_sections(HEADER), (HashMap::new(), None));
+        assert_eq!(parse_sections(&[]), (HashMap::new(), None));
+    }
+
+    #[test]
+    fn leb128_reads_multi_byte_numbers() {
+        assert_eq!(read_leb128(&[0x05]), Some((5, 1)));
+        assert_eq!(read_leb128(&[0xe5, 0x8e, 0x26]), Some((624_485, 3)));
+        assert_eq!(read_leb128(&[0x80]), None);
+    }
+}
@@ -0,0 +1,92 @@
+#[cfg(any(feature = "cranelift", feature = "singlepass", feature = "llvm"))]
+use wasmer::CompilerConfig;
+use wasmer::Engine;
+
+use crate::{
+    metering::{self, MeteringBackend},
+    vm_config::VMConfig,
+    wasm_vm::VMError,
+    Error,
+};
+
+///Which compiler turns a script into machine code, each one needs its cargo feature turned on
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum CompilerBackend {
+    /// Compiles quickly into reasonably fast code, the default
+    Cranelift,
+    /// Compiles in a single pass, the fastest to load but the slowest to run. Good for scripts players reload often
+    Singlepass,
+    /// Slow to compile but makes the fastest code, for long running server scripts
+    Llvm,
+}
+
+impl Default for CompilerBackend {
+    ///Cranelift if it is enabled, otherwise whichever backend is
+    fn default() -> Self {
+        Self::enabled()
+            .first()
+            .copied()
+            .unwrap_or(CompilerBackend::Cranelift)
+    }
+}
+
+impl CompilerBackend {
+    ///Every backend whose feature is turned on
+    pub fn enabled() -> Vec<CompilerBackend> {
+        let mut enabled = vec![];
+        if cfg!(feature = "cranelift") {
+            enabled.push(CompilerBackend::Cranelift);
+        }
+        if cfg!(feature = "singlepass") {
+            enabled.push(CompilerBackend::Singlepass);
+        }
+        if cfg!(feature = "llvm") {
+            enabled.push(CompilerBackend::Llvm);
+        }
+        enabled
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        Self::enabled().contains(self)
+    }
+}
+
+///Creates an engine with the config's compiler, with wasmer's metering middleware on it if that is the backend
+pub(crate) fn create_engine(config: &VMConfig) -> Result<Engine, Error> {
+    #[cfg_attr(
+        not(any(feature = "cranelift", feature = "singlepass", feature = "llvm")),
+        allow(unused_variables)
+    )]
+    let middleware = (config.metering == MeteringBackend::Middleware)
+        .then(|| metering::middleware(config.budget.per_tick()));
+
+    let engine: Engine = match config.compiler {
+        #[cfg(feature = "cranelift")]
+        CompilerBackend::Cranelift => {
+            let mut compiler = wasmer::Cranelift::new();
+            if let Some(middleware) = middleware {
+                compiler.push_middleware(middleware);
+            }
+            compiler.into()
+        }
+        #[cfg(feature = "singlepass")]
+        CompilerBackend::Singlepass => {
+            let mut compiler = wasmer::Singlepass::new();
+            if let Some(middleware) = middleware {
+                compiler.push_middleware(middleware);
+            }
+            compiler.into()
+        }
+        #[cfg(feature = "llvm")]
+        CompilerBackend::Llvm => {
+            let mut compiler = wasmer::LLVM::new();
+            if let Some(middleware) = middleware {
+                compiler.push_middleware(middleware);
+            }
+            compiler.into()
+        }
+        #[allow(unreachable_patterns)]
+        backend => return Err(Box::new(VMError::CompilerNotEnabled(backend))),
+    };
+    Ok(engine)
+}
@@ -0,0 +1,129 @@
+use wasmer::wasmparser::{Validator, WasmFeatures};
+
+use crate::{wasm_vm::VMError, Error};
+
+///Which wasm proposals a script is allowed to use
+///
+///Defaults to what rustc emits for wasm32-unknown-unknown. Anything not listed here, like tail calls,
+///exceptions, memory64 or multiple memories, is always refused since the injector doesn't know how to meter it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct WasmProposals {
+    pub mutable_globals: bool,
+    pub sign_extension: bool,
+    pub saturating_float_to_int: bool,
+    pub multi_value: bool,
+    /// Also allows the table instructions, which are charged per element like bulk memory
+    pub reference_types: bool,
+    /// memory.copy, memory.fill and memory.init are charged per byte they touch
+    pub bulk_memory: bool,
+    pub simd: bool,
+    /// Atomic instructions and shared memory, memory.atomic.wait is refused since it can block forever
+    pub threads: bool,
+}
+
+impl Default for WasmProposals {
+    fn default() -> Self {
+        Self {
+            mutable_globals: true,
+            sign_extension: true,
+            saturating_float_to_int: true,
+            multi_value: true,
+            reference_types: true,
+            bulk_memory: true,
+            simd: false,
+            threads: false,
+        }
+    }
+}
+
+impl WasmProposals {
+    ///Only the original wasm 1.0 instruction set
+    pub fn mvp() -> Self {
+        Self {
+            mutable_globals: false,
+            sign_extension: false,
+            saturating_float_to_int: false,
+            multi_value: false,
+            reference_types: false,
+            bulk_memory: false,
+            simd: false,
+            threads: false,
+        }
+    }
+
+    fn to_wasm_features(self) -> WasmFeatures {
+        WasmFeatures {
+            mutable_global: self.mutable_globals,
+            sign_extension: self.sign_extension,
+            saturating_float_to_int: self.saturating_float_to_int,
+            multi_value: self.multi_value,
+            reference_types: self.reference_types,
+            bulk_memory: self.bulk_memory,
+            simd: self.simd,
+            threads: self.threads,
+            relaxed_simd: false,
+            tail_call: false,
+            multi_memory: false,
+            exceptions: false,
+            memory64: false,
+            extended_const: false,
+            component_model: false,
+            ..WasmFeatures::default()
+        }
+    }
+}
+
+///Validates the module with only the allowed proposals turned on
+pub(crate) fn check_features(wasm: &[u8], proposals: &WasmProposals) -> Result<(), Error> {
+    Validator::new_with_features(proposals.to_wasm_features())
+        .validate_all(wasm)
+        .map_err(|e| Box::new(VMError::FeatureNotAllowed(e.to_string())))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIMD_MODULE: &str = r#"(module
+        (func (param v128 v128) (result v128)
+            local.get 0
+            local.get 1
+            i32x4.add))"#;
+    const SIGN_EXTENSION_MODULE: &str = r#"(module
+        (func (param i32) (result i32)
+            local.get 0
+            i32.extend8_s))"#;
+
+    fn refusal(wat: &str, proposals: WasmProposals) -> Option<String> {
+        match check_features(&wat::parse_str(wat).unwrap(), &proposals) {
+            Err(error) => match error.downcast_ref::<VMError>() {
+                Some(VMError::FeatureNotAllowed(reason)) => Some(reason.clone()),
+                _ => panic!("expected FeatureNotAllowed, got {}", error),
+            },
+            Ok(()) => None,
+        }
+    }
+
+    #[test]
+    fn simd_is_off_by_default() {
+        let reason = refusal(SIMD_MODULE, WasmProposals::default()).unwrap();
+        assert!(reason.contains("SIMD"), "{}", reason);
+
+        let simd = WasmProposals {
+            simd: true,
+            ..WasmProposals::default()
+        };
+        assert_eq!(refusal(SIMD_MODULE, simd), None);
+    }
+
+    #[test]
+    fn mvp_refuses_later_proposals() {
+        assert_eq!(
+            refusal(SIGN_EXTENSION_MODULE, WasmProposals::default()),
+            None
+        );
+        let reason = refusal(SIGN_EXTENSION_MODULE, WasmProposals::mvp()).unwrap();
+        assert!(reason.contains("sign extension"), "{}", reason);
+    }
+}
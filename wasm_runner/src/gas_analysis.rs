@@ -0,0 +1,425 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use walrus::{ir::*, FunctionId, FunctionKind, GlobalId, LocalFunction};
+
+use crate::limitation_injector::{
+    block_cost, function_name, metering_check, METERING_INSTRUCTION_COUNT,
+};
+
+/// Name of the function the VM calls every tick
+pub const ENTRY_FUNCTION: &str = "export_run";
+
+///Estimated instruction cost of one function in the rewritten module
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionCost {
+    pub name: String,
+    /// Worst case instructions charged for one call, with every loop in it and its callees run once
+    pub cost: u64,
+    /// Instructions charged by one iteration of each loop in the function, outermost first
+    pub loop_iterations: Vec<u64>,
+    /// Instructions every call is charged no matter which branches are taken
+    pub min_cost: u64,
+    /// False when the function or something it calls loops, recurses or calls through a table,
+    /// in which case cost is only what a single pass costs
+    pub exact: bool,
+}
+
+///Static instruction costs of a script, worked out from the module after metering was added
+#[derive(Clone, Debug, Default)]
+pub struct GasReport {
+    /// Cost of every function defined in the module by name, including the runner's own
+    pub functions: BTreeMap<String, FunctionCost>,
+    /// Function name -> names of the functions it calls directly, imports included
+    pub call_graph: BTreeMap<String, BTreeSet<String>>,
+    /// Functions that call through a table, whose callees can't be known ahead of time
+    pub indirect_callers: BTreeSet<String>,
+}
+
+impl GasReport {
+    pub fn function(&self, name: &str) -> Option<&FunctionCost> {
+        self.functions.get(name)
+    }
+
+    ///Cost of the function run every tick
+    pub fn entry(&self) -> Option<&FunctionCost> {
+        self.function(ENTRY_FUNCTION)
+    }
+}
+
+///Works out the cost of every function in the module
+///
+///Blocks with a metering check cost what the check charges, anything else costs one per instruction.
+///Calls to imports are free since they run on the host
+pub(crate) fn analyze(module: &walrus::Module, gas_global: Option<GlobalId>) -> GasReport {
+    let mut analyzer = Analyzer {
+        module,
+        gas_global,
+        costs: HashMap::new(),
+        in_progress: HashSet::new(),
+        report: GasReport::default(),
+    };
+    let func_ids: Vec<_> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    for func_id in func_ids {
+        analyzer.function(func_id);
+    }
+
+    let mut report = analyzer.report;
+    for cost in analyzer.costs.into_values() {
+        report.functions.insert(cost.name.clone(), cost);
+    }
+    report
+}
+
+struct Analyzer<'a> {
+    module: &'a walrus::Module,
+    gas_global: Option<GlobalId>,
+    costs: HashMap<FunctionId, FunctionCost>,
+    /// Functions whose callees are still being worked out, calling one of them is recursion
+    in_progress: HashSet<FunctionId>,
+    report: GasReport,
+}
+
+/// What walking a function's blocks found
+struct Walk {
+    exact: bool,
+    loop_iterations: Vec<u64>,
+    callees: BTreeSet<String>,
+    calls_indirect: bool,
+}
+
+/// What a block is waiting on from the block nested in it that is being walked
+enum Pending {
+    None,
+    Block,
+    /// Index of the loop's slot in loop_iterations
+    Loop(usize),
+    /// The consequent of an if is being walked, the alternative comes next
+    Consequent(InstrSeqId),
+    /// The alternative is being walked, with what the consequent cost
+    Alternative(u64),
+}
+
+/// A block part way through being walked by seq_cost
+struct SeqFrame<'i> {
+    body: &'i [(Instr, InstrLocId)],
+    next: usize,
+    cost: u64,
+    pending: Pending,
+}
+
+impl<'a> Analyzer<'a> {
+    ///Works out the cost of the function and everything it calls
+    ///
+    ///Callees are worked out before their callers with an explicit stack, so long call chains can't overflow
+    ///the real one. A callee that is still being worked out further up is recursion and counts as nothing
+    fn function(&mut self, func_id: FunctionId) {
+        let module = self.module;
+        // (function, whether its callees have been pushed already)
+        let mut stack = vec![(func_id, false)];
+        while let Some((func_id, expanded)) = stack.pop() {
+            if self.costs.contains_key(&func_id) {
+                continue;
+            }
+            let FunctionKind::Local(func) = &module.funcs.get(func_id).kind else {
+                continue;
+            };
+            if expanded {
+                self.evaluate(func_id, func);
+                self.in_progress.remove(&func_id);
+            } else if self.in_progress.insert(func_id) {
+                stack.push((func_id, true));
+                // Reversed so the first callee is worked out first, like walking the function would
+                for callee in self.direct_callees(func).into_iter().rev() {
+                    if !self.costs.contains_key(&callee) && !self.in_progress.contains(&callee) {
+                        stack.push((callee, false));
+                    }
+                }
+            }
+        }
+    }
+
+    ///Cost of a function that has been worked out, imports are free and recursion counts as nothing
+    fn callee_cost(&self, func_id: FunctionId) -> (u64, bool) {
+        match self.costs.get(&func_id) {
+            Some(cost) => (cost.cost, cost.exact),
+            None => match self.module.funcs.get(func_id).kind {
+                FunctionKind::Local(_) => (0, false),
+                _ => (0, true),
+            },
+        }
+    }
+
+    ///Functions called directly from the function's blocks, in the order walking it meets them
+    fn direct_callees(&self, func: &LocalFunction) -> Vec<FunctionId> {
+        let mut callees = vec![];
+        let mut stack = vec![self.charge(&func.block(func.entry_block()).instrs).1.iter()];
+        while let Some(body) = stack.last_mut() {
+            let Some((instr, _)) = body.next() else {
+                stack.pop();
+                continue;
+            };
+            match instr {
+                Instr::Call(Call { func: callee }) => callees.push(*callee),
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                    stack.push(self.charge(&func.block(*seq).instrs).1.iter());
+                }
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    stack.push(self.charge(&func.block(*alternative).instrs).1.iter());
+                    stack.push(self.charge(&func.block(*consequent).instrs).1.iter());
+                }
+                _ => {}
+            }
+        }
+        callees
+    }
+
+    ///Works out the cost of a function whose callees have all been worked out or are recursion
+    fn evaluate(&mut self, func_id: FunctionId, func: &LocalFunction) {
+        let mut walk = Walk {
+            exact: true,
+            loop_iterations: vec![],
+            callees: BTreeSet::new(),
+            calls_indirect: false,
+        };
+        let cost = self.seq_cost(func, func.entry_block(), &mut walk);
+        let min_cost = self.min_seq_cost(func, func.entry_block());
+
+        let name = function_name(self.module, func_id);
+        if walk.calls_indirect {
+            self.report.indirect_callers.insert(name.clone());
+        }
+        self.report.call_graph.insert(name.clone(), walk.callees);
+        self.costs.insert(
+            func_id,
+            FunctionCost {
+                name,
+                cost,
+                loop_iterations: walk.loop_iterations,
+                min_cost,
+                exact: walk.exact,
+            },
+        );
+    }
+
+    ///Worst case cost of a block and everything nested in it, taking the dearer side of every if
+    ///
+    ///Nested blocks are walked with an explicit stack of frames, so deep nesting can't overflow the real one
+    fn seq_cost(&self, func: &LocalFunction, seq_id: InstrSeqId, walk: &mut Walk) -> u64 {
+        let frame = |seq_id: InstrSeqId| {
+            let (cost, body) = self.charge(&func.block(seq_id).instrs);
+            SeqFrame {
+                body,
+                next: 0,
+                cost,
+                pending: Pending::None,
+            }
+        };
+
+        let mut frames = vec![frame(seq_id)];
+        let mut finished: Option<u64> = None;
+        loop {
+            let top = frames.len() - 1;
+            if let Some(nested) = finished.take() {
+                let current = &mut frames[top];
+                match std::mem::replace(&mut current.pending, Pending::None) {
+                    Pending::Block => current.cost += nested,
+                    Pending::Loop(index) => {
+                        walk.loop_iterations[index] = nested;
+                        current.cost += nested;
+                    }
+                    Pending::Consequent(alternative) => {
+                        current.pending = Pending::Alternative(nested);
+                        frames.push(frame(alternative));
+                        continue;
+                    }
+                    Pending::Alternative(consequent) => current.cost += consequent.max(nested),
+                    Pending::None => {}
+                }
+            }
+
+            let current = &mut frames[top];
+            let body = current.body;
+            let Some((instr, _)) = body.get(current.next) else {
+                let done = frames.pop().unwrap();
+                if frames.is_empty() {
+                    return done.cost;
+                }
+                finished = Some(done.cost);
+                continue;
+            };
+            current.next += 1;
+            match instr {
+                Instr::Block(Block { seq }) => {
+                    current.pending = Pending::Block;
+                    frames.push(frame(*seq));
+                }
+                Instr::Loop(Loop { seq }) => {
+                    // Keep the slot so outer loops come before the loops nested in them
+                    current.pending = Pending::Loop(walk.loop_iterations.len());
+                    walk.loop_iterations.push(0);
+                    walk.exact = false;
+                    frames.push(frame(*seq));
+                }
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    current.pending = Pending::Consequent(*alternative);
+                    frames.push(frame(*consequent));
+                }
+                Instr::Call(Call { func: callee }) => {
+                    walk.callees.insert(function_name(self.module, *callee));
+                    let (callee_cost, callee_exact) = self.callee_cost(*callee);
+                    walk.exact &= callee_exact;
+                    current.cost += callee_cost;
+                }
+                Instr::CallIndirect(_) => {
+                    walk.calls_indirect = true;
+                    walk.exact = false;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    ///Cost charged before the first point where the block could branch away
+    ///
+    ///Metering charges a whole block when it is entered, so the block's own cost always counts
+    fn min_seq_cost(&self, func: &LocalFunction, seq_id: InstrSeqId) -> u64 {
+        let mut total = 0;
+        let mut seq_id = seq_id;
+        'blocks: loop {
+            let (cost, body) = self.charge(&func.block(seq_id).instrs);
+            total += cost;
+            for (instr, _) in body {
+                match instr {
+                    Instr::Call(Call { func: callee }) => {
+                        total += self
+                            .costs
+                            .get(callee)
+                            .map_or(0, |callee_cost| callee_cost.min_cost);
+                    }
+                    // Entered at least once, but a branch inside could skip the rest of this block
+                    Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => {
+                        seq_id = *seq;
+                        continue 'blocks;
+                    }
+                    Instr::Br(_)
+                    | Instr::BrIf(_)
+                    | Instr::BrTable(_)
+                    | Instr::IfElse(_)
+                    | Instr::Return(_)
+                    | Instr::Unreachable(_)
+                    | Instr::CallIndirect(_) => return total,
+                    _ => {}
+                }
+            }
+            return total;
+        }
+    }
+
+    ///What entering a block charges, and the instructions after its metering check
+    fn charge<'i>(&self, instrs: &'i [(Instr, InstrLocId)]) -> (u64, &'i [(Instr, InstrLocId)]) {
+        if let Some(gas_global) = self.gas_global {
+            if metering_check(instrs, gas_global).is_some() {
+                let body = &instrs[METERING_INSTRUCTION_COUNT..];
+                return (block_cost(body) as u64, body);
+            }
+        }
+        (instrs.len() as u64, instrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use walrus::{FunctionBuilder, Module, ModuleConfig};
+
+    use super::*;
+
+    const DEPTH: usize = 100_000;
+
+    fn parse(wat: &str) -> Module {
+        let wasm = wat::parse_str(wat).unwrap();
+        ModuleConfig::new().parse(&wasm).unwrap()
+    }
+
+    #[test]
+    fn loops_and_ifs() {
+        let module = parse(
+            r#"(module
+                (func $leaf (result i32) i32.const 1)
+                (func $f (param i32)
+                    (loop
+                        local.get 0
+                        (if (then call $leaf drop nop) (else nop))
+                        (loop nop))
+                    call $leaf
+                    drop))"#,
+        );
+        let report = analyze(&module, None);
+
+        let leaf = report.function("leaf").unwrap();
+        assert_eq!((leaf.cost, leaf.min_cost, leaf.exact), (1, 1, true));
+
+        let f = report.function("f").unwrap();
+        //Entry block 3 + leaf 1, the outer loop 3 + the dearer if side 4 + the inner loop 1
+        assert_eq!(f.cost, 12);
+        assert_eq!(f.loop_iterations, vec![8, 1]);
+        //Entry block 3 + outer loop 3, the if could branch away
+        assert_eq!(f.min_cost, 6);
+        assert!(!f.exact);
+        assert_eq!(report.call_graph["f"], BTreeSet::from(["leaf".to_string()]));
+    }
+
+    #[test]
+    fn recursion_is_inexact() {
+        let module = parse(
+            r#"(module
+                (func $even call $odd)
+                (func $odd call $even))"#,
+        );
+        let report = analyze(&module, None);
+        assert!(!report.function("even").unwrap().exact);
+        assert!(!report.function("odd").unwrap().exact);
+    }
+
+    #[test]
+    fn long_call_chains_do_not_overflow() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        builder.func_body().i32_const(1).drop();
+        let mut callee = builder.finish(vec![], &mut module.funcs);
+        for _ in 1..DEPTH {
+            let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+            builder.func_body().call(callee);
+            callee = builder.finish(vec![], &mut module.funcs);
+        }
+
+        let report = analyze(&module, None);
+        let first = &report.functions[&function_name(&module, callee)];
+        assert_eq!(first.cost, DEPTH as u64 + 1);
+        assert!(first.exact);
+    }
+
+    #[test]
+    fn deep_nesting_does_not_overflow() {
+        let mut module = Module::default();
+        let mut builder = FunctionBuilder::new(&mut module.types, &[], &[]);
+        let mut inner = builder.dangling_instr_seq(InstrSeqType::Simple(None)).id();
+        for _ in 0..DEPTH {
+            let mut outer = builder.dangling_instr_seq(InstrSeqType::Simple(None));
+            outer.i32_const(1).drop().instr(Block { seq: inner });
+            inner = outer.id();
+        }
+        builder.func_body().instr(Block { seq: inner });
+        let func = builder.finish(vec![], &mut module.funcs);
+
+        let report = analyze(&module, None);
+        let cost = &report.functions[&function_name(&module, func)];
+        assert_eq!(cost.cost, 3 * DEPTH as u64 + 1);
+        assert_eq!(cost.min_cost, cost.cost);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    fmt,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::Arc,
+};
+
+use wasmer::{
+    Function, FunctionEnv, FunctionEnvMut, FunctionType, Imports, Memory, MemoryAccessError,
+    MemoryView, RuntimeError, Store, Type, Value,
+};
+
+use crate::{
+    limitation_injector::{RESERVED_PREFIX, RUNNER_IMPORTS},
+    wasm_vm::VMError,
+};
+
+/// Import module host functions go under unless another one is given, matches script_api::host_function!
+pub const DEFAULT_HOST_MODULE: &str = "host";
+
+/// What a host function returns, any error fails the tick with VMError::HostFunctionFailed
+pub type HostResult = Result<Vec<Value>, Box<dyn std::error::Error + Send + Sync>>;
+
+type HostCallback = dyn Fn(&mut HostContext, &[Value]) -> HostResult + Send + Sync;
+
+///Gives a host function access to the calling script's memory, for passing strings and buffers by pointer
+pub struct HostContext<'a> {
+    memory: MemoryView<'a>,
+}
+
+impl HostContext<'_> {
+    pub fn read_bytes(&self, pointer: u32, length: u32) -> Result<Vec<u8>, MemoryAccessError> {
+        let mut bytes = vec![0; length as usize];
+        self.memory.read(pointer as u64, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn read_string(
+        &self,
+        pointer: u32,
+        length: u32,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(String::from_utf8(self.read_bytes(pointer, length)?)?)
+    }
+
+    pub fn write_bytes(&self, pointer: u32, bytes: &[u8]) -> Result<(), MemoryAccessError> {
+        self.memory.write(pointer as u64, bytes)
+    }
+}
+
+///A host function a script can import and call while it runs
+#[derive(Clone)]
+struct HostFunction {
+    module: String,
+    name: String,
+    ty: FunctionType,
+    callback: Arc<HostCallback>,
+}
+
+///Host functions to link scripts against
+#[derive(Clone, Default)]
+pub struct HostFunctions {
+    functions: Vec<HostFunction>,
+}
+
+impl fmt::Debug for HostFunctions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.functions.iter().map(|function| {
+                format!("{}.{}: {:?}", function.module, function.name, function.ty)
+            }))
+            .finish()
+    }
+}
+
+impl HostFunctions {
+    ///Adds a function scripts can import as `module.name`, the callback can capture whatever host state it needs
+    pub fn register<F>(
+        &mut self,
+        module: &str,
+        name: &str,
+        params: &[Type],
+        results: &[Type],
+        callback: F,
+    ) -> &mut Self
+    where
+        F: Fn(&mut HostContext, &[Value]) -> HostResult + Send + Sync + 'static,
+    {
+        self.functions.push(HostFunction {
+            module: module.to_string(),
+            name: name.to_string(),
+            ty: FunctionType::new(params.to_vec(), results.to_vec()),
+            callback: Arc::new(callback),
+        });
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    ///Checks no two functions share a `module.name` and none of them takes the place of the runner's own imports
+    pub(crate) fn check_names(&self) -> Result<(), VMError> {
+        for (index, function) in self.functions.iter().enumerate() {
+            let full_name = format!("{}.{}", function.module, function.name);
+            if function.module.starts_with(RESERVED_PREFIX)
+                || function.name.starts_with(RESERVED_PREFIX)
+                || RUNNER_IMPORTS.contains(&(function.module.as_str(), function.name.as_str()))
+            {
+                return Err(VMError::ReservedName(full_name));
+            }
+            //Only the last registration would be linked, the others would silently never be called
+            if self.functions[..index]
+                .iter()
+                .any(|other| other.module == function.module && other.name == function.name)
+            {
+                return Err(VMError::DuplicateHostFunction(full_name));
+            }
+        }
+        Ok(())
+    }
+}
+
+///State shared by every host function of a VM
+pub(crate) struct HostEnv {
+    pub(crate) memory: Option<Memory>,
+    /// Host calls made this tick
+    pub(crate) calls: u32,
+    pub(crate) call_limit: Option<u32>,
+}
+
+///Defines every registered host function on the import object
+pub(crate) fn define_imports(
+    store: &mut Store,
+    import_object: &mut Imports,
+    host_functions: &HostFunctions,
+    call_limit: Option<u32>,
+) -> FunctionEnv<HostEnv> {
+    let env = FunctionEnv::new(
+        store,
+        HostEnv {
+            memory: None,
+            calls: 0,
+            call_limit,
+        },
+    );
+
+    for function in &host_functions.functions {
+        let host_function = function.clone();
+        let wasm_function = Function::new_with_env(
+            store,
+            &env,
+            function.ty.clone(),
+            move |mut env: FunctionEnvMut<HostEnv>, args: &[Value]| {
+                call_host_function(&mut env, &host_function, args)
+            },
+        );
+        import_object.define(&function.module, &function.name, wasm_function);
+    }
+    env
+}
+
+///Counts the call against the tick's limit and runs the callback, turning errors and panics into VMErrors
+fn call_host_function(
+    env: &mut FunctionEnvMut<HostEnv>,
+    function: &HostFunction,
+    args: &[Value],
+) -> Result<Vec<Value>, RuntimeError> {
+    let (data, store) = env.data_and_store_mut();
+    data.calls += 1;
+    if data.call_limit.is_some_and(|limit| data.calls > limit) {
+        return Err(RuntimeError::user(Box::new(VMError::HostCallLimitReached)));
+    }
+
+    let fail = |message: String| {
+        RuntimeError::user(Box::new(VMError::HostFunctionFailed(
+            function.name.clone(),
+            message,
+        )))
+    };
+
+    let memory = data.memory.clone().expect("host env not initialized");
+    let mut context = HostContext {
+        memory: memory.view(&store),
+    };
+    let values = match catch_unwind(AssertUnwindSafe(|| (function.callback)(&mut context, args))) {
+        Ok(Ok(values)) => values,
+        Ok(Err(e)) => return Err(fail(e.to_string())),
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            return Err(fail(format!("panicked: {}", message)));
+        }
+    };
+
+    let types: Vec<Type> = values.iter().map(Value::ty).collect();
+    if types != function.ty.results() {
+        return Err(fail(format!(
+            "returned {:?} but is declared to return {:?}",
+            types,
+            function.ty.results()
+        )));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn functions(names: &[(&str, &str)]) -> HostFunctions {
+        let mut functions = HostFunctions::default();
+        for (module, name) in names {
+            functions.register(module, name, &[], &[], |_, _| Ok(vec![]));
+        }
+        functions
+    }
+
+    #[test]
+    fn same_name_in_different_modules_is_allowed() {
+        let functions = functions(&[("host", "log"), ("physics", "log")]);
+        assert!(functions.check_names().is_ok());
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let functions = functions(&[("host", "log"), ("physics", "log"), ("host", "log")]);
+        assert!(matches!(
+            functions.check_names(),
+            Err(VMError::DuplicateHostFunction(name)) if name == "host.log"
+        ));
+    }
+
+    #[test]
+    fn runner_names_are_rejected() {
+        for (module, name) in RUNNER_IMPORTS
+            .into_iter()
+            .chain([("host", "__runner_log"), ("__runner_host", "log")])
+        {
+            assert!(
+                matches!(
+                    functions(&[(module, name)]).check_names(),
+                    Err(VMError::ReservedName(_))
+                ),
+                "{}.{}",
+                module,
+                name
+            );
+        }
+    }
+}
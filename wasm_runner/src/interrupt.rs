@@ -0,0 +1,155 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use wasmer::{imports, Function, FunctionEnv, FunctionEnvMut, Imports, RuntimeError, Store, Value};
+
+use crate::{limitation_injector::OUT_OF_GAS_IMPORT, wasm_vm::VMError};
+
+/// Default number of instructions handed to a script between interrupt checks
+pub const INTERRUPT_CHECK_INTERVAL: i32 = 10_000;
+
+///Lets another thread stop a tick that is running
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    ///Asks the running tick to stop, it fails with VMError::Interrupted the next time the script checks
+    ///
+    ///Scripts check every VMConfig::interrupt_check_interval instructions, so the tick can run that many more
+    ///before it stops. If no tick is running, or the tick finishes before checking, the next tick fails instead
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn take(&self) -> bool {
+        self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+///Where a preemptible script is in its pause/resume cycle
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ResumeState {
+    /// Running normally, or not started
+    Running,
+    /// Ran out of instructions and unwound its stack into the asyncify buffer
+    Suspended,
+    /// Being rewound back to where it ran out of instructions
+    Rewinding,
+}
+
+///State behind the out_of_gas import the injector calls when the instruction global runs low
+pub(crate) struct GasEnv {
+    /// Instructions of the current tick not handed to the script yet
+    pub(crate) reserve: i64,
+    /// How many instructions are handed out at a time, smaller means interrupts are noticed sooner
+    pub(crate) check_interval: i32,
+    /// Most instructions any tick can hand out, a block costing more can never run
+    pub(crate) max_allowance: i64,
+    pub(crate) interrupt: InterruptHandle,
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) reset_instructions: Option<Function>,
+    pub(crate) get_instructions: Option<Function>,
+    /// Set when the script is preemptible
+    pub(crate) preemptible: bool,
+    pub(crate) state: ResumeState,
+    pub(crate) asyncify_pointer: i32,
+    pub(crate) start_unwind: Option<Function>,
+    pub(crate) stop_rewind: Option<Function>,
+}
+
+///Creates the out_of_gas import used by preemptible and interruptible scripts
+pub(crate) fn imports(
+    store: &mut Store,
+    check_interval: i32,
+    max_allowance: i64,
+    preemptible: bool,
+) -> (Imports, FunctionEnv<GasEnv>) {
+    let env = FunctionEnv::new(
+        store,
+        GasEnv {
+            reserve: 0,
+            check_interval,
+            max_allowance,
+            interrupt: InterruptHandle::default(),
+            deadline: None,
+            reset_instructions: None,
+            get_instructions: None,
+            preemptible,
+            state: ResumeState::Running,
+            asyncify_pointer: 0,
+            start_unwind: None,
+            stop_rewind: None,
+        },
+    );
+    let out_of_gas = Function::new_typed_with_env(store, &env, out_of_gas);
+    let import_object = imports! {
+        "env" => {
+            OUT_OF_GAS_IMPORT => out_of_gas,
+        }
+    };
+    (import_object, env)
+}
+
+///Called by a block that costs more than the instructions the script has been handed
+///
+///Checks for interrupts and timeouts, then hands out more of the tick's instructions.
+///If the tick has none left the script is paused when preemptible, otherwise it traps
+fn out_of_gas(mut env: FunctionEnvMut<GasEnv>, cost: i32) -> Result<(), RuntimeError> {
+    let (data, mut store) = env.data_and_store_mut();
+
+    if data.state == ResumeState::Rewinding {
+        let stop_rewind = data.stop_rewind.clone().expect("asyncify not initialized");
+        stop_rewind.call(&mut store, &[])?;
+        data.state = ResumeState::Running;
+    }
+
+    if data.interrupt.take() {
+        return Err(RuntimeError::user(Box::new(VMError::Interrupted)));
+    }
+    if let Some(deadline) = data.deadline {
+        if Instant::now() >= deadline {
+            return Err(RuntimeError::user(Box::new(VMError::Timeout)));
+        }
+    }
+
+    let get_instructions = data
+        .get_instructions
+        .clone()
+        .expect("gas env not initialized");
+    let reset_instructions = data
+        .reset_instructions
+        .clone()
+        .expect("gas env not initialized");
+    let current = match get_instructions.call(&mut store, &[])?.first() {
+        Some(Value::I32(current)) => *current as i64,
+        _ => return Err(RuntimeError::user(Box::new(VMError::VMErrorNoGas))),
+    };
+
+    if current + data.reserve >= cost as i64 {
+        let top_up = data
+            .reserve
+            .min((data.check_interval as i64).max(cost as i64 - current));
+        data.reserve -= top_up;
+        reset_instructions.call(&mut store, &[((current + top_up) as i32).into()])?;
+        return Ok(());
+    }
+
+    //Pausing only helps if a later tick can hand out enough for the block, otherwise every tick would pause
+    //in the same place without getting anywhere
+    if data.preemptible && cost as i64 <= data.max_allowance {
+        let start_unwind = data.start_unwind.clone().expect("asyncify not initialized");
+        start_unwind.call(&mut store, &[data.asyncify_pointer.into()])?;
+        data.state = ResumeState::Suspended;
+        return Ok(());
+    }
+
+    //Running out uses up the whole allowance, the same as the middleware reports it
+    data.reserve = 0;
+    reset_instructions.call(&mut store, &[0.into()])?;
+    Err(RuntimeError::user(Box::new(VMError::VMProcLimitReached)))
+}
@@ -1,6 +1,41 @@
+mod budget;
+mod calibration;
 mod compiler;
+mod coverage;
+mod engine;
+mod features;
+mod gas_analysis;
+mod host;
+mod interrupt;
 mod limitation_injector;
+mod metering;
+mod module_limits;
+mod pool;
+mod preempt;
+mod report;
+mod snapshot;
+mod template;
+mod vm_config;
 mod wasm_vm;
+pub use budget::*;
+pub use calibration::{
+    calibrate, default_calibration_scripts, Calibration, CalibrationScript, MixResult,
+};
+pub use compiler::compile;
+pub use coverage::{BlockCoverage, CoverageReport};
+pub use engine::CompilerBackend;
+pub use features::WasmProposals;
+pub use gas_analysis::{FunctionCost, GasReport, ENTRY_FUNCTION};
+pub use host::{HostContext, HostFunctions, HostResult, DEFAULT_HOST_MODULE};
+pub use interrupt::{InterruptHandle, INTERRUPT_CHECK_INTERVAL};
+pub use limitation_injector::{instrument, FloatDeterminism, RESERVED_PREFIX};
+pub use metering::MeteringBackend;
+pub use module_limits::ModuleLimits;
+pub use pool::{ScriptId, ScriptPool, ScriptResult};
+pub use report::{TickError, TickErrorKind, TickOutcome, TickReport};
+pub use snapshot::{ModuleHash, VmSnapshot, SNAPSHOT_MAGIC, SNAPSHOT_VERSION};
+pub use template::ScriptTemplate;
+pub use vm_config::*;
 pub use wasm_vm::*;
 
 pub(crate) type Error = Box<dyn std::error::Error>;
@@ -1,58 +1,1212 @@
 // Taken from https://github.com/rlane/oort3/blob/master/shared/simulator/src/vm/limiter.rs
 // I would write it myself but this is exactly what I would do anyway
 use std::error::Error;
-use walrus::{ir::*, FunctionBuilder, GlobalId, InitExpr, LocalFunction, ValType};
+use walrus::{
+    ir::*, FunctionBuilder, FunctionId, FunctionKind, GlobalId, InitExpr, LocalFunction, LocalId,
+    MemoryId, ValType,
+};
 
-pub fn rewrite(wasm: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+use crate::{
+    compiler::asyncify,
+    coverage::{CoverageBlock, CoverageMap},
+    gas_analysis::{analyze, GasReport},
+    metering::MeteringBackend,
+    vm_config::{VMConfig, WASM_PAGE_SIZE},
+    wasm_vm::VMError,
+};
+
+/// How floating point instructions are treated, needed when scripts run in lockstep across machines
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum FloatDeterminism {
+    /// Floats are left alone
+    #[default]
+    Off,
+    /// Every float operation that can produce a NaN has its result replaced with the canonical NaN
+    CanonicalizeNaN,
+    /// Any module using f32 or f64 instructions is rejected
+    RejectFloats,
+}
+
+/// Every export and import the runner adds starts with this, scripts can't use it for their own
+pub const RESERVED_PREFIX: &str = "__runner_";
+pub(crate) const RESET_INSTRUCTIONS_EXPORT: &str = "__runner_reset_instructions";
+pub(crate) const GET_INSTRUCTIONS_EXPORT: &str = "__runner_get_instructions";
+pub(crate) const MEMORY_LIMIT_EXPORT: &str = "__runner_memory_limit_reached";
+pub(crate) const GROW_BUDGET_EXPORT: &str = "__runner_grow_budget";
+pub(crate) const OUT_OF_GAS_IMPORT: &str = "__runner_out_of_gas";
+/// Set to 1 just before a script without the out of gas import traps for running out of instructions
+pub(crate) const GAS_EXHAUSTED_EXPORT: &str = "__runner_gas_exhausted";
+/// The one reserved name scripts can use, script_api::remaining_instructions imports it
+pub(crate) const REMAINING_INSTRUCTIONS_IMPORT: &str = "__runner_remaining_instructions";
+/// Imports the runner defines itself, no host function can take their place
+pub(crate) const RUNNER_IMPORTS: [(&str, &str); 2] = [
+    ("env", OUT_OF_GAS_IMPORT),
+    ("env", REMAINING_INSTRUCTIONS_IMPORT),
+];
+pub(crate) const COVERAGE_EXPORT: &str = "__runner_coverage";
+/// Every mutable global is also exported as this followed by a number, so snapshots can save and restore them
+pub(crate) const GLOBAL_EXPORT_PREFIX: &str = "__runner_global_";
+
+/// Values of the memory limit global, which says why the script's last memory.grow failed
+pub(crate) const GROW_REFUSED: i32 = 1;
+pub(crate) const GROW_BUDGET_EXHAUSTED: i32 = 2;
+
+/// The rewritten module and anything the VM needs to know about what was added to it
+pub(crate) struct Rewritten {
+    pub(crate) wasm: Vec<u8>,
+    pub(crate) coverage: Option<CoverageMap>,
+    pub(crate) gas_report: GasReport,
+}
+
+pub(crate) fn rewrite(wasm: &[u8], config: &VMConfig) -> Result<Rewritten, Box<dyn Error>> {
     let mut module = walrus::Module::from_buffer(wasm)?;
 
+    check_reserved_names(&module)?;
+    check_atomic_wait(&module)?;
+
+    // Done before metering so the canonicalization is paid for by the script
+    if config.float_determinism != FloatDeterminism::Off {
+        enforce_float_determinism(&mut module, config.float_determinism)?;
+    }
+
+    // Also before metering, so it only marks the script's own blocks and the verification still holds
+    let coverage = if config.coverage {
+        Some(inject_coverage(&mut module)?)
+    } else {
+        None
+    };
+
+    // Preemptible and interruptible scripts call out to the host when they run out of instructions instead of trapping
+    let out_of_gas = if config.uses_gas_import() {
+        if config.metering != MeteringBackend::Injector {
+            return Err(Box::new(VMError::NeedsInjectorMetering));
+        }
+        let ty = module.types.add(&[ValType::I32], &[]);
+        let (out_of_gas, _) = module.add_import_func("env", OUT_OF_GAS_IMPORT, ty);
+        Some(out_of_gas)
+    } else {
+        None
+    };
+
+    let metering = if config.metering == MeteringBackend::Injector {
+        Some(inject_metering(&mut module, out_of_gas))
+    } else {
+        None
+    };
+
+    let mut runtime_funcs = vec![];
+    if config.max_memory_pages.is_some() || config.resource_limits.memory_grow_bytes.is_some() {
+        runtime_funcs.extend(limit_memory(&mut module, config.max_memory_pages)?);
+    }
+
+    let out_of_gas = metering.as_ref().map(|metering| metering.out_of_gas);
+    let gas_global = metering.map(|metering| {
+        runtime_funcs.extend(metering.runtime_funcs);
+        metering.gas_global
+    });
+    // Only while the whole tick's instructions are in the global, otherwise the host has to add what it is holding back
+    if let (Some(gas_global), false) = (gas_global, config.uses_gas_import()) {
+        runtime_funcs.extend(inline_remaining_instructions(&mut module, gas_global));
+    }
+    if let (Some(gas_global), Some(out_of_gas)) = (gas_global, out_of_gas) {
+        verify_metering(&module, gas_global, out_of_gas, &runtime_funcs)?;
+    }
+
+    // A preemptible script can spread its work over as many ticks as it needs
+    let gas_report = analyze(&module, gas_global);
+    if config.reject_over_budget && !config.preemptible {
+        if let Some(entry) = gas_report.entry() {
+            let max_allowance = config.budget.max_allowance();
+            if entry.min_cost > max_allowance as u64 {
+                return Err(Box::new(VMError::ExceedsBudget(
+                    entry.min_cost,
+                    max_allowance,
+                )));
+            }
+        }
+    }
+
+    export_mutable_globals(&mut module);
+    let mut wasm = module.emit_wasm();
+    if config.preemptible {
+        wasm = asyncify(&wasm)?;
+    }
+    Ok(Rewritten {
+        wasm,
+        coverage,
+        gas_report,
+    })
+}
+
+///Exports every mutable global, including the ones the injector added, for WasmVM::snapshot
+fn export_mutable_globals(module: &mut walrus::Module) {
+    let mutable_globals: Vec<GlobalId> = module
+        .globals
+        .iter()
+        .filter(|global| global.mutable)
+        .map(|global| global.id())
+        .collect();
+    for (index, global) in mutable_globals.into_iter().enumerate() {
+        module
+            .exports
+            .add(&format!("{}{}", GLOBAL_EXPORT_PREFIX, index), global);
+    }
+}
+
+///Rewrites a module the same way WasmVM::from_wasm does and returns the new binary
+///
+///Lets the instrumented module be inspected or run outside of the VM, the tests and fuzz targets use it
+pub fn instrument(wasm: &[u8], config: &VMConfig) -> Result<Vec<u8>, Box<dyn Error>> {
+    Ok(rewrite(wasm, config)?.wasm)
+}
+
+/// Fails if the script already exports or imports anything in the runner's reserved namespace
+fn check_reserved_names(module: &walrus::Module) -> Result<(), Box<dyn Error>> {
+    let exports = module.exports.iter().map(|export| export.name.as_str());
+    let imports = module
+        .imports
+        .iter()
+        .filter(|import| !is_remaining_instructions_import(module, import))
+        .map(|import| import.name.as_str());
+    for name in exports.chain(imports) {
+        if name.starts_with(RESERVED_PREFIX) {
+            return Err(Box::new(VMError::ReservedName(name.to_string())));
+        }
+    }
+    Ok(())
+}
+
+/// Whether the import is script_api's read only view of the instruction counter, with the type it should have
+fn is_remaining_instructions_import(module: &walrus::Module, import: &walrus::Import) -> bool {
+    let walrus::ImportKind::Function(func_id) = import.kind else {
+        return false;
+    };
+    let ty = module.types.get(module.funcs.get(func_id).ty());
+    import.module == "env"
+        && import.name == REMAINING_INSTRUCTIONS_IMPORT
+        && ty.params().is_empty()
+        && ty.results() == [ValType::I32]
+}
+
+/// Points calls to the remaining instructions import at a function that reads the gas global directly,
+/// saving a trip to the host. The host still provides the import for calls made through a table
+///
+/// Returns the function it added, if the script imports it at all
+fn inline_remaining_instructions(
+    module: &mut walrus::Module,
+    gas_global: GlobalId,
+) -> Option<FunctionId> {
+    let import = module
+        .imports
+        .iter()
+        .find(|import| is_remaining_instructions_import(module, import))?;
+    let walrus::ImportKind::Function(import_func) = import.kind else {
+        return None;
+    };
+
+    let mut func = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
+    func.func_body().global_get(gas_global);
+    let accessor = func.finish(vec![], &mut module.funcs);
+
+    for (func_id, func) in module.funcs.iter_local_mut() {
+        if func_id == accessor {
+            continue;
+        }
+        let block_ids: Vec<_> = func.blocks().map(|(block_id, _block)| block_id).collect();
+        for block_id in block_ids {
+            for (instr, _) in func.block_mut(block_id).instrs.iter_mut() {
+                if let Instr::Call(Call { func }) = instr {
+                    if *func == import_func {
+                        *func = accessor;
+                    }
+                }
+            }
+        }
+    }
+    Some(accessor)
+}
+
+/// Marks a byte in a bitmap every time a block runs
+///
+/// The bitmap goes in new pages added to the end of the initial memory. This relies on the script's
+/// allocator only handing out memory it got from memory.grow, which is true of Rust's default allocator
+/// on wasm32-unknown-unknown, and on the linker's static data and stack ending below the old initial size,
+/// which is checked through __heap_base. A custom allocator that also hands out the free space below
+/// memory.size would overwrite the bitmap. Its address is exported as a global
+fn inject_coverage(module: &mut walrus::Module) -> Result<CoverageMap, Box<dyn Error>> {
+    let memory = module
+        .memories
+        .iter()
+        .next()
+        .ok_or(VMError::VMErrorNoMemory)?;
+    if memory.import.is_some() {
+        return Err(Box::new(VMError::CoverageUnsupported(
+            "the memory is imported, so pages can't be added to it".to_string(),
+        )));
+    }
+    let memory_id = memory.id();
+    let memory_end = memory.initial as u64 * WASM_PAGE_SIZE;
+    if let Some(heap_base) = heap_base(module) {
+        if heap_base as u64 > memory_end {
+            return Err(Box::new(VMError::CoverageUnsupported(format!(
+                "__heap_base {} is past the end of the initial memory",
+                heap_base
+            ))));
+        }
+    }
+
+    // Find every block first so the bitmap size is known
+    let mut blocks = vec![];
+    for (func_id, func) in module.funcs.iter_local() {
+        let function = function_name(module, func_id);
+        for (index, block_id) in reachable_blocks(func).into_iter().enumerate() {
+            let code_offset = func
+                .block(block_id)
+                .instrs
+                .first()
+                .filter(|(_, loc)| !loc.is_default())
+                .map(|(_, loc)| loc.data());
+            let block = CoverageBlock {
+                function: function.clone(),
+                block: index as u32,
+                code_offset,
+            };
+            blocks.push((func_id, block_id, block));
+        }
+    }
+
+    let memory = module.memories.get_mut(memory_id);
+    let pointer = memory.initial * WASM_PAGE_SIZE as u32;
+    let pages = (blocks.len() as u64).div_ceil(WASM_PAGE_SIZE) as u32;
+    memory.initial += pages;
+    memory.maximum = memory.maximum.map(|max| max + pages);
+
+    let coverage_global = module.globals.add_local(
+        ValType::I32,
+        false,
+        InitExpr::Value(Value::I32(pointer as i32)),
+    );
+    module.exports.add(COVERAGE_EXPORT, coverage_global);
+
+    let mut map_blocks = Vec::with_capacity(blocks.len());
+    for (index, (func_id, block_id, block)) in blocks.into_iter().enumerate() {
+        // bitmap[index] = 1
+        let marker = [
+            Instr::Const(Const {
+                value: Value::I32((pointer + index as u32) as i32),
+            }),
+            Instr::Const(Const {
+                value: Value::I32(1),
+            }),
+            Instr::Store(Store {
+                memory: memory_id,
+                kind: StoreKind::I32_8 { atomic: false },
+                arg: MemArg {
+                    align: 1,
+                    offset: 0,
+                },
+            }),
+        ];
+        let func = module.funcs.get_mut(func_id).kind.unwrap_local_mut();
+        func.block_mut(block_id)
+            .instrs
+            .splice(0..0, marker.map(|instr| (instr, Default::default())));
+        map_blocks.push(block);
+    }
+
+    Ok(CoverageMap {
+        pointer,
+        blocks: map_blocks,
+    })
+}
+
+/// Where the linker put the start of the heap, after the static data and stack, if the module exports it
+fn heap_base(module: &walrus::Module) -> Option<u32> {
+    let export = module
+        .exports
+        .iter()
+        .find(|export| export.name == "__heap_base")?;
+    let walrus::ExportItem::Global(global_id) = export.item else {
+        return None;
+    };
+    match module.globals.get(global_id).kind {
+        walrus::GlobalKind::Local(InitExpr::Value(Value::I32(heap_base))) => Some(heap_base as u32),
+        _ => None,
+    }
+}
+
+/// Every block of a function reachable from its entry, in a stable order starting with the entry
+fn reachable_blocks(func: &LocalFunction) -> Vec<InstrSeqId> {
+    let mut blocks = vec![];
+    let mut stack = vec![func.entry_block()];
+    while let Some(block_id) = stack.pop() {
+        blocks.push(block_id);
+        for (instr, _) in func.block(block_id).instrs.iter().rev() {
+            match instr {
+                Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => stack.push(*seq),
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                }) => {
+                    stack.push(*alternative);
+                    stack.push(*consequent);
+                }
+                _ => {}
+            }
+        }
+    }
+    blocks
+}
+
+/// What inject_metering added to the module
+struct InjectedMetering {
+    gas_global: GlobalId,
+    /// What the checks call when a block costs more than is left, the out of gas import or gas_exhausted_function
+    out_of_gas: FunctionId,
+    /// Functions added for the host that aren't metered themselves
+    runtime_funcs: Vec<FunctionId>,
+}
+
+/// Adds the instruction global, the per block checks and the functions the VM uses to read and reset it
+///
+/// When out_of_gas is given the checks call it with the block's cost, otherwise they trap through gas_exhausted_function
+fn inject_metering(
+    module: &mut walrus::Module,
+    out_of_gas: Option<FunctionId>,
+) -> InjectedMetering {
     let instruction_global =
         module
             .globals
             .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
 
+    // Without the host import running out traps, after setting a global so the host can tell it from other traps
+    let gas_exhausted = out_of_gas.is_none().then(|| gas_exhausted_function(module));
+    let out_of_gas = out_of_gas.or(gas_exhausted).unwrap();
+
+    // Bulk instructions are also charged for the bytes or elements they touch, through a helper called just before them
+    let has_bulk_instrs = module.funcs.iter_local().any(|(_, func)| {
+        func.blocks()
+            .any(|(_, block)| block.instrs.iter().any(|(instr, _)| is_bulk_instr(instr)))
+    });
+    let charge_bulk =
+        has_bulk_instrs.then(|| charge_bulk_function(module, instruction_global, out_of_gas));
+
     // Rewrite each block to check and decrement instrucions
-    for (_, func) in module.funcs.iter_local_mut() {
-        rewrite_function(func, instruction_global);
+    for (func_id, func) in module.funcs.iter_local_mut() {
+        if Some(func_id) == charge_bulk || Some(func_id) == gas_exhausted {
+            continue;
+        }
+        if let Some(charge_bulk) = charge_bulk {
+            insert_bulk_charges(func, charge_bulk);
+        }
+        rewrite_function(func, instruction_global, out_of_gas);
     }
 
     // Create a reset_instruction function to reset instruction limit
-    {
+    let reset_gas = {
         let mut func = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
         let amount = module.locals.add(ValType::I32);
         func.func_body()
             .local_get(amount)
             .global_set(instruction_global);
         let reset_gas = func.finish(vec![amount], &mut module.funcs);
-        module.exports.add("reset_instructions", reset_gas);
-    }
+        module.exports.add(RESET_INSTRUCTIONS_EXPORT, reset_gas);
+        reset_gas
+    };
 
     // Create a get_instruction function to allow the VM to check the instructions
-    {
+    let get_gas = {
         let mut func = FunctionBuilder::new(&mut module.types, &[], &[ValType::I32]);
         func.func_body().global_get(instruction_global);
         let get_gas = func.finish(vec![], &mut module.funcs);
-        module.exports.add("get_instructions", get_gas);
+        module.exports.add(GET_INSTRUCTIONS_EXPORT, get_gas);
+        get_gas
+    };
+
+    let mut runtime_funcs = vec![reset_gas, get_gas];
+    runtime_funcs.extend(charge_bulk);
+    runtime_funcs.extend(gas_exhausted);
+    InjectedMetering {
+        gas_global: instruction_global,
+        out_of_gas,
+        runtime_funcs,
     }
+}
 
-    Ok(module.emit_wasm())
+/// Cost of a v128 instruction, it does the work of several scalar ones
+pub(crate) const SIMD_COST: i32 = 2;
+/// Cost of an atomic instruction, which has to synchronize memory
+pub(crate) const ATOMIC_COST: i32 = 4;
+/// Bulk instructions are charged one instruction for every 2^BULK_COST_SHIFT bytes or elements
+const BULK_COST_SHIFT: i32 = 3;
+
+/// What a single instruction costs, listed by the proposal it comes from
+///
+/// Instructions from proposals that aren't allowed are refused by check_features before this runs,
+/// so the fallback only covers wasm 1.0, mutable globals, sign extension, saturating conversions and multi value
+fn instr_cost(instr: &Instr) -> i32 {
+    match instr {
+        // Bulk memory and reference types, charge_bulk takes care of the length of the bulk ones
+        Instr::MemoryCopy(_)
+        | Instr::MemoryFill(_)
+        | Instr::MemoryInit(_)
+        | Instr::DataDrop(_)
+        | Instr::TableCopy(_)
+        | Instr::TableFill(_)
+        | Instr::TableInit(_)
+        | Instr::TableGrow(_)
+        | Instr::ElemDrop(_)
+        | Instr::TableGet(_)
+        | Instr::TableSet(_)
+        | Instr::TableSize(_)
+        | Instr::RefNull(_)
+        | Instr::RefIsNull(_)
+        | Instr::RefFunc(_) => 1,
+        // Threads, memory.atomic.wait is refused by check_atomic_wait
+        Instr::AtomicRmw(_)
+        | Instr::Cmpxchg(_)
+        | Instr::AtomicNotify(_)
+        | Instr::AtomicWait(_)
+        | Instr::AtomicFence(_) => ATOMIC_COST,
+        // SIMD
+        Instr::V128Bitselect(_)
+        | Instr::I8x16Swizzle(_)
+        | Instr::I8x16Shuffle(_)
+        | Instr::LoadSimd(_)
+        | Instr::Const(Const {
+            value: Value::V128(_),
+        }) => SIMD_COST,
+        Instr::Binop(Binop { op }) if is_simd_binop(op) => SIMD_COST,
+        Instr::Unop(Unop { op }) if is_simd_unop(op) => SIMD_COST,
+        Instr::Load(Load {
+            kind: LoadKind::V128,
+            ..
+        })
+        | Instr::Store(Store {
+            kind: StoreKind::V128,
+            ..
+        }) => SIMD_COST,
+        _ => 1,
+    }
+}
+
+/// Binary operators that work on v128 values
+fn is_simd_binop(op: &BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::I8x16ReplaceLane { .. }
+            | BinaryOp::I16x8ReplaceLane { .. }
+            | BinaryOp::I32x4ReplaceLane { .. }
+            | BinaryOp::I64x2ReplaceLane { .. }
+            | BinaryOp::F32x4ReplaceLane { .. }
+            | BinaryOp::F64x2ReplaceLane { .. }
+            | BinaryOp::I8x16Eq
+            | BinaryOp::I8x16Ne
+            | BinaryOp::I8x16LtS
+            | BinaryOp::I8x16LtU
+            | BinaryOp::I8x16GtS
+            | BinaryOp::I8x16GtU
+            | BinaryOp::I8x16LeS
+            | BinaryOp::I8x16LeU
+            | BinaryOp::I8x16GeS
+            | BinaryOp::I8x16GeU
+            | BinaryOp::I16x8Eq
+            | BinaryOp::I16x8Ne
+            | BinaryOp::I16x8LtS
+            | BinaryOp::I16x8LtU
+            | BinaryOp::I16x8GtS
+            | BinaryOp::I16x8GtU
+            | BinaryOp::I16x8LeS
+            | BinaryOp::I16x8LeU
+            | BinaryOp::I16x8GeS
+            | BinaryOp::I16x8GeU
+            | BinaryOp::I32x4Eq
+            | BinaryOp::I32x4Ne
+            | BinaryOp::I32x4LtS
+            | BinaryOp::I32x4LtU
+            | BinaryOp::I32x4GtS
+            | BinaryOp::I32x4GtU
+            | BinaryOp::I32x4LeS
+            | BinaryOp::I32x4LeU
+            | BinaryOp::I32x4GeS
+            | BinaryOp::I32x4GeU
+            | BinaryOp::I64x2Eq
+            | BinaryOp::I64x2Ne
+            | BinaryOp::I64x2LtS
+            | BinaryOp::I64x2GtS
+            | BinaryOp::I64x2LeS
+            | BinaryOp::I64x2GeS
+            | BinaryOp::F32x4Eq
+            | BinaryOp::F32x4Ne
+            | BinaryOp::F32x4Lt
+            | BinaryOp::F32x4Gt
+            | BinaryOp::F32x4Le
+            | BinaryOp::F32x4Ge
+            | BinaryOp::F64x2Eq
+            | BinaryOp::F64x2Ne
+            | BinaryOp::F64x2Lt
+            | BinaryOp::F64x2Gt
+            | BinaryOp::F64x2Le
+            | BinaryOp::F64x2Ge
+            | BinaryOp::V128And
+            | BinaryOp::V128Or
+            | BinaryOp::V128Xor
+            | BinaryOp::V128AndNot
+            | BinaryOp::I8x16Shl
+            | BinaryOp::I8x16ShrS
+            | BinaryOp::I8x16ShrU
+            | BinaryOp::I8x16Add
+            | BinaryOp::I8x16AddSatS
+            | BinaryOp::I8x16AddSatU
+            | BinaryOp::I8x16Sub
+            | BinaryOp::I8x16SubSatS
+            | BinaryOp::I8x16SubSatU
+            | BinaryOp::I16x8Shl
+            | BinaryOp::I16x8ShrS
+            | BinaryOp::I16x8ShrU
+            | BinaryOp::I16x8Add
+            | BinaryOp::I16x8AddSatS
+            | BinaryOp::I16x8AddSatU
+            | BinaryOp::I16x8Sub
+            | BinaryOp::I16x8SubSatS
+            | BinaryOp::I16x8SubSatU
+            | BinaryOp::I16x8Mul
+            | BinaryOp::I32x4Shl
+            | BinaryOp::I32x4ShrS
+            | BinaryOp::I32x4ShrU
+            | BinaryOp::I32x4Add
+            | BinaryOp::I32x4Sub
+            | BinaryOp::I32x4Mul
+            | BinaryOp::I64x2Shl
+            | BinaryOp::I64x2ShrS
+            | BinaryOp::I64x2ShrU
+            | BinaryOp::I64x2Add
+            | BinaryOp::I64x2Sub
+            | BinaryOp::I64x2Mul
+            | BinaryOp::F32x4Add
+            | BinaryOp::F32x4Sub
+            | BinaryOp::F32x4Mul
+            | BinaryOp::F32x4Div
+            | BinaryOp::F32x4Min
+            | BinaryOp::F32x4Max
+            | BinaryOp::F32x4PMin
+            | BinaryOp::F32x4PMax
+            | BinaryOp::F64x2Add
+            | BinaryOp::F64x2Sub
+            | BinaryOp::F64x2Mul
+            | BinaryOp::F64x2Div
+            | BinaryOp::F64x2Min
+            | BinaryOp::F64x2Max
+            | BinaryOp::F64x2PMin
+            | BinaryOp::F64x2PMax
+            | BinaryOp::I8x16NarrowI16x8S
+            | BinaryOp::I8x16NarrowI16x8U
+            | BinaryOp::I16x8NarrowI32x4S
+            | BinaryOp::I16x8NarrowI32x4U
+            | BinaryOp::I8x16RoundingAverageU
+            | BinaryOp::I16x8RoundingAverageU
+            | BinaryOp::I8x16MinS
+            | BinaryOp::I8x16MinU
+            | BinaryOp::I8x16MaxS
+            | BinaryOp::I8x16MaxU
+            | BinaryOp::I16x8MinS
+            | BinaryOp::I16x8MinU
+            | BinaryOp::I16x8MaxS
+            | BinaryOp::I16x8MaxU
+            | BinaryOp::I32x4MinS
+            | BinaryOp::I32x4MinU
+            | BinaryOp::I32x4MaxS
+            | BinaryOp::I32x4MaxU
+            | BinaryOp::I32x4DotI16x8S
+            | BinaryOp::I16x8Q15MulrSatS
+            | BinaryOp::I16x8ExtMulLowI8x16S
+            | BinaryOp::I16x8ExtMulHighI8x16S
+            | BinaryOp::I16x8ExtMulLowI8x16U
+            | BinaryOp::I16x8ExtMulHighI8x16U
+            | BinaryOp::I32x4ExtMulLowI16x8S
+            | BinaryOp::I32x4ExtMulHighI16x8S
+            | BinaryOp::I32x4ExtMulLowI16x8U
+            | BinaryOp::I32x4ExtMulHighI16x8U
+            | BinaryOp::I64x2ExtMulLowI32x4S
+            | BinaryOp::I64x2ExtMulHighI32x4S
+            | BinaryOp::I64x2ExtMulLowI32x4U
+            | BinaryOp::I64x2ExtMulHighI32x4U
+    )
+}
+
+/// Unary operators that work on v128 values, including splats and lane extracts to and from scalars
+fn is_simd_unop(op: &UnaryOp) -> bool {
+    matches!(
+        op,
+        UnaryOp::I8x16Splat
+            | UnaryOp::I8x16ExtractLaneS { .. }
+            | UnaryOp::I8x16ExtractLaneU { .. }
+            | UnaryOp::I16x8Splat
+            | UnaryOp::I16x8ExtractLaneS { .. }
+            | UnaryOp::I16x8ExtractLaneU { .. }
+            | UnaryOp::I32x4Splat
+            | UnaryOp::I32x4ExtractLane { .. }
+            | UnaryOp::I64x2Splat
+            | UnaryOp::I64x2ExtractLane { .. }
+            | UnaryOp::F32x4Splat
+            | UnaryOp::F32x4ExtractLane { .. }
+            | UnaryOp::F64x2Splat
+            | UnaryOp::F64x2ExtractLane { .. }
+            | UnaryOp::V128Not
+            | UnaryOp::V128AnyTrue
+            | UnaryOp::I8x16Abs
+            | UnaryOp::I8x16Popcnt
+            | UnaryOp::I8x16Neg
+            | UnaryOp::I8x16AllTrue
+            | UnaryOp::I8x16Bitmask
+            | UnaryOp::I16x8Abs
+            | UnaryOp::I16x8Neg
+            | UnaryOp::I16x8AllTrue
+            | UnaryOp::I16x8Bitmask
+            | UnaryOp::I32x4Abs
+            | UnaryOp::I32x4Neg
+            | UnaryOp::I32x4AllTrue
+            | UnaryOp::I32x4Bitmask
+            | UnaryOp::I64x2Abs
+            | UnaryOp::I64x2Neg
+            | UnaryOp::I64x2AllTrue
+            | UnaryOp::I64x2Bitmask
+            | UnaryOp::F32x4Abs
+            | UnaryOp::F32x4Neg
+            | UnaryOp::F32x4Sqrt
+            | UnaryOp::F32x4Ceil
+            | UnaryOp::F32x4Floor
+            | UnaryOp::F32x4Trunc
+            | UnaryOp::F32x4Nearest
+            | UnaryOp::F64x2Abs
+            | UnaryOp::F64x2Neg
+            | UnaryOp::F64x2Sqrt
+            | UnaryOp::F64x2Ceil
+            | UnaryOp::F64x2Floor
+            | UnaryOp::F64x2Trunc
+            | UnaryOp::F64x2Nearest
+            | UnaryOp::I16x8ExtAddPairwiseI8x16S
+            | UnaryOp::I16x8ExtAddPairwiseI8x16U
+            | UnaryOp::I32x4ExtAddPairwiseI16x8S
+            | UnaryOp::I32x4ExtAddPairwiseI16x8U
+            | UnaryOp::I64x2ExtendLowI32x4S
+            | UnaryOp::I64x2ExtendHighI32x4S
+            | UnaryOp::I64x2ExtendLowI32x4U
+            | UnaryOp::I64x2ExtendHighI32x4U
+            | UnaryOp::I32x4TruncSatF64x2SZero
+            | UnaryOp::I32x4TruncSatF64x2UZero
+            | UnaryOp::F64x2ConvertLowI32x4S
+            | UnaryOp::F64x2ConvertLowI32x4U
+            | UnaryOp::F32x4DemoteF64x2Zero
+            | UnaryOp::F64x2PromoteLowF32x4
+            | UnaryOp::I32x4TruncSatF32x4S
+            | UnaryOp::I32x4TruncSatF32x4U
+            | UnaryOp::F32x4ConvertI32x4S
+            | UnaryOp::F32x4ConvertI32x4U
+            | UnaryOp::I16x8WidenLowI8x16S
+            | UnaryOp::I16x8WidenLowI8x16U
+            | UnaryOp::I16x8WidenHighI8x16S
+            | UnaryOp::I16x8WidenHighI8x16U
+            | UnaryOp::I32x4WidenLowI16x8S
+            | UnaryOp::I32x4WidenLowI16x8U
+            | UnaryOp::I32x4WidenHighI16x8S
+            | UnaryOp::I32x4WidenHighI16x8U
+    )
 }
 
-fn rewrite_function(func: &mut LocalFunction, gas_global: GlobalId) {
+/// What entering a block charges, the cost of all of its instructions
+pub(crate) fn block_cost(instrs: &[(Instr, InstrLocId)]) -> i32 {
+    instrs.iter().map(|(instr, _)| instr_cost(instr)).sum()
+}
+
+/// Bulk instructions whose length is the value on top of the stack
+fn is_bulk_instr(instr: &Instr) -> bool {
+    matches!(
+        instr,
+        Instr::MemoryCopy(_)
+            | Instr::MemoryFill(_)
+            | Instr::MemoryInit(_)
+            | Instr::TableCopy(_)
+            | Instr::TableFill(_)
+            | Instr::TableInit(_)
+            | Instr::TableGrow(_)
+    )
+}
+
+/// Builds a function that charges for the bytes or elements a bulk instruction is about to touch
+///
+/// It takes the length and hands it back, so a call can go right before the bulk instruction
+fn charge_bulk_function(
+    module: &mut walrus::Module,
+    gas_global: GlobalId,
+    out_of_gas: FunctionId,
+) -> FunctionId {
+    let mut func = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    let length = module.locals.add(ValType::I32);
+    let cost = module.locals.add(ValType::I32);
+    func.func_body()
+        .local_get(length)
+        .i32_const(BULK_COST_SHIFT)
+        .binop(BinaryOp::I32ShrU)
+        .local_set(cost)
+        .global_get(gas_global)
+        .local_get(cost)
+        .binop(BinaryOp::I32LtU)
+        .if_else(
+            None,
+            |then| {
+                then.local_get(cost).call(out_of_gas);
+            },
+            |_else| {},
+        )
+        .global_get(gas_global)
+        .local_get(cost)
+        .binop(BinaryOp::I32Sub)
+        .global_set(gas_global)
+        .local_get(length);
+    func.finish(vec![length], &mut module.funcs)
+}
+
+fn insert_bulk_charges(func: &mut LocalFunction, charge_bulk: FunctionId) {
     let block_ids: Vec<_> = func.blocks().map(|(block_id, _block)| block_id).collect();
     for block_id in block_ids {
-        rewrite_block(func, block_id, gas_global);
+        let block = func.block_mut(block_id);
+        if !block.instrs.iter().any(|(instr, _)| is_bulk_instr(instr)) {
+            continue;
+        }
+        let block_instrs = std::mem::take(&mut block.instrs);
+        let mut new_instrs = Vec::with_capacity(block_instrs.len() + 1);
+        for (instr, loc) in block_instrs {
+            if is_bulk_instr(&instr) {
+                new_instrs.push((Instr::Call(Call { func: charge_bulk }), loc));
+            }
+            new_instrs.push((instr, loc));
+        }
+        func.block_mut(block_id).instrs = new_instrs;
+    }
+}
+
+/// memory.atomic.wait can block the thread without running any instructions, so metering can't stop it
+fn check_atomic_wait(module: &walrus::Module) -> Result<(), Box<dyn Error>> {
+    for (func_id, func) in module.funcs.iter_local() {
+        let waits = func.blocks().any(|(_, block)| {
+            block
+                .instrs
+                .iter()
+                .any(|(instr, _)| matches!(instr, Instr::AtomicWait(_)))
+        });
+        if waits {
+            return Err(Box::new(VMError::FeatureNotAllowed(format!(
+                "{} uses memory.atomic.wait, which can block without using instructions",
+                function_name(module, func_id)
+            ))));
+        }
+    }
+    Ok(())
+}
+
+/// Checks the rewritten module so a script can't get around metering
+///
+/// Every block reachable in the script's functions has to start with a metering check charging
+/// the block's length, whose branches can only call out_of_gas, and only the check is allowed to
+/// write the gas global
+fn verify_metering(
+    module: &walrus::Module,
+    gas_global: GlobalId,
+    out_of_gas: FunctionId,
+    runtime_funcs: &[FunctionId],
+) -> Result<(), Box<dyn Error>> {
+    for (func_id, func) in module.funcs.iter_local() {
+        if runtime_funcs.contains(&func_id) {
+            continue;
+        }
+        let name = function_name(module, func_id);
+        let fail =
+            |reason: &str| VMError::MeteringVerificationFailed(format!("{}: {}", name, reason));
+
+        let mut stack = vec![func.entry_block()];
+        while let Some(block_id) = stack.pop() {
+            let instrs = &func.block(block_id).instrs;
+            let check = metering_check(instrs, gas_global)
+                .ok_or_else(|| fail("block does not start with a metering check"))?;
+
+            // The branches of the check only trap or call out_of_gas
+            for branch in [check.consequent, check.alternative] {
+                let unexpected = func.block(branch).instrs.iter().any(|(instr, _)| {
+                    !matches!(instr, Instr::Unreachable(_) | Instr::Const(_))
+                        && !matches!(instr, Instr::Call(Call { func }) if *func == out_of_gas)
+                });
+                if unexpected {
+                    return Err(Box::new(fail("metering check has unexpected instructions")));
+                }
+            }
+
+            for (instr, _) in &instrs[METERING_INSTRUCTION_COUNT..] {
+                match instr {
+                    Instr::GlobalSet(GlobalSet { global }) if *global == gas_global => {
+                        return Err(Box::new(fail("code writes the gas global")));
+                    }
+                    Instr::Block(Block { seq }) | Instr::Loop(Loop { seq }) => stack.push(*seq),
+                    Instr::IfElse(IfElse {
+                        consequent,
+                        alternative,
+                    }) => {
+                        stack.push(*consequent);
+                        stack.push(*alternative);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the if/else of the metering check if the block starts with one that charges its length
+pub(crate) fn metering_check(
+    instrs: &[(Instr, InstrLocId)],
+    gas_global: GlobalId,
+) -> Option<IfElse> {
+    if instrs.len() < METERING_INSTRUCTION_COUNT {
+        return None;
+    }
+    let expected_cost = block_cost(&instrs[METERING_INSTRUCTION_COUNT..]);
+    let prefix: Vec<&Instr> = instrs[..METERING_INSTRUCTION_COUNT]
+        .iter()
+        .map(|(instr, _)| instr)
+        .collect();
+
+    let gets_gas = |instr: &Instr| matches!(instr, Instr::GlobalGet(GlobalGet { global }) if *global == gas_global);
+    let is_cost = |instr: &Instr| matches!(instr, Instr::Const(Const { value: Value::I32(cost) }) if *cost == expected_cost);
+
+    // global.get, i32.const cost, i32.lt_u, if, global.get, i32.const cost, i32.sub, global.set
+    let charges_block = gets_gas(prefix[0])
+        && is_cost(prefix[1])
+        && matches!(
+            prefix[2],
+            Instr::Binop(Binop {
+                op: BinaryOp::I32LtU
+            })
+        )
+        && gets_gas(prefix[4])
+        && is_cost(prefix[5])
+        && matches!(
+            prefix[6],
+            Instr::Binop(Binop {
+                op: BinaryOp::I32Sub
+            })
+        )
+        && matches!(prefix[7], Instr::GlobalSet(GlobalSet { global }) if *global == gas_global);
+
+    match prefix[3] {
+        Instr::IfElse(check) if charges_block => Some(check.clone()),
+        _ => None,
+    }
+}
+
+pub(crate) fn function_name(module: &walrus::Module, func_id: FunctionId) -> String {
+    module
+        .funcs
+        .get(func_id)
+        .name
+        .clone()
+        .unwrap_or_else(|| format!("func{}", func_id.index()))
+}
+
+/// Builds a function with the same signature as the out of gas import that sets the exhausted global and traps
+fn gas_exhausted_function(module: &mut walrus::Module) -> FunctionId {
+    let exhausted_global =
+        module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+    module.exports.add(GAS_EXHAUSTED_EXPORT, exhausted_global);
+
+    let mut func = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    let cost = module.locals.add(ValType::I32);
+    func.func_body()
+        .i32_const(1)
+        .global_set(exhausted_global)
+        .unreachable();
+    func.finish(vec![cost], &mut module.funcs)
+}
+
+/// Caps the declared maximum of every memory and routes memory.grow through a helper
+/// that takes the grow out of the tick's page budget and flags the memory limit global when a grow fails
+///
+/// Returns the helper functions it added
+fn limit_memory(
+    module: &mut walrus::Module,
+    max_pages: Option<u32>,
+) -> Result<Vec<FunctionId>, Box<dyn Error>> {
+    let limit_global = module
+        .globals
+        .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+    module.exports.add(MEMORY_LIMIT_EXPORT, limit_global);
+    // Pages left to grow this tick, compared unsigned so the host can set -1 for no limit
+    let budget_global =
+        module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(-1)));
+    module.exports.add(GROW_BUDGET_EXPORT, budget_global);
+
+    let mut grow_funcs = vec![];
+    let memory_ids: Vec<_> = module.memories.iter().map(|memory| memory.id()).collect();
+    for memory_id in memory_ids {
+        if let Some(max_pages) = max_pages {
+            let memory = module.memories.get_mut(memory_id);
+            if memory.initial > max_pages {
+                return Err(Box::new(VMError::MemoryLimitExceeded));
+            }
+            memory.maximum = Some(memory.maximum.map_or(max_pages, |max| max.min(max_pages)));
+        }
+
+        let grow = grow_function(module, memory_id, limit_global, budget_global);
+        for (func_id, func) in module.funcs.iter_local_mut() {
+            if func_id != grow {
+                replace_memory_grow(func, memory_id, grow);
+            }
+        }
+        grow_funcs.push(grow);
+    }
+
+    Ok(grow_funcs)
+}
+
+/// Builds a function that behaves like memory.grow but sets the limit global when it fails,
+/// and traps when the grow is bigger than what is left of the tick's page budget
+fn grow_function(
+    module: &mut walrus::Module,
+    memory_id: MemoryId,
+    limit_global: GlobalId,
+    budget_global: GlobalId,
+) -> FunctionId {
+    let mut func = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+    let delta = module.locals.add(ValType::I32);
+    let result = module.locals.add(ValType::I32);
+    func.func_body()
+        .local_get(delta)
+        .global_get(budget_global)
+        .binop(BinaryOp::I32GtU)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(GROW_BUDGET_EXHAUSTED)
+                    .global_set(limit_global)
+                    .unreachable();
+            },
+            |_else| {},
+        )
+        .local_get(delta)
+        .memory_grow(memory_id)
+        .local_tee(result)
+        .i32_const(-1)
+        .binop(BinaryOp::I32Eq)
+        .if_else(
+            None,
+            |then| {
+                then.i32_const(GROW_REFUSED).global_set(limit_global);
+            },
+            |else_| {
+                else_
+                    .global_get(budget_global)
+                    .local_get(delta)
+                    .binop(BinaryOp::I32Sub)
+                    .global_set(budget_global);
+            },
+        )
+        .local_get(result);
+    func.finish(vec![delta], &mut module.funcs)
+}
+
+fn replace_memory_grow(func: &mut LocalFunction, memory_id: MemoryId, grow: FunctionId) {
+    let block_ids: Vec<_> = func.blocks().map(|(block_id, _block)| block_id).collect();
+    for block_id in block_ids {
+        for (instr, _) in func.block_mut(block_id).instrs.iter_mut() {
+            if let Instr::MemoryGrow(MemoryGrow { memory }) = instr {
+                if *memory == memory_id {
+                    *instr = Instr::Call(Call { func: grow });
+                }
+            }
+        }
+    }
+}
+
+/// Canonicalizes NaNs or rejects floats depending on the mode, listing offending instructions by function name
+fn enforce_float_determinism(
+    module: &mut walrus::Module,
+    mode: FloatDeterminism,
+) -> Result<(), Box<dyn Error>> {
+    let mut violations: Vec<String> = vec![];
+
+    for func in module.funcs.iter_mut() {
+        let name = func
+            .name
+            .clone()
+            .unwrap_or_else(|| format!("func{}", func.id().index()));
+        let FunctionKind::Local(local_func) = &mut func.kind else {
+            continue;
+        };
+
+        // Scratch locals to hold results while they are checked for NaN, only added if needed
+        let mut nan_locals: Option<(LocalId, LocalId)> = None;
+
+        let block_ids: Vec<_> = local_func
+            .blocks()
+            .map(|(block_id, _block)| block_id)
+            .collect();
+        for block_id in block_ids {
+            let block_instrs = std::mem::take(&mut local_func.block_mut(block_id).instrs);
+            let mut new_instrs = Vec::with_capacity(block_instrs.len());
+
+            for (instr, loc) in block_instrs {
+                if let Some(float_name) = float_instr_name(&instr) {
+                    // Vector float lanes can't be canonicalized one at a time so they are always rejected
+                    let is_violation = mode == FloatDeterminism::RejectFloats
+                        || float_name.contains("x4")
+                        || float_name.contains("x2");
+                    let violation = format!("{}: {}", name, float_name);
+                    if is_violation && !violations.contains(&violation) {
+                        violations.push(violation);
+                    }
+                }
+
+                let nan_type = nan_result_type(&instr);
+                new_instrs.push((instr, loc));
+
+                if mode != FloatDeterminism::CanonicalizeNaN {
+                    continue;
+                }
+                if let Some(ty) = nan_type {
+                    let (f32_local, f64_local) = *nan_locals.get_or_insert_with(|| {
+                        (
+                            module.locals.add(ValType::F32),
+                            module.locals.add(ValType::F64),
+                        )
+                    });
+                    let canonicalize = match ty {
+                        ValType::F32 => canonical_nan_instrs(
+                            f32_local,
+                            Value::F32(f32::from_bits(CANONICAL_NAN_F32)),
+                            BinaryOp::F32Eq,
+                        ),
+                        _ => canonical_nan_instrs(
+                            f64_local,
+                            Value::F64(f64::from_bits(CANONICAL_NAN_F64)),
+                            BinaryOp::F64Eq,
+                        ),
+                    };
+                    new_instrs.extend(
+                        canonicalize
+                            .into_iter()
+                            .map(|instr| (instr, Default::default())),
+                    );
+                }
+            }
+
+            local_func.block_mut(block_id).instrs = new_instrs;
+        }
+    }
+
+    if !violations.is_empty() {
+        return Err(Box::new(VMError::FloatDeterminismViolation(violations)));
+    }
+    Ok(())
+}
+
+const CANONICAL_NAN_F32: u32 = 0x7fc0_0000;
+const CANONICAL_NAN_F64: u64 = 0x7ff8_0000_0000_0000;
+
+/// Replaces the float on top of the stack with the canonical NaN if it is any NaN
+///
+/// local.tee tmp; const NaN; local.get tmp; local.get tmp; eq; select
+fn canonical_nan_instrs(local: LocalId, canonical_nan: Value, eq: BinaryOp) -> [Instr; 6] {
+    [
+        Instr::LocalTee(LocalTee { local }),
+        Instr::Const(Const {
+            value: canonical_nan,
+        }),
+        Instr::LocalGet(LocalGet { local }),
+        Instr::LocalGet(LocalGet { local }),
+        Instr::Binop(Binop { op: eq }),
+        Instr::Select(Select { ty: None }),
+    ]
+}
+
+/// Returns the name of the instruction if it works with f32 or f64 values
+fn float_instr_name(instr: &Instr) -> Option<String> {
+    let name = match instr {
+        Instr::Binop(Binop { op }) => format!("{:?}", op),
+        Instr::Unop(Unop { op }) => format!("{:?}", op),
+        Instr::Load(Load { kind, .. }) => format!("Load{:?}", kind),
+        Instr::Store(Store { kind, .. }) => format!("Store{:?}", kind),
+        Instr::Const(Const {
+            value: Value::F32(_),
+        }) => "F32Const".to_string(),
+        Instr::Const(Const {
+            value: Value::F64(_),
+        }) => "F64Const".to_string(),
+        _ => return None,
+    };
+    (name.contains("F32") || name.contains("F64")).then_some(name)
+}
+
+/// Returns the type of the result if the instruction can produce a NaN with an unspecified bit pattern
+fn nan_result_type(instr: &Instr) -> Option<ValType> {
+    match instr {
+        Instr::Binop(Binop { op }) => match op {
+            BinaryOp::F32Add
+            | BinaryOp::F32Sub
+            | BinaryOp::F32Mul
+            | BinaryOp::F32Div
+            | BinaryOp::F32Min
+            | BinaryOp::F32Max => Some(ValType::F32),
+            BinaryOp::F64Add
+            | BinaryOp::F64Sub
+            | BinaryOp::F64Mul
+            | BinaryOp::F64Div
+            | BinaryOp::F64Min
+            | BinaryOp::F64Max => Some(ValType::F64),
+            _ => None,
+        },
+        Instr::Unop(Unop { op }) => match op {
+            UnaryOp::F32Ceil
+            | UnaryOp::F32Floor
+            | UnaryOp::F32Trunc
+            | UnaryOp::F32Nearest
+            | UnaryOp::F32Sqrt
+            | UnaryOp::F32DemoteF64 => Some(ValType::F32),
+            UnaryOp::F64Ceil
+            | UnaryOp::F64Floor
+            | UnaryOp::F64Trunc
+            | UnaryOp::F64Nearest
+            | UnaryOp::F64Sqrt
+            | UnaryOp::F64PromoteF32 => Some(ValType::F64),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn rewrite_function(func: &mut LocalFunction, gas_global: GlobalId, out_of_gas: FunctionId) {
+    let block_ids: Vec<_> = func.blocks().map(|(block_id, _block)| block_id).collect();
+    for block_id in block_ids {
+        rewrite_block(func, block_id, gas_global, out_of_gas);
     }
 }
 
 /// Number of injected metering instructions (needed to calculate final instruction size).
-const METERING_INSTRUCTION_COUNT: usize = 8;
+pub(crate) const METERING_INSTRUCTION_COUNT: usize = 8;
 
-fn rewrite_block(func: &mut LocalFunction, block_id: InstrSeqId, gas_global: GlobalId) {
+fn rewrite_block(
+    func: &mut LocalFunction,
+    block_id: InstrSeqId,
+    gas_global: GlobalId,
+    out_of_gas: FunctionId,
+) {
     let block = func.block_mut(block_id);
     let block_instrs = &mut block.instrs;
     let block_len = block_instrs.len();
-    let block_cost = block_len as i32;
+    let block_cost = block_cost(block_instrs);
 
     let builder = func.builder_mut();
     let mut builder = builder.dangling_instr_seq(None);
@@ -63,8 +1217,10 @@ fn rewrite_block(func: &mut LocalFunction, block_id: InstrSeqId, gas_global: Glo
         .binop(BinaryOp::I32LtU)
         .if_else(
             None,
+            // Either asks the host for more instructions, which traps or pauses the script if there are none,
+            // or marks the gas as exhausted and traps
             |then| {
-                then.unreachable();
+                then.i32_const(block_cost).call(out_of_gas);
             },
             |_else| {},
         )
@@ -81,3 +1237,226 @@ fn rewrite_block(func: &mut LocalFunction, block_id: InstrSeqId, gas_global: Glo
     new_instrs.extend_from_slice(block);
     block.instrs = new_instrs;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite_wat(wat: &str, config: &VMConfig) -> Result<Vec<u8>, Box<dyn Error>> {
+        rewrite(&wat::parse_str(wat).unwrap(), config)
+    }
+
+    /// Calls an export of a rewritten module with plenty of instructions to spare
+    fn call(wasm: &[u8], name: &str, args: &[wasmer::Value]) -> Vec<wasmer::Value> {
+        let mut store = wasmer::Store::default();
+        let module = wasmer::Module::new(&store, wasm).unwrap();
+        let instance = wasmer::Instance::new(&mut store, &module, &wasmer::Imports::new()).unwrap();
+        instance
+            .exports
+            .get_function(RESET_INSTRUCTIONS_EXPORT)
+            .unwrap()
+            .call(&mut store, &[wasmer::Value::I32(1_000_000)])
+            .unwrap();
+        let function = instance.exports.get_function(name).unwrap();
+        function.call(&mut store, args).unwrap().to_vec()
+    }
+
+    fn float_config(float_determinism: FloatDeterminism) -> VMConfig {
+        VMConfig::default().with_float_determinism(float_determinism)
+    }
+
+    #[test]
+    fn nans_are_canonicalized() {
+        let wasm = rewrite_wat(
+            r#"(module
+                (func (export "add_f32") (param i32) (result i32)
+                    local.get 0
+                    f32.reinterpret_i32
+                    f32.const 1
+                    f32.add
+                    i32.reinterpret_f32)
+                (func (export "add_f64") (param i64) (result i64)
+                    local.get 0
+                    f64.reinterpret_i64
+                    f64.const 1
+                    f64.add
+                    i64.reinterpret_f64))"#,
+            &float_config(FloatDeterminism::CanonicalizeNaN),
+        )
+        .unwrap();
+
+        //NaNs with a payload or the sign bit set come out as the canonical NaN
+        for nan in [0x7fa0_0001u32, 0xffc0_0000, 0xff80_1234] {
+            let result = call(&wasm, "add_f32", &[wasmer::Value::I32(nan as i32)]);
+            assert_eq!(
+                result[0].i32(),
+                Some(CANONICAL_NAN_F32 as i32),
+                "{:#x}",
+                nan
+            );
+        }
+        for nan in [0x7ff4_0000_0000_0001u64, 0xfff8_0000_0000_0000] {
+            let result = call(&wasm, "add_f64", &[wasmer::Value::I64(nan as i64)]);
+            assert_eq!(
+                result[0].i64(),
+                Some(CANONICAL_NAN_F64 as i64),
+                "{:#x}",
+                nan
+            );
+        }
+
+        //Everything else is left alone
+        let result = call(
+            &wasm,
+            "add_f32",
+            &[wasmer::Value::I32(1f32.to_bits() as i32)],
+        );
+        assert_eq!(result[0].i32(), Some(2f32.to_bits() as i32));
+        let result = call(
+            &wasm,
+            "add_f64",
+            &[wasmer::Value::I64(1f64.to_bits() as i64)],
+        );
+        assert_eq!(result[0].i64(), Some(2f64.to_bits() as i64));
+    }
+
+    #[test]
+    fn floats_are_rejected_by_function() {
+        let error = rewrite_wat(
+            r#"(module
+                (func $scale (export "scale") (param f32) (result f32)
+                    local.get 0
+                    f32.const 2
+                    f32.mul)
+                (func $count (export "count") (param i32) (result i32)
+                    local.get 0
+                    i32.const 1
+                    i32.add))"#,
+            &float_config(FloatDeterminism::RejectFloats),
+        )
+        .unwrap_err();
+        match error.downcast_ref::<VMError>() {
+            Some(VMError::FloatDeterminismViolation(violations)) => assert_eq!(
+                violations,
+                &vec!["scale: F32Const".to_string(), "scale: F32Mul".to_string()]
+            ),
+            _ => panic!("expected a float determinism violation, got {}", error),
+        }
+    }
+
+    fn reserved_name(wat: &str) -> Option<String> {
+        match rewrite_wat(wat, &VMConfig::default()) {
+            Err(error) => match error.downcast_ref::<VMError>() {
+                Some(VMError::ReservedName(name)) => Some(name.clone()),
+                _ => panic!("expected a reserved name error, got {}", error),
+            },
+            Ok(_) => None,
+        }
+    }
+
+    #[test]
+    fn reserved_names_are_refused() {
+        assert_eq!(
+            reserved_name(r#"(module (func (export "__runner_reset_instructions")))"#),
+            Some(RESET_INSTRUCTIONS_EXPORT.to_string())
+        );
+        assert_eq!(
+            reserved_name(r#"(module (import "env" "__runner_out_of_gas" (func (param i32))))"#),
+            Some(OUT_OF_GAS_IMPORT.to_string())
+        );
+        //The remaining instructions import is only allowed with the type script_api gives it
+        assert_eq!(
+            reserved_name(
+                r#"(module (import "env" "__runner_remaining_instructions" (func (param i32))))"#
+            ),
+            Some(REMAINING_INSTRUCTIONS_IMPORT.to_string())
+        );
+        assert_eq!(
+            reserved_name(
+                r#"(module (import "env" "__runner_remaining_instructions" (func (result i32))))"#
+            ),
+            None
+        );
+    }
+
+    /// A module with the injector's metering and nothing else, and a function that isn't out_of_gas
+    fn metered_module() -> (walrus::Module, InjectedMetering, FunctionId) {
+        let wasm = wat::parse_str(
+            r#"(module
+                (func $other)
+                (func $work (export "work") (param i32) (result i32)
+                    local.get 0
+                    i32.const 1
+                    i32.add))"#,
+        )
+        .unwrap();
+        let mut module = walrus::Module::from_buffer(&wasm).unwrap();
+        let other = module.funcs.by_name("other").unwrap();
+        let metering = inject_metering(&mut module, None);
+        (module, metering, other)
+    }
+
+    fn verify(module: &walrus::Module, metering: &InjectedMetering) -> Result<(), String> {
+        verify_metering(
+            module,
+            metering.gas_global,
+            metering.out_of_gas,
+            &metering.runtime_funcs,
+        )
+        .map_err(|error| match error.downcast_ref::<VMError>() {
+            Some(VMError::MeteringVerificationFailed(reason)) => reason.clone(),
+            _ => panic!("expected a metering verification error, got {}", error),
+        })
+    }
+
+    fn work_entry(module: &mut walrus::Module) -> &mut Vec<(Instr, InstrLocId)> {
+        let work = module.funcs.by_name("work").unwrap();
+        let func = module.funcs.get_mut(work).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        &mut func.block_mut(entry).instrs
+    }
+
+    #[test]
+    fn tampered_metering_is_rejected() {
+        let (module, metering, _) = metered_module();
+        assert_eq!(verify(&module, &metering), Ok(()));
+
+        //The check calling something other than out_of_gas when the block costs too much
+        let (mut module, metering, other) = metered_module();
+        let Instr::IfElse(check) = work_entry(&mut module)[3].0.clone() else {
+            panic!("work doesn't start with a metering check");
+        };
+        let work = module.funcs.by_name("work").unwrap();
+        let func = module.funcs.get_mut(work).kind.unwrap_local_mut();
+        for branch in [check.consequent, check.alternative] {
+            for (instr, _) in func.block_mut(branch).instrs.iter_mut() {
+                if let Instr::Call(call) = instr {
+                    call.func = other;
+                }
+            }
+        }
+        assert_eq!(
+            verify(&module, &metering),
+            Err("work: metering check has unexpected instructions".to_string())
+        );
+
+        //The script writing the gas global, swapped in for an instruction of the same cost
+        let (mut module, metering, _) = metered_module();
+        let gas_global = metering.gas_global;
+        let instrs = work_entry(&mut module);
+        instrs.last_mut().unwrap().0 = Instr::GlobalSet(GlobalSet { global: gas_global });
+        assert_eq!(
+            verify(&module, &metering),
+            Err("work: code writes the gas global".to_string())
+        );
+
+        //A block that isn't charged for
+        let (mut module, metering, _) = metered_module();
+        let instrs = work_entry(&mut module);
+        *instrs = instrs.split_off(METERING_INSTRUCTION_COUNT);
+        assert_eq!(
+            verify(&module, &metering),
+            Err("work: block does not start with a metering check".to_string())
+        );
+    }
+}
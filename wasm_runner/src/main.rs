@@ -1,22 +1,62 @@
-use std::{env, fs, time::Instant};
+use std::{env, fs, time::Duration};
+
+use wasm_runner::{calibrate, default_calibration_scripts, VMConfig, WasmVM};
+
+/// Tick length budgets are recommended for when none is given
+const DEFAULT_TARGET_TICK_MICROS: u64 = 1000;
+const CALIBRATION_TICKS: u32 = 20;
+
+///Usage: wasm_runner calibrate [target tick μs]
+fn run_calibration(args: &[String]) {
+    let target = Duration::from_micros(
+        args.get(2)
+            .map(|micros| micros.parse().expect("Target tick length in μs"))
+            .unwrap_or(DEFAULT_TARGET_TICK_MICROS),
+    );
+
+    let calibration = calibrate(
+        &default_calibration_scripts(),
+        CALIBRATION_TICKS,
+        VMConfig::default(),
+    )
+    .unwrap();
+
+    for mix in &calibration.mixes {
+        println!(
+            "{:<12} {:>8.1} instructions/μs, {} per {} μs tick",
+            mix.name,
+            mix.instructions_per_micro(),
+            mix.budget_for(target),
+            target.as_micros()
+        );
+    }
+    println!(
+        "Recommended budget for a {} μs tick: {} instructions",
+        target.as_micros(),
+        calibration.recommended_budget(target)
+    );
+}
 
-use wasm_runner::WasmVM;
 fn main() {
     let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("calibrate") {
+        run_calibration(&args);
+        return;
+    }
+
     let code = fs::read_to_string(args.get(1).unwrap()).unwrap();
 
-    let mut vm = WasmVM::new(code).unwrap();
+    let mut vm: WasmVM = WasmVM::new(code).unwrap();
 
 
     for _ in 0..10 {
-        let time = Instant::now();
-        let outputs = vm.run_tick(Vec::default()).unwrap();
-        println!("Debug text: \n{}", vm.read_debug_string().unwrap());
+        let report = vm.run_tick(Vec::default());
+        println!("Debug text: \n{}", report.debug_output);
         println!(
             "Took {} μs with a instruction cost of: {}",
-            time.elapsed().as_micros(),
-            vm.get_instructions_used().unwrap()
+            report.wall_time.as_micros(),
+            report.instructions
         );
-        println!("outputs: {:#?}", outputs);
+        println!("outputs: {:#?}", report.into_result().unwrap());
     }
 }
@@ -0,0 +1,209 @@
+use std::sync::Arc;
+
+use wasmer::{
+    wasmparser::Operator, AsStoreMut, Function, FunctionEnv, FunctionEnvMut, Instance,
+    ModuleMiddleware, RuntimeError, Store, Value,
+};
+use wasmer_middlewares::{
+    metering::{get_remaining_points, set_remaining_points, MeteringPoints},
+    Metering,
+};
+
+use crate::{
+    interrupt::GasEnv,
+    limitation_injector::{
+        ATOMIC_COST, GAS_EXHAUSTED_EXPORT, GET_INSTRUCTIONS_EXPORT, RESET_INSTRUCTIONS_EXPORT,
+        SIMD_COST,
+    },
+    wasm_vm::VMError,
+    Error,
+};
+
+///Which method is used to count the instructions a script runs
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum MeteringBackend {
+    /// Rewrites the module with walrus so every block checks and decrements a gas global
+    #[default]
+    Injector,
+    /// Uses wasmer's metering middleware while the module is being compiled
+    Middleware,
+}
+
+///Creates the compiler middleware used by MeteringBackend::Middleware
+pub(crate) fn middleware(initial_limit: i32) -> Arc<dyn ModuleMiddleware> {
+    Arc::new(Metering::new(initial_limit as u64, operator_cost))
+}
+
+///What an operator costs the middleware, the same as the injector charges for the instruction
+///
+///`end` and `else` cost nothing since walrus has no instructions for them. The injector also charges
+///bulk memory and table instructions for every 8 bytes or elements they touch, which the middleware
+///can't see, so scripts leaning on them use fewer instructions under the middleware
+fn operator_cost(operator: &Operator) -> u64 {
+    match operator {
+        Operator::End | Operator::Else => 0,
+        operator => proposal_cost(operator),
+    }
+}
+
+macro_rules! cost_of_proposal {
+    (simd) => {
+        SIMD_COST as u64
+    };
+    (relaxed_simd) => {
+        SIMD_COST as u64
+    };
+    (threads) => {
+        ATOMIC_COST as u64
+    };
+    ($proposal:ident) => {
+        1
+    };
+}
+
+macro_rules! define_proposal_cost {
+    ($( @$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident)*) => {
+        ///What an operator costs going by the proposal it comes from
+        fn proposal_cost(operator: &Operator) -> u64 {
+            match operator {
+                $(Operator::$op { .. } => cost_of_proposal!($proposal),)*
+            }
+        }
+    };
+}
+
+wasmer::wasmparser::for_each_operator!(define_proposal_cost);
+
+///Gives the VM one way to reset and read the budget no matter which backend is counting
+#[derive(Clone)]
+pub(crate) enum Meter {
+    Injected {
+        reset_instructions: wasmer::Function,
+        get_instructions: wasmer::Function,
+        /// Set to 1 just before the script traps for running out of instructions
+        ///
+        /// Only there when running out traps instead of calling the out of gas import
+        gas_exhausted: Option<wasmer::Global>,
+    },
+    Middleware(Instance),
+}
+
+impl Meter {
+    pub(crate) fn new(backend: MeteringBackend, instance: &Instance) -> Result<Self, Error> {
+        Ok(match backend {
+            MeteringBackend::Injector => Meter::Injected {
+                reset_instructions: instance
+                    .exports
+                    .get_function(RESET_INSTRUCTIONS_EXPORT)?
+                    .clone(),
+                get_instructions: instance
+                    .exports
+                    .get_function(GET_INSTRUCTIONS_EXPORT)?
+                    .clone(),
+                gas_exhausted: instance
+                    .exports
+                    .get_global(GAS_EXHAUSTED_EXPORT)
+                    .ok()
+                    .cloned(),
+            },
+            MeteringBackend::Middleware => Meter::Middleware(instance.clone()),
+        })
+    }
+
+    ///Sets the amount of instructions the script is allowed to run
+    pub(crate) fn reset_instructions(
+        &self,
+        store: &mut impl AsStoreMut,
+        amount: i32,
+    ) -> Result<(), Error> {
+        match self {
+            Meter::Injected {
+                reset_instructions,
+                gas_exhausted,
+                ..
+            } => {
+                reset_instructions.call(store, &[amount.into()])?;
+                if let Some(gas_exhausted) = gas_exhausted {
+                    gas_exhausted.set(store, Value::I32(0))?;
+                }
+            }
+            Meter::Middleware(instance) => {
+                set_remaining_points(store, instance, amount.max(0) as u64);
+            }
+        }
+        Ok(())
+    }
+
+    ///Gets the amount of instructions the script has left, none once it has run out
+    pub(crate) fn get_instructions(&self, store: &mut impl AsStoreMut) -> Result<i32, Error> {
+        if self.is_exhausted(store) {
+            //The injector traps before charging the block that didn't fit, the middleware forgets what was left
+            return Ok(0);
+        }
+        match self {
+            Meter::Injected {
+                get_instructions, ..
+            } => {
+                let res = get_instructions.call(store, &[])?.to_vec();
+                if let Some(Value::I32(num)) = res.get(0) {
+                    return Ok(*num);
+                }
+                Err(Box::new(VMError::VMErrorNoGas))
+            }
+            Meter::Middleware(instance) => match get_remaining_points(store, instance) {
+                MeteringPoints::Remaining(points) => Ok(points.min(i32::MAX as u64) as i32),
+                MeteringPoints::Exhausted => Ok(0),
+            },
+        }
+    }
+
+    ///Whether the last trap came from the script running out of instructions
+    ///
+    ///Scripts using the out of gas import fail with VMError::VMProcLimitReached from the host instead
+    pub(crate) fn is_exhausted(&self, store: &mut impl AsStoreMut) -> bool {
+        match self {
+            Meter::Injected { gas_exhausted, .. } => gas_exhausted
+                .as_ref()
+                .is_some_and(|global| global.get(store).i32() == Some(1)),
+            Meter::Middleware(instance) => matches!(
+                get_remaining_points(store, instance),
+                MeteringPoints::Exhausted
+            ),
+        }
+    }
+}
+
+///State behind the remaining instructions import, filled in once the instance exists
+#[derive(Default)]
+pub(crate) struct RemainingEnv {
+    pub(crate) meter: Option<Meter>,
+    /// Set when instructions are handed out in slices, so the ones held back are counted too
+    pub(crate) gas_env: Option<FunctionEnv<GasEnv>>,
+}
+
+///Creates the host side of script_api::remaining_instructions
+///
+///The injector points direct calls at a function reading the gas global instead when it can,
+///this covers the middleware backend, instructions handed out in slices and calls through a table
+pub(crate) fn remaining_instructions_import(
+    store: &mut Store,
+) -> (Function, FunctionEnv<RemainingEnv>) {
+    let env = FunctionEnv::new(store, RemainingEnv::default());
+    let function = Function::new_typed_with_env(store, &env, remaining_instructions);
+    (function, env)
+}
+
+fn remaining_instructions(mut env: FunctionEnvMut<RemainingEnv>) -> Result<i32, RuntimeError> {
+    let (data, mut store) = env.data_and_store_mut();
+    let meter = data
+        .meter
+        .clone()
+        .expect("remaining instructions not initialized");
+    let mut remaining = meter
+        .get_instructions(&mut store)
+        .map_err(|e| RuntimeError::new(e.to_string()))? as i64;
+    if let Some(gas_env) = &data.gas_env {
+        remaining += gas_env.as_ref(&store).reserve;
+    }
+    Ok(remaining.clamp(0, i32::MAX as i64) as i32)
+}
@@ -0,0 +1,287 @@
+use wasmer::wasmparser::{Operator, Parser, Payload, TypeRef};
+
+use crate::{wasm_vm::VMError, Error};
+
+///Structural limits checked before a module is rewritten or compiled
+///
+///Keeps pathological modules from making walrus or Cranelift slow or eat memory
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ModuleLimits {
+    /// Functions defined in the module, imports not included
+    pub max_functions: u32,
+    /// Locals declared by a single function, parameters not included
+    pub max_locals_per_function: u32,
+    /// How deeply blocks, loops and ifs can be nested in a function
+    pub max_nesting_depth: u32,
+    /// Initial number of elements in a single table
+    pub max_table_elements: u32,
+    /// Bytes across all data segments
+    pub max_data_bytes: u64,
+    pub max_exports: u32,
+}
+
+impl Default for ModuleLimits {
+    fn default() -> Self {
+        Self {
+            max_functions: 100_000,
+            max_locals_per_function: 50_000,
+            max_nesting_depth: 1_024,
+            max_table_elements: 100_000,
+            max_data_bytes: 16 * 1024 * 1024,
+            max_exports: 10_000,
+        }
+    }
+}
+
+impl ModuleLimits {
+    ///Limits high enough that no real module will hit them
+    pub fn unlimited() -> Self {
+        Self {
+            max_functions: u32::MAX,
+            max_locals_per_function: u32::MAX,
+            max_nesting_depth: u32::MAX,
+            max_table_elements: u32::MAX,
+            max_data_bytes: u64::MAX,
+            max_exports: u32::MAX,
+        }
+    }
+}
+
+///Reads through the module's sections without building it and fails on the first limit it breaks
+pub(crate) fn check_module_limits(wasm: &[u8], limits: &ModuleLimits) -> Result<(), Error> {
+    let too_complex = |reason: String| -> Error { Box::new(VMError::ModuleTooComplex(reason)) };
+
+    let mut imported_functions = 0;
+    let mut defined_functions = 0;
+    let mut data_bytes: u64 = 0;
+
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let TypeRef::Func(_) = import?.ty {
+                        imported_functions += 1;
+                    }
+                }
+            }
+            Payload::FunctionSection(reader) => {
+                if reader.count() > limits.max_functions {
+                    return Err(too_complex(format!(
+                        "module defines {} functions, the limit is {}",
+                        reader.count(),
+                        limits.max_functions
+                    )));
+                }
+            }
+            Payload::TableSection(reader) => {
+                for (index, table) in reader.into_iter().enumerate() {
+                    let table = table?;
+                    if table.initial > limits.max_table_elements {
+                        return Err(too_complex(format!(
+                            "table {} has {} elements, the limit is {}",
+                            index, table.initial, limits.max_table_elements
+                        )));
+                    }
+                }
+            }
+            Payload::ExportSection(reader) => {
+                if reader.count() > limits.max_exports {
+                    return Err(too_complex(format!(
+                        "module has {} exports, the limit is {}",
+                        reader.count(),
+                        limits.max_exports
+                    )));
+                }
+            }
+            Payload::DataSection(reader) => {
+                for data in reader {
+                    data_bytes += data?.data.len() as u64;
+                    if data_bytes > limits.max_data_bytes {
+                        return Err(too_complex(format!(
+                            "data segments are over {} bytes",
+                            limits.max_data_bytes
+                        )));
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                // Numbered the same way as the function index space, imports first
+                let function = imported_functions + defined_functions;
+                defined_functions += 1;
+
+                let mut locals: u64 = 0;
+                let mut locals_reader = body.get_locals_reader()?;
+                for _ in 0..locals_reader.get_count() {
+                    let (count, _) = locals_reader.read()?;
+                    locals += count as u64;
+                }
+                if locals > limits.max_locals_per_function as u64 {
+                    return Err(too_complex(format!(
+                        "function {} declares {} locals, the limit is {}",
+                        function, locals, limits.max_locals_per_function
+                    )));
+                }
+
+                // Only blocks inside the body count, the body's own end is ignored
+                let mut depth: u32 = 0;
+                let mut operators = body.get_operators_reader()?;
+                while !operators.eof() {
+                    match operators.read()? {
+                        Operator::Block { .. }
+                        | Operator::Loop { .. }
+                        | Operator::If { .. }
+                        | Operator::Try { .. } => {
+                            depth += 1;
+                            if depth > limits.max_nesting_depth {
+                                return Err(too_complex(format!(
+                                    "function {} nests blocks more than {} deep",
+                                    function, limits.max_nesting_depth
+                                )));
+                            }
+                        }
+                        Operator::End => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks the module against the defaults with one limit changed, giving back why it was refused
+    fn check(wat: &str, limits: ModuleLimits) -> Result<(), String> {
+        check_module_limits(&wat::parse_str(wat).unwrap(), &limits).map_err(|error| {
+            match error.downcast_ref::<VMError>() {
+                Some(VMError::ModuleTooComplex(reason)) => reason.clone(),
+                _ => panic!("expected ModuleTooComplex, got {}", error),
+            }
+        })
+    }
+
+    #[test]
+    fn functions() {
+        let limits = ModuleLimits {
+            max_functions: 2,
+            ..ModuleLimits::default()
+        };
+        //Imports don't count
+        assert_eq!(
+            check(
+                r#"(module (import "env" "f" (func)) (func) (func))"#,
+                limits
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(r#"(module (func) (func) (func))"#, limits),
+            Err("module defines 3 functions, the limit is 2".to_string())
+        );
+    }
+
+    #[test]
+    fn locals() {
+        let limits = ModuleLimits {
+            max_locals_per_function: 3,
+            ..ModuleLimits::default()
+        };
+        //Parameters don't count
+        assert_eq!(
+            check(
+                r#"(module (func (param i32 i32) (local i32 i64 f32)))"#,
+                limits
+            ),
+            Ok(())
+        );
+        //Functions are numbered after the imports
+        assert_eq!(
+            check(
+                r#"(module (import "env" "f" (func)) (func) (func (local i32 i64 i32 f32)))"#,
+                limits
+            ),
+            Err("function 2 declares 4 locals, the limit is 3".to_string())
+        );
+    }
+
+    #[test]
+    fn nesting() {
+        let limits = ModuleLimits {
+            max_nesting_depth: 2,
+            ..ModuleLimits::default()
+        };
+        assert_eq!(
+            check(r#"(module (func (block (loop)) (block)))"#, limits),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (func (block (loop (if (i32.const 0) (then))))))"#,
+                limits
+            ),
+            Err("function 0 nests blocks more than 2 deep".to_string())
+        );
+    }
+
+    #[test]
+    fn table_elements() {
+        let limits = ModuleLimits {
+            max_table_elements: 10,
+            ..ModuleLimits::default()
+        };
+        assert_eq!(check(r#"(module (table 10 funcref))"#, limits), Ok(()));
+        assert_eq!(
+            check(r#"(module (table 11 funcref))"#, limits),
+            Err("table 0 has 11 elements, the limit is 10".to_string())
+        );
+    }
+
+    #[test]
+    fn data_bytes() {
+        let limits = ModuleLimits {
+            max_data_bytes: 8,
+            ..ModuleLimits::default()
+        };
+        //Counted across every segment
+        assert_eq!(
+            check(
+                r#"(module (memory 1) (data (i32.const 0) "abcd") (data (i32.const 8) "efgh"))"#,
+                limits
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (memory 1) (data (i32.const 0) "abcd") (data (i32.const 8) "efghi"))"#,
+                limits
+            ),
+            Err("data segments are over 8 bytes".to_string())
+        );
+    }
+
+    #[test]
+    fn exports() {
+        let limits = ModuleLimits {
+            max_exports: 2,
+            ..ModuleLimits::default()
+        };
+        assert_eq!(
+            check(
+                r#"(module (func (export "a")) (func (export "b")))"#,
+                limits
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            check(
+                r#"(module (func (export "a")) (func (export "b")) (memory (export "c") 1))"#,
+                limits
+            ),
+            Err("module has 3 exports, the limit is 2".to_string())
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    sync::Mutex,
+    thread::{self, available_parallelism},
+};
+
+use script_api::{Data, ScriptAction};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{report::TickReport, wasm_vm::WasmVM};
+
+///Identifies a script in a ScriptPool, handed out in increasing order as scripts are added
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScriptId(u64);
+
+///What one script did in a pool tick
+#[derive(Debug)]
+pub struct ScriptResult<O = ScriptAction> {
+    pub id: ScriptId,
+    pub report: TickReport<O>,
+}
+
+struct PoolEntry<I, O> {
+    id: ScriptId,
+    priority: i32,
+    vm: WasmVM<I, O>,
+}
+
+///Owns many VMs and runs their ticks spread over worker threads
+///
+///Each script keeps the budget and limits from the VMConfig it was built with. Higher priority scripts are
+///started first, so they finish first when there are more scripts than threads. Results always come back
+///in ScriptId order, so a simulation gives the same results whatever the thread count
+pub struct ScriptPool<I = Data, O = ScriptAction> {
+    scripts: Vec<PoolEntry<I, O>>,
+    next_id: u64,
+    threads: usize,
+}
+
+impl<I, O> Default for ScriptPool<I, O> {
+    ///A pool with a worker thread for every core
+    fn default() -> Self {
+        Self::new(available_parallelism().map_or(1, |threads| threads.get()))
+    }
+}
+
+impl<I, O> ScriptPool<I, O> {
+    ///A pool that runs ticks on up to the given number of threads
+    pub fn new(threads: usize) -> Self {
+        Self {
+            scripts: vec![],
+            next_id: 0,
+            threads: threads.max(1),
+        }
+    }
+
+    ///Adds a script to the pool, scripts with a higher priority are started first each tick
+    pub fn insert(&mut self, vm: WasmVM<I, O>, priority: i32) -> ScriptId {
+        let id = ScriptId(self.next_id);
+        self.next_id += 1;
+        self.scripts.push(PoolEntry { id, priority, vm });
+        id
+    }
+
+    ///Takes a script out of the pool
+    pub fn remove(&mut self, id: ScriptId) -> Option<WasmVM<I, O>> {
+        let index = self.index(id)?;
+        Some(self.scripts.remove(index).vm)
+    }
+
+    pub fn get(&self, id: ScriptId) -> Option<&WasmVM<I, O>> {
+        Some(&self.scripts[self.index(id)?].vm)
+    }
+
+    pub fn get_mut(&mut self, id: ScriptId) -> Option<&mut WasmVM<I, O>> {
+        let index = self.index(id)?;
+        Some(&mut self.scripts[index].vm)
+    }
+
+    ///Changes when a script is started in each tick, returns false if it isn't in the pool
+    pub fn set_priority(&mut self, id: ScriptId, priority: i32) -> bool {
+        match self.index(id) {
+            Some(index) => {
+                self.scripts[index].priority = priority;
+                true
+            }
+            None => false,
+        }
+    }
+
+    ///Every script in the pool in ScriptId order
+    pub fn ids(&self) -> impl Iterator<Item = ScriptId> + '_ {
+        self.scripts.iter().map(|entry| entry.id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.scripts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scripts.is_empty()
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    //Scripts are only ever pushed with increasing ids, so they stay sorted
+    fn index(&self, id: ScriptId) -> Option<usize> {
+        self.scripts
+            .binary_search_by_key(&id, |entry| entry.id)
+            .ok()
+    }
+}
+
+impl<I, O> ScriptPool<I, O>
+where
+    I: Serialize + Send,
+    O: DeserializeOwned + Send,
+{
+    ///Runs a tick of every script, scripts with no inputs in the map get none
+    ///
+    ///Returns a report for every script in ScriptId order, one script failing doesn't stop the others
+    pub fn run_tick(&mut self, mut inputs: BTreeMap<ScriptId, Vec<I>>) -> Vec<ScriptResult<O>> {
+        let script_count = self.scripts.len();
+        let threads = self.threads.min(script_count);
+
+        //Highest priority first, ties broken by id so the start order is the same every tick
+        let mut jobs: Vec<_> = self
+            .scripts
+            .iter_mut()
+            .map(|entry| {
+                let input = inputs.remove(&entry.id).unwrap_or_default();
+                (entry, input)
+            })
+            .collect();
+        jobs.sort_by_key(|(entry, _)| (Reverse(entry.priority), entry.id));
+
+        let queue = Mutex::new(jobs.into_iter());
+        let results = Mutex::new(Vec::with_capacity(script_count));
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    let job = queue.lock().unwrap().next();
+                    let (entry, input) = match job {
+                        Some(job) => job,
+                        None => break,
+                    };
+                    let report = entry.vm.run_tick(input);
+                    results.lock().unwrap().push(ScriptResult {
+                        id: entry.id,
+                        report,
+                    });
+                });
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|result| result.id);
+        results
+    }
+}
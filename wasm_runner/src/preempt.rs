@@ -0,0 +1,120 @@
+use script_api::preempt::ASYNCIFY_BUFFER_SIZE;
+use wasmer::{Function, FunctionEnv, Instance, Memory, Store, WasmPtr};
+
+use crate::{
+    interrupt::{GasEnv, ResumeState},
+    wasm_vm::VMError,
+    Error,
+};
+
+///Pauses and resumes a script across ticks using the asyncify exports added by wasm-opt
+pub(crate) struct Preemption {
+    env: FunctionEnv<GasEnv>,
+    data_pointer: WasmPtr<u8>,
+    stop_unwind: Function,
+    start_rewind: Function,
+}
+
+impl Preemption {
+    pub(crate) fn new(
+        store: &mut Store,
+        env: FunctionEnv<GasEnv>,
+        instance: &Instance,
+    ) -> Result<Self, Error> {
+        let data_offset: i32 = instance
+            .exports
+            .get_global("ASYNCIFY_BUFFER")?
+            .get(store)
+            .i32()
+            .ok_or(VMError::VMErrorNoAsyncifyBuffer)?;
+
+        let data = env.as_mut(store);
+        data.asyncify_pointer = data_offset;
+        data.start_unwind = Some(
+            instance
+                .exports
+                .get_function("asyncify_start_unwind")?
+                .clone(),
+        );
+        data.stop_rewind = Some(
+            instance
+                .exports
+                .get_function("asyncify_stop_rewind")?
+                .clone(),
+        );
+
+        Ok(Self {
+            env,
+            data_pointer: WasmPtr::new(data_offset as u32),
+            stop_unwind: instance
+                .exports
+                .get_function("asyncify_stop_unwind")?
+                .clone(),
+            start_rewind: instance
+                .exports
+                .get_function("asyncify_start_rewind")?
+                .clone(),
+        })
+    }
+
+    ///Whether the script is paused waiting for the next tick
+    pub(crate) fn is_suspended(&self, store: &Store) -> bool {
+        self.env.as_ref(store).state == ResumeState::Suspended
+    }
+
+    ///Marks the script as paused or not, for restoring a snapshot
+    pub(crate) fn set_suspended(&self, store: &mut Store, suspended: bool) {
+        self.env.as_mut(store).state = if suspended {
+            ResumeState::Suspended
+        } else {
+            ResumeState::Running
+        };
+    }
+
+    ///Gets the script ready to run, either setting up an empty asyncify buffer or rewinding a paused script
+    pub(crate) fn before_run(&self, store: &mut Store, memory: &Memory) -> Result<(), Error> {
+        let offset = self.data_pointer.offset() as i32;
+        if self.is_suspended(store) {
+            self.start_rewind.call(store, &[offset.into()])?;
+            self.env.as_mut(store).state = ResumeState::Rewinding;
+            return Ok(());
+        }
+
+        //The buffer starts with where the saved stack begins and ends
+        let mut header = (offset + 8).to_le_bytes().to_vec();
+        header.extend_from_slice(&(offset + ASYNCIFY_BUFFER_SIZE as i32).to_le_bytes());
+        let memory_view = memory.view(store);
+        self.data_pointer
+            .slice(&memory_view, header.len() as u32)?
+            .write_slice(&header)?;
+        Ok(())
+    }
+
+    ///Finishes unwinding if the script paused, or drops any paused state if the script failed
+    ///
+    ///A script can trap part way through unwinding or rewinding, like when its stack doesn't fit in the
+    ///asyncify buffer, so asyncify is taken back to normal running too or the next tick would carry on
+    ///unwinding or rewinding from wherever the trap left it
+    pub(crate) fn after_run(&self, store: &mut Store, succeeded: bool) -> Result<(), Error> {
+        let state = self.env.as_ref(store).state;
+        if !succeeded {
+            self.env.as_mut(store).state = ResumeState::Running;
+        }
+        match state {
+            ResumeState::Suspended => {
+                self.stop_unwind.call(store, &[])?;
+            }
+            ResumeState::Rewinding if !succeeded => {
+                let stop_rewind = self
+                    .env
+                    .as_ref(store)
+                    .stop_rewind
+                    .clone()
+                    .expect("asyncify not initialized");
+                stop_rewind.call(store, &[])?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
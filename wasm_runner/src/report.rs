@@ -0,0 +1,110 @@
+use std::time::Duration;
+
+use script_api::ScriptAction;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use wasmer::RuntimeError;
+
+use crate::{wasm_vm::VMError, Error};
+
+///Everything about one tick of a script, gathered in one pass by run_tick so it can be sent on as telemetry
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TickReport<O = ScriptAction> {
+    /// Actions the script emitted, empty if the tick failed
+    pub actions: Vec<O>,
+    pub instructions: i32,
+    pub debug_output: String,
+    pub wall_time: Duration,
+    /// Size of the script's memory when the tick ended
+    pub memory_pages: u32,
+    pub outcome: TickOutcome,
+}
+
+impl<O> TickReport<O> {
+    pub fn is_ok(&self) -> bool {
+        !matches!(self.outcome, TickOutcome::Failed(_))
+    }
+
+    ///The actions, or why the tick failed, for callers that only care about those
+    pub fn into_result(self) -> Result<Vec<O>, TickError> {
+        match self.outcome {
+            TickOutcome::Failed(error) => Err(error),
+            _ => Ok(self.actions),
+        }
+    }
+}
+
+///How a tick ended
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TickOutcome {
+    Completed,
+    /// A preemptible script ran out of instructions and resumes where it was next tick
+    Suspended,
+    Failed(TickError),
+}
+
+///Why a tick failed, with the message of the error behind it
+#[derive(Error, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[error("{kind:?}: {message}")]
+pub struct TickError {
+    pub kind: TickErrorKind,
+    pub message: String,
+}
+
+///Broad classes of tick failure, for grouping telemetry
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TickErrorKind {
+    /// The script panicked, the message is the panic's
+    Panic,
+    OutOfInstructions,
+    /// The script was refused memory or grew it by more than a tick allows
+    MemoryLimit,
+    /// Too many actions, host calls or too much debug text, input or output
+    ResourceLimit,
+    Interrupted,
+    Timeout,
+    /// Inputs or actions the script and host couldn't decode
+    MalformedData,
+    HostFunction,
+    /// Any other wasm trap, like an unreachable or a bad memory access
+    Trap,
+    /// The runner itself failed to set up or read back the tick
+    Runner,
+}
+
+impl TickError {
+    pub(crate) fn from_error(e: &Error) -> Self {
+        let kind = match e.downcast_ref::<VMError>() {
+            Some(vm_error) => match vm_error {
+                VMError::VMPanic(panic) => {
+                    return Self {
+                        kind: TickErrorKind::Panic,
+                        message: panic.clone(),
+                    }
+                }
+                VMError::VMProcLimitReached => TickErrorKind::OutOfInstructions,
+                VMError::MemoryLimitExceeded | VMError::MemoryGrowLimitReached => {
+                    TickErrorKind::MemoryLimit
+                }
+                VMError::ActionLimitReached
+                | VMError::DebugTextLimitReached
+                | VMError::HostCallLimitReached
+                | VMError::InputTooLarge(..)
+                | VMError::OutputTooLarge(..) => TickErrorKind::ResourceLimit,
+                VMError::Interrupted => TickErrorKind::Interrupted,
+                VMError::Timeout => TickErrorKind::Timeout,
+                VMError::MalformedInput(_) | VMError::MalformedOutput(_) => {
+                    TickErrorKind::MalformedData
+                }
+                VMError::HostFunctionFailed(..) => TickErrorKind::HostFunction,
+                _ => TickErrorKind::Runner,
+            },
+            None if e.is::<RuntimeError>() => TickErrorKind::Trap,
+            None => TickErrorKind::Runner,
+        };
+        Self {
+            kind,
+            message: e.to_string(),
+        }
+    }
+}
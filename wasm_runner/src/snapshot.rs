@@ -0,0 +1,212 @@
+use script_api::{frame, GuestBuffer};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use wasmer::Value;
+
+use crate::{wasm_vm::VMError, Error};
+
+pub const SNAPSHOT_MAGIC: [u8; 4] = *b"WSNP";
+///Bumped whenever what a snapshot holds changes, snapshots from other versions are rejected
+pub const SNAPSHOT_VERSION: u16 = 3;
+///A snapshot is laid out like a script_api frame with its own magic and version, a little endian header of the
+///magic, format version (u16), payload length (u32) and payload checksum (u32), then a bincode payload
+const SNAPSHOT_HEADER_SIZE: usize = 4 + 2 + 4 + 4;
+
+///Hash of the module a VM is running, after the injector rewrote it
+pub type ModuleHash = [u8; 32];
+
+pub(crate) fn module_hash(wasm: &[u8]) -> ModuleHash {
+    Sha256::digest(wasm).into()
+}
+
+///A global's value in a form that can be serialized, floats are kept as their bits
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+    V128(u128),
+}
+
+impl GlobalValue {
+    pub(crate) fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::I32(value) => Some(GlobalValue::I32(*value)),
+            Value::I64(value) => Some(GlobalValue::I64(*value)),
+            Value::F32(value) => Some(GlobalValue::F32(value.to_bits())),
+            Value::F64(value) => Some(GlobalValue::F64(value.to_bits())),
+            Value::V128(value) => Some(GlobalValue::V128(*value)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn to_value(self) -> Value {
+        match self {
+            GlobalValue::I32(value) => Value::I32(value),
+            GlobalValue::I64(value) => Value::I64(value),
+            GlobalValue::F32(bits) => Value::F32(f32::from_bits(bits)),
+            GlobalValue::F64(bits) => Value::F64(f64::from_bits(bits)),
+            GlobalValue::V128(value) => Value::V128(value),
+        }
+    }
+}
+
+///Everything a script needs to carry on from the tick it was taken after, see WasmVM::snapshot
+///
+///Linear memory holds the script's own state, including whether it has been initialized, and the asyncify
+///buffer a paused script's stack was unwound into. The mutable globals hold the stack pointer and instruction
+///counter. Can only be restored onto a VM running the same module
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmSnapshot {
+    pub(crate) module_hash: ModuleHash,
+    pub(crate) memory: Vec<u8>,
+    pub(crate) globals: Vec<(String, GlobalValue)>,
+    pub(crate) instructions: i32,
+    /// Instructions held back from the script to be handed out a slice at a time
+    pub(crate) gas_reserve: i64,
+    pub(crate) budget_balance: i64,
+    /// Whether a preemptible script was paused part way through a tick
+    pub(crate) suspended: bool,
+    pub(crate) stale_buffers: Vec<GuestBuffer>,
+}
+
+impl VmSnapshot {
+    ///Hash of the rewritten module the snapshot was taken from
+    pub fn module_hash(&self) -> &ModuleHash {
+        &self.module_hash
+    }
+
+    ///Size of the script's linear memory in bytes
+    pub fn memory_size(&self) -> usize {
+        self.memory.len()
+    }
+
+    ///Serializes the snapshot behind a magic value, format version and checksum, for save games and the network
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let payload = bincode::serialize(self)?;
+        let length = u32::try_from(payload.len()).map_err(|_| {
+            VMError::InvalidSnapshot(format!("{} bytes is too large", payload.len()))
+        })?;
+
+        let mut bytes = Vec::with_capacity(SNAPSHOT_HEADER_SIZE + payload.len());
+        bytes.extend_from_slice(&SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&length.to_le_bytes());
+        bytes.extend_from_slice(&frame::checksum(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = |reason: String| -> Error { Box::new(VMError::InvalidSnapshot(reason)) };
+
+        if bytes.len() < SNAPSHOT_HEADER_SIZE || bytes[0..4] != SNAPSHOT_MAGIC {
+            return Err(invalid("not a snapshot".to_string()));
+        }
+        let (header, payload) = bytes.split_at(SNAPSHOT_HEADER_SIZE);
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != SNAPSHOT_VERSION {
+            return Err(invalid(format!(
+                "snapshot is version {} but only version {} is supported",
+                version, SNAPSHOT_VERSION
+            )));
+        }
+        let length = u32::from_le_bytes([header[6], header[7], header[8], header[9]]);
+        if length as usize != payload.len() {
+            return Err(invalid(format!(
+                "snapshot says it is {} bytes but it is {}",
+                length,
+                payload.len()
+            )));
+        }
+        let checksum = u32::from_le_bytes([header[10], header[11], header[12], header[13]]);
+        if checksum != frame::checksum(payload) {
+            return Err(invalid("checksum doesn't match".to_string()));
+        }
+        bincode::deserialize(payload).map_err(|e| invalid(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot() -> VmSnapshot {
+        VmSnapshot {
+            module_hash: [7; 32],
+            memory: vec![1, 2, 3, 4],
+            globals: vec![
+                ("__runner_global_0".to_string(), GlobalValue::I32(-5)),
+                (
+                    "__runner_global_1".to_string(),
+                    GlobalValue::F64(1.5_f64.to_bits()),
+                ),
+            ],
+            instructions: 1234,
+            gas_reserve: 5678,
+            budget_balance: -20,
+            suspended: true,
+            stale_buffers: vec![GuestBuffer {
+                pointer: 64,
+                length: 8,
+            }],
+        }
+    }
+
+    fn invalid_reason(bytes: &[u8]) -> String {
+        match VmSnapshot::from_bytes(bytes)
+            .unwrap_err()
+            .downcast::<VMError>()
+        {
+            Ok(error) => match *error {
+                VMError::InvalidSnapshot(reason) => reason,
+                other => panic!("unexpected error {:?}", other),
+            },
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let bytes = snapshot().to_bytes().unwrap();
+        assert_eq!(bytes[..4], SNAPSHOT_MAGIC);
+        assert_eq!(VmSnapshot::from_bytes(&bytes).unwrap(), snapshot());
+    }
+
+    #[test]
+    fn only_one_header() {
+        let bytes = snapshot().to_bytes().unwrap();
+        let payload = bincode::serialize(&snapshot()).unwrap();
+        assert_eq!(bytes.len(), SNAPSHOT_HEADER_SIZE + payload.len());
+        assert_eq!(bytes[SNAPSHOT_HEADER_SIZE..], payload);
+    }
+
+    #[test]
+    fn bad_magic_is_rejected() {
+        let mut bytes = snapshot().to_bytes().unwrap();
+        bytes[0] = b'X';
+        assert_eq!(invalid_reason(&bytes), "not a snapshot");
+        assert_eq!(invalid_reason(&[]), "not a snapshot");
+        //A script_api frame isn't a snapshot even though it is laid out the same way
+        let frame = frame::encode(&snapshot()).unwrap();
+        assert_eq!(invalid_reason(&frame), "not a snapshot");
+    }
+
+    #[test]
+    fn other_versions_are_rejected() {
+        let mut bytes = snapshot().to_bytes().unwrap();
+        bytes[4..6].copy_from_slice(&(SNAPSHOT_VERSION - 1).to_le_bytes());
+        assert!(invalid_reason(&bytes).contains("version"));
+    }
+
+    #[test]
+    fn truncated_or_corrupted_snapshots_are_rejected() {
+        let bytes = snapshot().to_bytes().unwrap();
+        assert!(invalid_reason(&bytes[..bytes.len() - 1]).contains("bytes"));
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(invalid_reason(&corrupted).contains("checksum"));
+    }
+}
@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use wasmer::{Engine, Module};
+
+use crate::{
+    compiler::compile,
+    coverage::Coverage,
+    engine::create_engine,
+    features::check_features,
+    gas_analysis::GasReport,
+    limitation_injector::rewrite,
+    module_limits::check_module_limits,
+    snapshot::{module_hash, ModuleHash},
+    vm_config::VMConfig,
+    Error,
+};
+
+///A script compiled once, that any number of VMs can be created from with WasmVM::from_template
+///
+///Checking, rewriting and compiling the module all happen here, so creating a VM only has to instantiate
+///it. Each VM gets its own memory, globals and Script, nothing a script does is seen by the others.
+///Templates are shared behind an Arc, so they can be handed to VMs on other threads
+pub struct ScriptTemplate {
+    pub(crate) engine: Engine,
+    pub(crate) module: Module,
+    pub(crate) coverage: Option<Coverage>,
+    pub(crate) gas_report: GasReport,
+    pub(crate) module_hash: ModuleHash,
+    pub(crate) config: VMConfig,
+}
+
+impl ScriptTemplate {
+    pub fn new(code: String) -> Result<Arc<Self>, Error> {
+        Self::with_config(code, VMConfig::default())
+    }
+
+    ///Compiles a script with the limits in the given config, every VM created from it has that config
+    pub fn with_config(code: String, config: VMConfig) -> Result<Arc<Self>, Error> {
+        let wasm_data = compile(code)?;
+        Self::from_wasm(&wasm_data, config)
+    }
+
+    ///Rewrites and compiles an already compiled script, putting the limits in the config onto it
+    pub fn from_wasm(wasm: &[u8], config: VMConfig) -> Result<Arc<Self>, Error> {
+        check_module_limits(wasm, &config.module_limits)?;
+        check_features(wasm, &config.proposals)?;
+        config.host_functions.check_names()?;
+        let rewritten = rewrite(wasm, &config)?;
+        let coverage = rewritten
+            .coverage
+            .map(|coverage_map| Coverage::new(coverage_map, wasm));
+
+        let engine = create_engine(&config)?;
+        let module_hash = module_hash(&rewritten.wasm);
+        let module = Module::new(&engine, rewritten.wasm)?;
+
+        Ok(Arc::new(Self {
+            engine,
+            module,
+            coverage,
+            gas_report: rewritten.gas_report,
+            module_hash,
+            config,
+        }))
+    }
+
+    ///Static instruction costs of the script's functions and its call graph
+    pub fn gas_report(&self) -> &GasReport {
+        &self.gas_report
+    }
+
+    ///Hash of the module after the injector rewrote it, the same for every VM made from this template
+    pub fn module_hash(&self) -> &ModuleHash {
+        &self.module_hash
+    }
+
+    pub fn config(&self) -> &VMConfig {
+        &self.config
+    }
+}
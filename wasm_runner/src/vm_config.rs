@@ -0,0 +1,232 @@
+use std::time::Duration;
+
+use crate::{
+    budget::{BudgetPolicy, ResourceLimits},
+    engine::CompilerBackend,
+    features::WasmProposals,
+    host::HostFunctions,
+    interrupt::INTERRUPT_CHECK_INTERVAL,
+    limitation_injector::FloatDeterminism,
+    metering::MeteringBackend,
+    module_limits::ModuleLimits,
+};
+
+/// Size of a single wasm memory page in bytes
+pub const WASM_PAGE_SIZE: u64 = 65536;
+
+/// Default cap on a script's linear memory (64 MiB), VMConfig::default and WasmVM::new put it on every script.
+/// Use with_memory_limit_pages or set max_memory_pages to None for scripts that need more
+pub const DEFAULT_MEMORY_LIMIT_PAGES: u32 = 1024;
+
+/// Default cap on the serialized inputs or actions of a tick (1 MiB)
+pub const DEFAULT_MAX_BUFFER_BYTES: usize = 1024 * 1024;
+
+///Settings used when building a WasmVM
+#[derive(Clone, Debug)]
+pub struct VMConfig {
+    /// Maximum number of wasm pages the script's memory can grow to, None means no limit
+    pub max_memory_pages: Option<u32>,
+    /// How instructions are counted and limited
+    pub metering: MeteringBackend,
+    /// Which compiler turns the script into machine code
+    pub compiler: CompilerBackend,
+    /// How many instructions the script gets each tick
+    pub budget: BudgetPolicy,
+    /// Whether float NaNs are canonicalized or floats are rejected outright
+    pub float_determinism: FloatDeterminism,
+    /// Pause scripts that run out of instructions and resume them next tick instead of failing the tick
+    pub preemptible: bool,
+    /// Lets a running tick be stopped through WasmVM::interrupt_handle
+    pub interruptible: bool,
+    /// Wall clock time a tick can run for before failing with VMError::Timeout
+    pub timeout: Option<Duration>,
+    /// Instructions a script runs between checks for interrupts and timeouts
+    pub interrupt_check_interval: i32,
+    /// Records which blocks of the script run, see WasmVM::coverage_report
+    pub coverage: bool,
+    /// Limits on memory growth, actions, debug text and host calls in a tick
+    pub resource_limits: ResourceLimits,
+    /// Refuses to load scripts whose export_run always costs more than a tick can ever allow
+    pub reject_over_budget: bool,
+    /// Structural limits a module has to be within before it is rewritten and compiled
+    pub module_limits: ModuleLimits,
+    /// Wasm proposals the script is allowed to use, checked before the module is rewritten
+    pub proposals: WasmProposals,
+    /// Functions on the host that scripts can import and call
+    pub host_functions: HostFunctions,
+    /// Largest serialized inputs run_tick passes to the script, bigger ones fail with VMError::InputTooLarge
+    pub max_input_bytes: usize,
+    /// Largest serialized actions read back from the script, bigger ones fail with VMError::OutputTooLarge
+    pub max_output_bytes: usize,
+}
+
+impl VMConfig {
+    ///Sets the memory limit in bytes, rounded down to whole wasm pages so the script never gets more than asked for
+    ///
+    ///A limit under one page allows no memory at all, and any script with memory is refused when loading.
+    ///ResourceLimits::memory_grow_bytes is rounded down the same way
+    pub fn with_memory_limit_bytes(mut self, bytes: u64) -> Self {
+        self.max_memory_pages = Some((bytes / WASM_PAGE_SIZE).min(u32::MAX as u64) as u32);
+        self
+    }
+
+    ///Sets which metering backend counts instructions
+    pub fn with_metering(mut self, metering: MeteringBackend) -> Self {
+        self.metering = metering;
+        self
+    }
+
+    ///Sets which compiler the script is built with, its cargo feature has to be turned on
+    pub fn with_compiler(mut self, compiler: CompilerBackend) -> Self {
+        self.compiler = compiler;
+        self
+    }
+
+    ///Sets how the instruction budget is refilled between ticks
+    pub fn with_budget(mut self, budget: BudgetPolicy) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    ///Sets how floating point instructions are handled for lockstep determinism
+    pub fn with_float_determinism(mut self, float_determinism: FloatDeterminism) -> Self {
+        self.float_determinism = float_determinism;
+        self
+    }
+
+    ///Lets long running scripts pause when out of instructions and pick up again next tick
+    ///
+    ///Needs wasm-opt from binaryen to be installed and the injector metering backend
+    pub fn with_preemption(mut self, preemptible: bool) -> Self {
+        self.preemptible = preemptible;
+        self
+    }
+
+    ///Lets another thread stop a running tick with an InterruptHandle
+    pub fn with_interrupts(mut self, interruptible: bool) -> Self {
+        self.interruptible = interruptible;
+        self
+    }
+
+    ///Fails any tick that runs longer than the timeout
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    ///Sets how many instructions a script runs between checks for interrupts and timeouts
+    ///
+    ///An interrupt or timeout can take up to this many instructions to be noticed, plus the cost of the block
+    ///running when the last check was made. Smaller intervals stop ticks sooner but check more often
+    pub fn with_interrupt_check_interval(mut self, interrupt_check_interval: i32) -> Self {
+        self.interrupt_check_interval = interrupt_check_interval;
+        self
+    }
+
+    ///Records which blocks of the script run so it can be reported as lcov
+    pub fn with_coverage(mut self, coverage: bool) -> Self {
+        self.coverage = coverage;
+        self
+    }
+
+    ///Sets the per tick limits on resources other than instructions
+    ///
+    ///The memory growth limit is rounded down to whole wasm pages like with_memory_limit_bytes, so one under a
+    ///page lets the script grow by nothing
+    pub fn with_resource_limits(mut self, resource_limits: ResourceLimits) -> Self {
+        self.resource_limits = resource_limits;
+        self
+    }
+
+    ///Checks the static cost of export_run when loading and refuses scripts that can never finish in budget
+    pub fn with_budget_check(mut self, reject_over_budget: bool) -> Self {
+        self.reject_over_budget = reject_over_budget;
+        self
+    }
+
+    ///Sets the limits on function count, locals, nesting, tables, data and exports
+    pub fn with_module_limits(mut self, module_limits: ModuleLimits) -> Self {
+        self.module_limits = module_limits;
+        self
+    }
+
+    ///Sets which wasm proposals scripts are allowed to use
+    pub fn with_proposals(mut self, proposals: WasmProposals) -> Self {
+        self.proposals = proposals;
+        self
+    }
+
+    ///Sets the host functions scripts are linked against
+    pub fn with_host_functions(mut self, host_functions: HostFunctions) -> Self {
+        self.host_functions = host_functions;
+        self
+    }
+
+    ///Sets how big the serialized inputs and actions of a tick can be
+    pub fn with_buffer_limits(mut self, max_input_bytes: usize, max_output_bytes: usize) -> Self {
+        self.max_input_bytes = max_input_bytes;
+        self.max_output_bytes = max_output_bytes;
+        self
+    }
+
+    ///Whether the injected metering checks need to call back into the host
+    pub(crate) fn uses_gas_import(&self) -> bool {
+        self.preemptible || self.checks_interrupts()
+    }
+
+    pub(crate) fn checks_interrupts(&self) -> bool {
+        self.interruptible || self.timeout.is_some()
+    }
+
+    ///Sets the memory limit in wasm pages
+    pub fn with_memory_limit_pages(mut self, pages: u32) -> Self {
+        self.max_memory_pages = Some(pages);
+        self
+    }
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        Self {
+            max_memory_pages: Some(DEFAULT_MEMORY_LIMIT_PAGES),
+            metering: MeteringBackend::default(),
+            compiler: CompilerBackend::default(),
+            budget: BudgetPolicy::default(),
+            float_determinism: FloatDeterminism::default(),
+            preemptible: false,
+            interruptible: false,
+            timeout: None,
+            interrupt_check_interval: INTERRUPT_CHECK_INTERVAL,
+            coverage: false,
+            resource_limits: ResourceLimits::default(),
+            reject_over_budget: false,
+            module_limits: ModuleLimits::default(),
+            proposals: WasmProposals::default(),
+            host_functions: HostFunctions::default(),
+            max_input_bytes: DEFAULT_MAX_BUFFER_BYTES,
+            max_output_bytes: DEFAULT_MAX_BUFFER_BYTES,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_limit_bytes_round_down_to_pages() {
+        let pages = |bytes| {
+            VMConfig::default()
+                .with_memory_limit_bytes(bytes)
+                .max_memory_pages
+        };
+        assert_eq!(pages(0), Some(0));
+        assert_eq!(pages(1), Some(0));
+        assert_eq!(pages(WASM_PAGE_SIZE - 1), Some(0));
+        assert_eq!(pages(WASM_PAGE_SIZE), Some(1));
+        assert_eq!(pages(WASM_PAGE_SIZE + 1), Some(1));
+        assert_eq!(pages(100 * 1024), Some(1));
+        assert_eq!(pages(64 * 1024 * 1024), Some(DEFAULT_MEMORY_LIMIT_PAGES));
+        assert_eq!(pages(u64::MAX), Some(u32::MAX));
+    }
+}
@@ -1,36 +1,129 @@
-use crate::{compiler::compile, Error};
+use crate::{
+    budget::{Budget, TickUsage},
+    compiler::compile,
+    coverage::CoverageReport,
+    engine::CompilerBackend,
+    gas_analysis::GasReport,
+    host::{self, HostEnv},
+    interrupt::{self, GasEnv, InterruptHandle},
+    limitation_injector::{
+        GET_INSTRUCTIONS_EXPORT, GLOBAL_EXPORT_PREFIX, GROW_BUDGET_EXHAUSTED, GROW_BUDGET_EXPORT,
+        GROW_REFUSED, MEMORY_LIMIT_EXPORT, REMAINING_INSTRUCTIONS_IMPORT,
+        RESET_INSTRUCTIONS_EXPORT,
+    },
+    metering::{self, Meter},
+    preempt::Preemption,
+    report::{TickError, TickOutcome, TickReport},
+    snapshot::{GlobalValue, ModuleHash, VmSnapshot},
+    template::ScriptTemplate,
+    vm_config::{VMConfig, WASM_PAGE_SIZE},
+    Error,
+};
 use script_api::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{marker::PhantomData, sync::Arc, time::Instant};
 use thiserror::Error;
-use wasmer::{imports, Cranelift, Instance, MemoryView, Module, Store, Value, WasmPtr};
+use wasmer::{imports, Extern, FunctionEnv, Instance, MemoryView, Pages, Value, WasmPtr};
 
-const INSTRUCTIONS_PER_TICK: i32 = 1_000_000;
-
-pub struct WasmVM {
+///A loaded script, `I` is what the host passes in each tick and `O` the actions the script emits
+///
+///Both sides only have to agree on how the types serialize, the script declares its own copies and uses
+///script_api::read_inputs and script_api::emit. Defaults to the example Data and ScriptAction
+pub struct WasmVM<I = Data, O = ScriptAction> {
     store: wasmer::Store,
     memory: wasmer::Memory,
     script_output_pointer: WasmPtr<u8>,
     input_pointer: WasmPtr<u8>,
+    output_error_pointer: WasmPtr<u8>,
+    input_error_pointer: WasmPtr<u8>,
     panic_pointer: WasmPtr<u8>,
     run: wasmer::Function,
-    reset_instructions: wasmer::Function,
-    get_instructions: wasmer::Function,
+    meter: Meter,
     debug_text_pointer: WasmPtr<u8>,
-    get_text_size: wasmer::Function,
     erase_text: wasmer::Function,
+    allocate_buffer: wasmer::Function,
+    free_buffer: wasmer::Function,
+    /// Frames the actions the script emitted once the run is over
+    finish_output: wasmer::Function,
+    discard_output: wasmer::Function,
+    /// Reload hooks, only there when the script calls script_api::reload_hooks!
+    save_state: Option<wasmer::Function>,
+    load_state: Option<wasmer::Function>,
+    state_pointer: Option<WasmPtr<u8>>,
+    state_error_pointer: Option<WasmPtr<u8>>,
+    /// Output buffers taken from the script while it was suspended, freed once it finishes
+    stale_buffers: Vec<GuestBuffer>,
+    memory_limit_reached: Option<wasmer::Global>,
+    grow_budget: Option<wasmer::Global>,
+    /// Every mutable global in the module, saved and restored by snapshots
+    globals: Vec<(String, wasmer::Global)>,
+    /// What the script was instantiated from, shared with every other VM made from it
+    template: Arc<ScriptTemplate>,
+    gas_env: Option<FunctionEnv<GasEnv>>,
+    host_env: FunctionEnv<HostEnv>,
+    preemption: Option<Preemption>,
+    interrupt: InterruptHandle,
+    budget: Budget,
+    usage: TickUsage,
+    config: VMConfig,
+    io: PhantomData<fn(Vec<I>) -> Vec<O>>,
 }
 
-impl WasmVM {
+impl<I: Serialize, O: DeserializeOwned> WasmVM<I, O> {
     pub fn new(code: String) -> Result<Self, Error> {
+        Self::with_config(code, VMConfig::default())
+    }
+
+    ///Compiles and loads a script using the limits in the given config
+    pub fn with_config(code: String, config: VMConfig) -> Result<Self, Error> {
         //Take the text code and compile it into a wasm module to be loaded
         let wasm_data = compile(code)?;
-        let mut store = Store::new(Cranelift::new());
-        let module = Module::new(&store, wasm_data)?;
+        Self::from_wasm(&wasm_data, config)
+    }
+
+    ///Loads an already compiled script, putting the limits in the config onto it
+    pub fn from_wasm(wasm: &[u8], config: VMConfig) -> Result<Self, Error> {
+        Self::from_template(&ScriptTemplate::from_wasm(wasm, config)?)
+    }
+
+    ///Creates a new instance of an already compiled script, with its own memory and Script
+    ///
+    ///Much cheaper than from_wasm since nothing has to be rewritten or compiled again
+    pub fn from_template(template: &Arc<ScriptTemplate>) -> Result<Self, Error> {
+        let config = template.config.clone();
+        let mut store = wasmer::Store::new(template.engine.clone());
 
         //Get the necessary variable pointers
-        let import_object = imports! {};
-        let instance = Instance::new(&mut store, &module, &import_object)?;
+        let (mut import_object, gas_env) = if config.uses_gas_import() {
+            //Without interrupts the whole tick's instructions can be handed out at once
+            let check_interval = if config.checks_interrupts() {
+                config.interrupt_check_interval
+            } else {
+                i32::MAX
+            };
+            let (import_object, env) = interrupt::imports(
+                &mut store,
+                check_interval,
+                config.budget.max_allowance(),
+                config.preemptible,
+            );
+            (import_object, Some(env))
+        } else {
+            (imports! {}, None)
+        };
+        let (remaining_instructions, remaining_env) =
+            metering::remaining_instructions_import(&mut store);
+        import_object.define("env", REMAINING_INSTRUCTIONS_IMPORT, remaining_instructions);
+        let host_env = host::define_imports(
+            &mut store,
+            &mut import_object,
+            &config.host_functions,
+            config.resource_limits.host_calls,
+        );
+        let instance = Instance::new(&mut store, &template.module, &import_object)?;
 
         let memory = instance.exports.get_memory("memory")?.clone();
+        host_env.as_mut(&mut store).memory = Some(memory.clone());
 
         let action_offset: i32 = instance
             .exports
@@ -48,6 +141,22 @@ impl WasmVM {
             .ok_or(VMError::VMInitErrorInputBuffer)?;
         let input_pointer: WasmPtr<u8> = WasmPtr::new(input_offset as u32);
 
+        let output_error_offset: i32 = instance
+            .exports
+            .get_global("SCRIPT_OUTPUT_ERROR")?
+            .get(&mut store)
+            .i32()
+            .ok_or(VMError::VMInitErrorScriptOutput)?;
+        let output_error_pointer: WasmPtr<u8> = WasmPtr::new(output_error_offset as u32);
+
+        let input_error_offset: i32 = instance
+            .exports
+            .get_global("DATA_INPUT_ERROR")?
+            .get(&mut store)
+            .i32()
+            .ok_or(VMError::VMInitErrorInputBuffer)?;
+        let input_error_pointer: WasmPtr<u8> = WasmPtr::new(input_error_offset as u32);
+
         let panic_buffer_offset: i32 = instance
             .exports
             .get_global("PANIC_BUFFER")?
@@ -66,117 +175,626 @@ impl WasmVM {
 
         //Get functions needed to run script
         let run = instance.exports.get_function("export_run")?.clone();
-        let reset_instructions = instance.exports.get_function("reset_instructions")?.clone();
-        let get_instructions = instance.exports.get_function("get_instructions")?.clone();
-        let get_text_size = instance.exports.get_function("get_text_size")?.clone();
+        let meter = Meter::new(config.metering, &instance)?;
+
+        let interrupt = InterruptHandle::default();
+        let mut preemption = None;
+        let remaining_data = remaining_env.as_mut(&mut store);
+        remaining_data.meter = Some(meter.clone());
+        remaining_data.gas_env = gas_env.clone();
+
+        if let Some(env) = &gas_env {
+            let data = env.as_mut(&mut store);
+            data.interrupt = interrupt.clone();
+            data.reset_instructions = Some(
+                instance
+                    .exports
+                    .get_function(RESET_INSTRUCTIONS_EXPORT)?
+                    .clone(),
+            );
+            data.get_instructions = Some(
+                instance
+                    .exports
+                    .get_function(GET_INSTRUCTIONS_EXPORT)?
+                    .clone(),
+            );
+            if config.preemptible {
+                preemption = Some(Preemption::new(&mut store, env.clone(), &instance)?);
+            }
+        }
         let erase_text = instance.exports.get_function("erase_text")?.clone();
+        let allocate_buffer = instance.exports.get_function("allocate_buffer")?.clone();
+        let free_buffer = instance.exports.get_function("free_buffer")?.clone();
+        let finish_output = instance.exports.get_function("finish_output")?.clone();
+        let discard_output = instance.exports.get_function("discard_output")?.clone();
+        let save_state = instance.exports.get_function("save_state").ok().cloned();
+        let load_state = instance.exports.get_function("load_state").ok().cloned();
+        let state_pointer = instance
+            .exports
+            .get_global("SCRIPT_STATE_BUFFER")
+            .ok()
+            .and_then(|global| global.get(&mut store).i32())
+            .map(|offset| WasmPtr::new(offset as u32));
+        let state_error_pointer = instance
+            .exports
+            .get_global("SCRIPT_STATE_ERROR")
+            .ok()
+            .and_then(|global| global.get(&mut store).i32())
+            .map(|offset| WasmPtr::new(offset as u32));
+
+        //Only present when the module was rewritten with a memory limit or grow budget
+        let memory_limit_reached = instance
+            .exports
+            .get_global(MEMORY_LIMIT_EXPORT)
+            .ok()
+            .cloned();
+        let grow_budget = instance
+            .exports
+            .get_global(GROW_BUDGET_EXPORT)
+            .ok()
+            .cloned();
+        let globals = instance
+            .exports
+            .iter()
+            .filter(|(name, _)| name.starts_with(GLOBAL_EXPORT_PREFIX))
+            .filter_map(|(name, export)| match export {
+                Extern::Global(global) => Some((name.clone(), global.clone())),
+                _ => None,
+            })
+            .collect();
 
         Ok(Self {
             store,
             memory,
             script_output_pointer,
             input_pointer,
+            output_error_pointer,
+            input_error_pointer,
             panic_pointer,
             run,
-            reset_instructions,
-            get_instructions,
+            meter,
             debug_text_pointer,
-            get_text_size,
             erase_text,
+            allocate_buffer,
+            free_buffer,
+            finish_output,
+            discard_output,
+            save_state,
+            load_state,
+            state_pointer,
+            state_error_pointer,
+            stale_buffers: vec![],
+            memory_limit_reached,
+            grow_budget,
+            globals,
+            template: template.clone(),
+            gas_env,
+            host_env,
+            preemption,
+            interrupt,
+            budget: Budget::new(config.budget),
+            usage: TickUsage::default(),
+            config,
+            io: PhantomData,
         })
     }
 
+    ///Compiles new code for the script and swaps it in, carrying the script's state over
+    ///
+    ///If the old script exports a save_state hook the state it saves is handed to the new script's load_state
+    ///hook, see script_api::reload_hooks!, otherwise the new script starts from Script::new. If compiling,
+    ///loading or either hook fails the error is returned and the VM keeps running the old script, and so does
+    ///a new script without a load_state hook when the old one saved state, rather than losing it
+    pub fn reload(&mut self, code: String) -> Result<(), Error> {
+        let wasm = compile(code)?;
+        self.reload_wasm(&wasm)
+    }
+
+    ///Same as reload but with an already compiled script
+    pub fn reload_wasm(&mut self, wasm: &[u8]) -> Result<(), Error> {
+        //A paused script could be part way through changing its state
+        if self.is_suspended() {
+            return Err(Box::new(VMError::ReloadWhileSuspended));
+        }
+
+        let state = self.save_state()?;
+        let mut new_vm = Self::from_wasm(wasm, self.config.clone())?;
+        if let Some(state) = state {
+            new_vm.load_state(&state)?;
+        }
+        self.replace_instance(new_vm);
+        Ok(())
+    }
+
+    ///Swaps in another instance of a script
+    ///
+    ///Interrupt handles given out for the old instance keep working and the budget carries on where it was
+    fn replace_instance(&mut self, mut new_vm: Self) {
+        new_vm.interrupt = self.interrupt.clone();
+        if let Some(gas_env) = &new_vm.gas_env {
+            gas_env.as_mut(&mut new_vm.store).interrupt = self.interrupt.clone();
+        }
+        new_vm.budget = self.budget.clone();
+        *self = new_vm;
+    }
+
+    ///Runs the script's save_state hook and takes the frame it saved, None if there is no hook or no state
+    fn save_state(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        let (save_state, state_pointer) = match (self.save_state.clone(), self.state_pointer) {
+            (Some(save_state), Some(state_pointer)) => (save_state, state_pointer),
+            _ => return Ok(None),
+        };
+
+        //Hooks get a whole tick's worth of instructions
+        self.meter
+            .reset_instructions(&mut self.store, self.config.budget.per_tick())?;
+        self.call_outside_run(save_state, &[])?;
+
+        let state = self.read_guest_buffer(state_pointer)?;
+        self.write_guest_buffer(state_pointer, GuestBuffer::EMPTY)?;
+        if state.length as usize > self.config.max_output_bytes {
+            return Err(Box::new(VMError::OutputTooLarge(
+                state.length as usize,
+                self.config.max_output_bytes,
+            )));
+        }
+        let state_frame = self
+            .read_guest_bytes(state)
+            .map_err(|e| VMError::MalformedOutput(e.to_string()))?;
+        self.free_guest_buffer(state)?;
+
+        if state_frame.is_empty() {
+            return Ok(None);
+        }
+        frame::payload(&state_frame).map_err(|e| VMError::MalformedOutput(e.to_string()))?;
+        Ok(Some(state_frame))
+    }
+
+    ///Hands a frame saved by the old script to the load_state hook
+    ///
+    ///Fails with VMError::StateLoadFailed if there is no hook to take the state, the frame is corrupt or the
+    ///hook turns the state down
+    fn load_state(&mut self, state_frame: &[u8]) -> Result<(), Error> {
+        let (load_state, state_pointer) = match (self.load_state.clone(), self.state_pointer) {
+            (Some(load_state), Some(state_pointer)) => (load_state, state_pointer),
+            _ => {
+                return Err(Box::new(VMError::StateLoadFailed(
+                    "the new script has no load_state hook".to_string(),
+                )))
+            }
+        };
+
+        self.meter
+            .reset_instructions(&mut self.store, self.config.budget.per_tick())?;
+        let state = self.allocate_guest_buffer(state_frame.len())?;
+        let memory_view = self.memory.view(&self.store);
+        WasmPtr::<u8>::new(state.pointer)
+            .slice(&memory_view, state.length)?
+            .write_slice(state_frame)?;
+        self.write_guest_buffer(state_pointer, state)?;
+        self.call_outside_run(load_state, &[])?;
+
+        if let Some(location) = self.state_error_pointer {
+            if let Some(message) = self.read_guest_error(location)? {
+                return Err(Box::new(VMError::StateLoadFailed(message)));
+            }
+        }
+        Ok(())
+    }
+
     ///Resets a script for another run
     fn reset_script(&mut self) -> Result<(), Error> {
-        self.reset_instructions
-            .call(&mut self.store, &[INSTRUCTIONS_PER_TICK.into()])?;
-        self.erase_text.call(&mut self.store, &[])?;
+        let mut allowance = self.budget.start_tick();
 
-        let memory_view = self.memory.view(&self.store);
+        //Scripts that call back into the host are handed their instructions a slice at a time
+        if let Some(gas_env) = &self.gas_env {
+            let data = gas_env.as_mut(&mut self.store);
+            let first_slice = allowance.min(data.check_interval);
+            data.reserve = (allowance - first_slice) as i64;
+            data.deadline = self.config.timeout.map(|timeout| Instant::now() + timeout);
+            allowance = first_slice;
+        }
+        self.host_env.as_mut(&mut self.store).calls = 0;
+        self.meter.reset_instructions(&mut self.store, allowance)?;
+        self.erase_text.call(&mut self.store, &[])?;
+        if let Some(limit_global) = &self.memory_limit_reached {
+            limit_global.set(&mut self.store, Value::I32(0))?;
+        }
+        if let Some(grow_budget) = &self.grow_budget {
+            //-1 is read as the largest unsigned number, so no limit. Rounded down like the memory limit
+            let pages = match self.config.resource_limits.memory_grow_bytes {
+                Some(bytes) => (bytes / WASM_PAGE_SIZE).min(u32::MAX as u64) as u32 as i32,
+                None => -1,
+            };
+            grow_budget.set(&mut self.store, Value::I32(pages))?;
+        }
 
-        //Get the byte array to store outputs and reset it to nothing
-        let output_slice = self
-            .script_output_pointer
-            .slice(&memory_view, MAX_OUTPUT_SIZE as u32)
-            .expect("Read action slice");
-        let empty_array = [0_u8; MAX_OUTPUT_SIZE];
-        output_slice.write_slice(&empty_array)?;
+        //Take the last tick's outputs and errors away from the script, a suspended script could be part
+        //way through its allocator so nothing is freed until it finishes. The output points into the
+        //script's own pending actions, which it reuses
+        self.write_guest_buffer(self.script_output_pointer, GuestBuffer::EMPTY)?;
+        for location in [self.output_error_pointer, self.input_error_pointer] {
+            let buffer = self.read_guest_buffer(location)?;
+            self.write_guest_buffer(location, GuestBuffer::EMPTY)?;
+            self.stale_buffers.push(buffer);
+        }
+        if !self.is_suspended() {
+            for buffer in std::mem::take(&mut self.stale_buffers) {
+                self.free_guest_buffer(buffer)?;
+            }
+            //Actions left over from a tick that failed before they were finished
+            self.discard_output.call(&mut self.store, &[])?;
+        }
 
         Ok(())
     }
 
     ///Replaces the data input buffer with new data
-    fn set_input(&mut self, inputs: Inputs) -> Result<(), Error> {
+    fn set_input(&mut self, inputs: Vec<I>) -> Result<(), Error> {
+        //The script already read its inputs before it was suspended
+        if self.is_suspended() {
+            return Ok(());
+        }
+
+        let final_data = frame::encode(&inputs)?;
+        if final_data.len() > self.config.max_input_bytes {
+            return Err(Box::new(VMError::InputTooLarge(
+                final_data.len(),
+                self.config.max_input_bytes,
+            )));
+        }
+
+        //Swap the last tick's input for a buffer the script allocates to fit
+        let old_input = self.read_guest_buffer(self.input_pointer)?;
+        self.free_guest_buffer(old_input)?;
+        self.write_guest_buffer(self.input_pointer, GuestBuffer::EMPTY)?;
+        let input = self.allocate_guest_buffer(final_data.len())?;
+
         let memory_view = self.memory.view(&self.store);
+        let input_slice = WasmPtr::<u8>::new(input.pointer).slice(&memory_view, input.length)?;
+        input_slice.write_slice(&final_data)?;
+        self.write_guest_buffer(self.input_pointer, input)?;
+        Ok(())
+    }
 
-        //Serialize hashmap, get size, have the size be the first 8 bytes
-        let mut map_data = bincode::serialize(&inputs)?;
-        let mut final_data: Vec<u8> = vec![];
-        let mut map_size = (map_data.len() as u64).to_le_bytes().to_vec();
-        final_data.append(&mut map_size);
-        final_data.append(&mut map_data);
-        final_data.resize(2048, 0);
-
-        //Write devices
-        let devices_index = self.input_pointer.slice(&memory_view, 2048)?;
-        devices_index.write_slice(&mut final_data)?;
+    ///Has the script allocate bytes for the host to write into
+    fn allocate_guest_buffer(&mut self, length: usize) -> Result<GuestBuffer, Error> {
+        if length == 0 {
+            return Ok(GuestBuffer::EMPTY);
+        }
+        let res =
+            self.call_outside_run(self.allocate_buffer.clone(), &[Value::I32(length as i32)])?;
+        match res.get(0) {
+            Some(Value::I32(pointer)) if *pointer != 0 => Ok(GuestBuffer {
+                pointer: *pointer as u32,
+                length: length as u32,
+            }),
+            _ => Err(Box::new(VMError::MemoryLimitExceeded)),
+        }
+    }
+
+    ///Gives a buffer from allocate_buffer back to the script's allocator
+    fn free_guest_buffer(&mut self, buffer: GuestBuffer) -> Result<(), Error> {
+        if buffer.pointer != 0 {
+            self.call_outside_run(
+                self.free_buffer.clone(),
+                &[
+                    Value::I32(buffer.pointer as i32),
+                    Value::I32(buffer.length as i32),
+                ],
+            )?;
+        }
         Ok(())
     }
 
-    ///Parse the action buffer the script modified to get the actions that want to be performed
-    fn read_actions(&self) -> Result<Vec<ScriptAction>, Error> {
+    ///Has the script frame the actions it emitted, without charging the tick's instructions for it
+    fn finish_output(&mut self) -> Result<(), Error> {
+        let remaining = self.meter.get_instructions(&mut self.store)?;
+        self.meter.reset_instructions(&mut self.store, i32::MAX)?;
+        self.call_outside_run(self.finish_output.clone(), &[])?;
+        self.meter.reset_instructions(&mut self.store, remaining)?;
+        Ok(())
+    }
+
+    ///Calls one of the script's exports other than export_run, which can't be paused part way through
+    fn call_outside_run(
+        &mut self,
+        function: wasmer::Function,
+        args: &[Value],
+    ) -> Result<Box<[Value]>, Error> {
+        let gas_env = self.gas_env.clone();
+        let preemptible = gas_env
+            .as_ref()
+            .map(|env| std::mem::replace(&mut env.as_mut(&mut self.store).preemptible, false));
+        let result = function.call(&mut self.store, args);
+        if let (Some(env), Some(preemptible)) = (&gas_env, preemptible) {
+            env.as_mut(&mut self.store).preemptible = preemptible;
+        }
+        Ok(result?)
+    }
+
+    ///Reads the pointer and length of one of the script's GuestBuffer statics
+    fn read_guest_buffer(&self, location: WasmPtr<u8>) -> Result<GuestBuffer, Error> {
         let memory_view = self.memory.view(&self.store);
+        let words = WasmPtr::<u32>::new(location.offset())
+            .slice(&memory_view, 2)?
+            .read_to_vec()?;
+        Ok(GuestBuffer {
+            pointer: words[0],
+            length: words[1],
+        })
+    }
 
-        //Extract byte array from memory
-        let action_slice = self
-            .script_output_pointer
-            .slice(&memory_view, MAX_OUTPUT_SIZE as u32)?;
+    fn write_guest_buffer(&self, location: WasmPtr<u8>, buffer: GuestBuffer) -> Result<(), Error> {
+        let memory_view = self.memory.view(&self.store);
+        WasmPtr::<u32>::new(location.offset())
+            .slice(&memory_view, 2)?
+            .write_slice(&[buffer.pointer, buffer.length])?;
+        Ok(())
+    }
 
-        let mut action_slice_buffer: Vec<u8> = vec![];
-        action_slice_buffer.resize(MAX_OUTPUT_SIZE, 0);
+    ///Reads the bytes a GuestBuffer points to
+    fn read_guest_bytes(&self, buffer: GuestBuffer) -> Result<Vec<u8>, Error> {
+        if buffer.pointer == 0 {
+            return Ok(vec![]);
+        }
+        let memory_view = self.memory.view(&self.store);
+        Ok(WasmPtr::<u8>::new(buffer.pointer)
+            .slice(&memory_view, buffer.length)?
+            .read_to_vec()?)
+    }
 
-        action_slice.read_slice(&mut action_slice_buffer)?;
+    ///Parse the action buffer the script modified to get the actions that want to be performed
+    fn read_actions(&self) -> Result<Vec<O>, Error> {
+        let output = self.read_guest_buffer(self.script_output_pointer)?;
+        if output.length as usize > self.config.max_output_bytes {
+            return Err(Box::new(VMError::OutputTooLarge(
+                output.length as usize,
+                self.config.max_output_bytes,
+            )));
+        }
 
-        //Split array between the size u32 and the rest of the body
-        let (size_bytes, body) = action_slice_buffer.split_at(4);
+        //A length without a pointer is a buffer the script couldn't allocate
+        if output.pointer == 0 && output.length != 0 {
+            return Err(Box::new(VMError::MalformedOutput(format!(
+                "output buffer of {} bytes was never allocated",
+                output.length
+            ))));
+        }
 
-        //Get size
-        let mut u32_buffer: [u8; 4] = [0; 4];
-        u32_buffer.copy_from_slice(size_bytes);
-        let size = u32::from_le_bytes(u32_buffer);
+        //Extract byte array from memory, the script didn't emit anything if it's empty
+        let action_frame = self
+            .read_guest_bytes(output)
+            .map_err(|e| VMError::MalformedOutput(e.to_string()))?;
+        if action_frame.is_empty() {
+            return Ok(Vec::new());
+        }
 
-        //Find the actual content of buffer and parse
-        let content: &[u8] = &body[0..size as usize];
+        let actions =
+            frame::decode(&action_frame).map_err(|e| VMError::MalformedOutput(e.to_string()))?;
+        Ok(actions)
+    }
 
-        Ok(bincode::deserialize(content)?)
+    ///Reads the message the script left in one of its error buffers, if there is one
+    fn read_guest_error(&self, location: WasmPtr<u8>) -> Result<Option<String>, Error> {
+        let error = self.read_guest_buffer(location)?;
+        if error.length == 0 {
+            return Ok(None);
+        }
+        //The script had no room to allocate the message itself
+        if error.pointer == 0 {
+            return Ok(Some(format!(
+                "error message of {} bytes couldn't be allocated",
+                error.length
+            )));
+        }
+        let message = self.read_guest_bytes(error)?;
+        Ok(Some(String::from_utf8_lossy(&message).to_string()))
     }
 
-    ///Gets the instructions variable from the module and subtracts INSTRUCTIONS_PER_TICK to figure out how much gas has been used
+    ///Gets the instructions variable from the module and subtracts the tick's allowance to figure out how much gas has been used
+    ///
+    ///Means the same with either metering backend, a tick that ran out of instructions used its whole allowance
     pub fn get_instructions_used(&mut self) -> Result<i32, Error> {
-        let res = self.get_instructions.call(&mut self.store, &[])?.to_vec();
-        if let Some(Value::I32(num)) = res.get(0) {
-            let gas_used = INSTRUCTIONS_PER_TICK - num;
-            return Ok(gas_used);
+        let mut remaining = self.meter.get_instructions(&mut self.store)? as i64;
+        if let Some(gas_env) = &self.gas_env {
+            remaining += gas_env.as_ref(&self.store).reserve;
+        }
+        Ok((self.budget.allowance() as i64 - remaining) as i32)
+    }
+
+    ///Returns a handle other threads can use to stop a running tick
+    ///
+    ///Only has an effect if the VM was built with VMConfig::interruptible set. Interrupting between ticks,
+    ///or too late in a tick for the script to notice before it finishes, fails the next tick before the script runs
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    ///Whether a preemptible script ran out of instructions and will pick up where it left off next tick
+    pub fn is_suspended(&self) -> bool {
+        match &self.preemption {
+            Some(preemption) => preemption.is_suspended(&self.store),
+            None => false,
+        }
+    }
+
+    ///The budget policy and instruction balance of the script
+    pub fn budget(&self) -> &Budget {
+        &self.budget
+    }
+
+    ///Returns the size of the script's linear memory in bytes
+    pub fn memory_usage(&self) -> u64 {
+        self.memory.view(&self.store).data_size()
+    }
+
+    ///Returns the size of the script's linear memory in wasm pages
+    pub fn memory_pages(&self) -> u32 {
+        self.memory.view(&self.store).size().0
+    }
+
+    ///Returns the configured memory cap in wasm pages, if there is one
+    pub fn memory_limit_pages(&self) -> Option<u32> {
+        self.config.max_memory_pages
+    }
+
+    ///Static instruction costs of the script's functions and its call graph
+    pub fn gas_report(&self) -> &GasReport {
+        &self.template.gas_report
+    }
+
+    ///What the script used in its last tick, along with the limits it had
+    pub fn last_tick_usage(&self) -> &TickUsage {
+        &self.usage
+    }
+
+    ///Hash of the module the script is running, after the injector rewrote it
+    pub fn module_hash(&self) -> &ModuleHash {
+        &self.template.module_hash
+    }
+
+    ///The template the script was instantiated from, more VMs running the same script can be made from it
+    pub fn template(&self) -> &Arc<ScriptTemplate> {
+        &self.template
+    }
+
+    ///Captures the script's memory, globals and budget so it can be put back to this point with restore
+    ///
+    ///Taken between ticks, a preemptible script paused part way through a tick resumes from where it was
+    pub fn snapshot(&mut self) -> Result<VmSnapshot, Error> {
+        let mut globals = Vec::with_capacity(self.globals.len());
+        for (name, global) in &self.globals {
+            let value = GlobalValue::from_value(&global.get(&mut self.store)).ok_or_else(|| {
+                VMError::InvalidSnapshot(format!("global {} holds a reference", name))
+            })?;
+            globals.push((name.clone(), value));
         }
 
-        Err(Box::new(VMError::VMErrorNoGas))
+        Ok(VmSnapshot {
+            module_hash: self.template.module_hash,
+            memory: self.memory.view(&self.store).copy_to_vec()?,
+            globals,
+            instructions: self.meter.get_instructions(&mut self.store)?,
+            gas_reserve: self
+                .gas_env
+                .as_ref()
+                .map_or(0, |gas_env| gas_env.as_ref(&self.store).reserve),
+            budget_balance: self.budget.balance(),
+            suspended: self.is_suspended(),
+            stale_buffers: self.stale_buffers.clone(),
+        })
+    }
+
+    ///Puts the script back to where it was when the snapshot was taken
+    ///
+    ///The snapshot has to come from the same module with the same config, otherwise it is refused with
+    ///VMError::SnapshotModuleMismatch. Memory can't shrink, so if the script has grown it since the snapshot
+    ///the snapshot is restored into a new instance of the module, keeping the interrupt handles given out
+    pub fn restore(&mut self, snapshot: &VmSnapshot) -> Result<(), Error> {
+        if snapshot.module_hash != self.template.module_hash {
+            return Err(Box::new(VMError::SnapshotModuleMismatch));
+        }
+        if snapshot.globals.len() != self.globals.len()
+            || snapshot.memory.len() as u64 % WASM_PAGE_SIZE != 0
+        {
+            return Err(Box::new(VMError::InvalidSnapshot(
+                "snapshot doesn't match the module's layout".to_string(),
+            )));
+        }
+
+        let snapshot_pages = (snapshot.memory.len() as u64 / WASM_PAGE_SIZE) as u32;
+        if self.memory_pages() > snapshot_pages {
+            //A new instance starts with the module's initial memory, which is never more than the snapshot's
+            self.replace_instance(Self::from_template(&self.template)?);
+        }
+        let current_pages = self.memory_pages();
+        if snapshot_pages > current_pages {
+            self.memory
+                .grow(&mut self.store, Pages(snapshot_pages - current_pages))?;
+        }
+        self.memory.view(&self.store).write(0, &snapshot.memory)?;
+
+        for ((name, global), (snapshot_name, value)) in self.globals.iter().zip(&snapshot.globals) {
+            if name != snapshot_name {
+                return Err(Box::new(VMError::InvalidSnapshot(format!(
+                    "snapshot has global {} where the module has {}",
+                    snapshot_name, name
+                ))));
+            }
+            global.set(&mut self.store, value.to_value())?;
+        }
+
+        self.meter
+            .reset_instructions(&mut self.store, snapshot.instructions)?;
+        if let Some(gas_env) = &self.gas_env {
+            gas_env.as_mut(&mut self.store).reserve = snapshot.gas_reserve;
+        }
+        self.budget.restore_balance(snapshot.budget_balance);
+        if let Some(preemption) = &self.preemption {
+            preemption.set_suspended(&mut self.store, snapshot.suspended);
+        }
+        self.stale_buffers = snapshot.stale_buffers.clone();
+        Ok(())
+    }
+
+    ///Checks whether the script's last memory.grow failed for the given reason
+    fn hit_memory_limit(&mut self, reason: i32) -> bool {
+        match &self.memory_limit_reached {
+            Some(limit_global) => limit_global.get(&mut self.store).i32() == Some(reason),
+            None => false,
+        }
+    }
+
+    ///Size of the debug text the script wrote this tick in bytes
+    fn debug_text_size(&self) -> Result<usize, Error> {
+        Ok(self.read_guest_buffer(self.debug_text_pointer)?.length as usize)
+    }
+
+    ///Reads the coverage bitmap to see which blocks of the script have run since it was last reset
+    pub fn coverage_report(&self) -> Result<CoverageReport, Error> {
+        let coverage = self
+            .template
+            .coverage
+            .as_ref()
+            .ok_or(VMError::CoverageDisabled)?;
+        let memory_view = self.memory.view(&self.store);
+        let bitmap_pointer: WasmPtr<u8> = WasmPtr::new(coverage.map.pointer);
+        let bitmap = bitmap_pointer
+            .slice(&memory_view, coverage.map.blocks.len() as u32)?
+            .read_to_vec()?;
+        Ok(coverage.report(&bitmap))
+    }
+
+    ///Clears the coverage bitmap so the next report only has blocks run from now on
+    pub fn reset_coverage(&mut self) -> Result<(), Error> {
+        let coverage = self
+            .template
+            .coverage
+            .as_ref()
+            .ok_or(VMError::CoverageDisabled)?;
+        let memory_view = self.memory.view(&self.store);
+        let bitmap_pointer: WasmPtr<u8> = WasmPtr::new(coverage.map.pointer);
+        let empty_bitmap = vec![0_u8; coverage.map.blocks.len()];
+        bitmap_pointer
+            .slice(&memory_view, empty_bitmap.len() as u32)?
+            .write_slice(&empty_bitmap)?;
+        Ok(())
     }
 
     ///Reads the TEXT_BUFFER global to extract any text created by the debug!() macro
     pub fn read_debug_string(&mut self) -> Result<String, Error> {
-        let res = self.get_text_size.call(&mut self.store, &[])?;
-        let memory_view = self.memory.view(&self.store);
+        let text = self.read_guest_buffer(self.debug_text_pointer)?;
+        let byte_buffer = self.read_guest_bytes(text)?;
+        Ok(String::from_utf8(byte_buffer)?)
+    }
 
-        if let Some(Value::I32(length)) = res.get(0) {
-            let mut byte_buffer: Vec<u8> = vec![];
-            byte_buffer.resize(*length as usize, 0);
-            let slice = self
-                .debug_text_pointer
-                .slice(&memory_view, *length as u32)?;
-            slice.read_slice(&mut byte_buffer)?;
-            return Ok(String::from_utf8(byte_buffer)?);
-        }
-        Ok(String::default())
+    //Reports keep whatever text there is, even if the script wrote broken utf8
+    fn read_debug_text(&self) -> String {
+        self.read_guest_buffer(self.debug_text_pointer)
+            .and_then(|text| self.read_guest_bytes(text))
+            .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+            .unwrap_or_default()
     }
 
     fn read_vec<T: Default + Clone>(
@@ -209,21 +827,96 @@ impl WasmVM {
         return "".to_string();
     }
 
-    ///Call the "export_run" function once  and then check to see if the VM ran out of instructions are panicked
-    pub fn run_tick(
-        &mut self,
-        inputs: Inputs,
-    ) -> Result<Vec<ScriptAction>, Error> {
+    ///Call the "export_run" function once and report what the script did, and whether it ran out of
+    ///instructions, panicked or failed
+    ///
+    ///A preemptible script that runs out of instructions returns the actions it made so far and resumes next tick.
+    ///An interrupted or timed out tick fails, but the VM can still run the next one
+    pub fn run_tick(&mut self, inputs: Vec<I>) -> TickReport<O> {
+        let time = Instant::now();
+        let result = self.tick(inputs);
+        let wall_time = time.elapsed();
+
+        let (actions, outcome) = match result {
+            Ok(actions) if self.is_suspended() => (actions, TickOutcome::Suspended),
+            Ok(actions) => (actions, TickOutcome::Completed),
+            Err(e) => (vec![], TickOutcome::Failed(TickError::from_error(&e))),
+        };
+        TickReport {
+            actions,
+            instructions: self.usage.instructions,
+            debug_output: self.read_debug_text(),
+            wall_time,
+            memory_pages: self.memory_pages(),
+            outcome,
+        }
+    }
+
+    fn tick(&mut self, inputs: Vec<I>) -> Result<Vec<O>, Error> {
+        //Nothing is used if the tick fails before the script runs
+        self.usage = TickUsage {
+            limits: self.config.resource_limits,
+            ..TickUsage::default()
+        };
+        //An interrupt raised while no tick was running stops the next one before it starts
+        if self.config.interruptible && self.interrupt.take() {
+            return Err(Box::new(VMError::Interrupted));
+        }
         self.reset_script()?;
 
         self.set_input(inputs)?;
+        let pages_before = self.memory_pages();
+
+        if let Some(preemption) = &self.preemption {
+            preemption.before_run(&mut self.store, &self.memory)?;
+        }
+
+        let run_start = Instant::now();
+        let result = self.run.call(&mut self.store, &[]);
+        let run_time = run_start.elapsed();
+
+        if let Some(preemption) = &self.preemption {
+            preemption.after_run(&mut self.store, result.is_ok())?;
+        }
+
+        //Take what was used out of the budget whether or not the tick succeeded, a count that can't be read
+        //is charged as the whole allowance so a broken counter never hands out free instructions
+        let instructions_used = self
+            .get_instructions_used()
+            .unwrap_or_else(|_| self.budget.allowance());
+        self.budget.end_tick(instructions_used);
+        let grown_pages = self.memory_pages().saturating_sub(pages_before);
+        self.usage = TickUsage {
+            instructions: instructions_used,
+            instruction_allowance: self.budget.allowance(),
+            memory_grow_bytes: grown_pages as u64 * WASM_PAGE_SIZE,
+            actions: 0,
+            debug_text_bytes: self.debug_text_size().unwrap_or(0),
+            host_calls: self.host_env.as_ref(&self.store).calls,
+            run_time,
+            limits: self.config.resource_limits,
+        };
+
+        if let Err(e) = result {
+            //Errors raised by the host while the script was running, like interrupts and timeouts
+            let e = match e.downcast::<VMError>() {
+                Ok(vm_error) => return Err(Box::new(vm_error)),
+                Err(e) => e,
+            };
+
+            //Check to see if the script grew its memory by more than a tick allows
+            if self.hit_memory_limit(GROW_BUDGET_EXHAUSTED) {
+                return Err(Box::new(VMError::MemoryGrowLimitReached));
+            }
 
-        if let Err(e) = self.run.call(&mut self.store, &[]) {
             //Check to see if VM ran out of instructions
-            if let Ok(intructions_used) = self.get_instructions_used() {
-                if intructions_used >= INSTRUCTIONS_PER_TICK - 10000 {
-                    return Err(Box::new(VMError::VMProcLimitReached));
-                }
+            if self.meter.is_exhausted(&mut self.store) {
+                return Err(Box::new(VMError::VMProcLimitReached));
+            }
+
+            //Check to see if the script was refused more memory
+            if self.hit_memory_limit(GROW_REFUSED) {
+                return Err(Box::new(VMError::MemoryLimitExceeded));
             }
 
             //Check to see if some code in the VM panicked
@@ -235,7 +928,32 @@ impl WasmVM {
             //Must be a runtime error then
             return Err(Box::new(e));
         }
-        Ok(self.read_actions()?)
+        self.finish_output()?;
+
+        //Inputs or actions the script couldn't decode or encode on its side
+        if let Some(message) = self.read_guest_error(self.input_error_pointer)? {
+            return Err(Box::new(VMError::MalformedInput(message)));
+        }
+        if let Some(message) = self.read_guest_error(self.output_error_pointer)? {
+            return Err(Box::new(VMError::MalformedOutput(message)));
+        }
+
+        let actions = self.read_actions()?;
+        self.usage.actions = actions.len();
+        let limits = self.config.resource_limits;
+        if limits
+            .actions
+            .is_some_and(|limit| self.usage.actions > limit)
+        {
+            return Err(Box::new(VMError::ActionLimitReached));
+        }
+        if limits
+            .debug_text_bytes
+            .is_some_and(|limit| self.usage.debug_text_bytes > limit)
+        {
+            return Err(Box::new(VMError::DebugTextLimitReached));
+        }
+        Ok(actions)
     }
 }
 
@@ -257,4 +975,62 @@ pub enum VMError {
     VMProcLimitReached,
     #[error("WASM VM failed to compile code for the following reason")]
     VMCompileFail(String),
+    #[error("WASM VM tried to use more memory than it is allowed")]
+    MemoryLimitExceeded,
+    #[error("Script uses float instructions that break determinism: {0:?}")]
+    FloatDeterminismViolation(Vec<String>),
+    #[error("Module is missing ASYNCIFY_BUFFER global")]
+    VMErrorNoAsyncifyBuffer,
+    #[error("Preemption and interruption need the injector metering backend")]
+    NeedsInjectorMetering,
+    #[error("Script uses the name {0} which is reserved for the runner")]
+    ReservedName(String),
+    #[error("Metering verification failed: {0}")]
+    MeteringVerificationFailed(String),
+    #[error("Module has no memory")]
+    VMErrorNoMemory,
+    #[error("Coverage was not turned on for this VM")]
+    CoverageDisabled,
+    #[error("Coverage can't be added to this module: {0}")]
+    CoverageUnsupported(String),
+    #[error("WASM VM was interrupted by the host")]
+    Interrupted,
+    #[error("WASM VM ran past its time limit")]
+    Timeout,
+    #[error("WASM VM grew its memory by more than it is allowed to in a tick")]
+    MemoryGrowLimitReached,
+    #[error("WASM VM emitted more actions than it is allowed to in a tick")]
+    ActionLimitReached,
+    #[error("WASM VM wrote more debug text than it is allowed to in a tick")]
+    DebugTextLimitReached,
+    #[error("WASM VM made more host calls than it is allowed to in a tick")]
+    HostCallLimitReached,
+    #[error("export_run always uses at least {0} instructions but a tick allows at most {1}")]
+    ExceedsBudget(u64, i64),
+    #[error("Module is too complex to load: {0}")]
+    ModuleTooComplex(String),
+    #[error("Host function {0} failed: {1}")]
+    HostFunctionFailed(String, String),
+    #[error("Host function {0} is registered more than once")]
+    DuplicateHostFunction(String),
+    #[error("Module uses a wasm feature that is not allowed: {0}")]
+    FeatureNotAllowed(String),
+    #[error("Inputs are {0} bytes serialized but the limit is {1}")]
+    InputTooLarge(usize, usize),
+    #[error("WASM VM output {0} bytes of actions but the limit is {1}")]
+    OutputTooLarge(usize, usize),
+    #[error("WASM VM output actions that could not be read: {0}")]
+    MalformedOutput(String),
+    #[error("WASM VM could not read its inputs: {0}")]
+    MalformedInput(String),
+    #[error("Snapshot was taken from a different module or config")]
+    SnapshotModuleMismatch,
+    #[error("Snapshot could not be read: {0}")]
+    InvalidSnapshot(String),
+    #[error("New script could not load the saved state: {0}")]
+    StateLoadFailed(String),
+    #[error("A script paused part way through a tick can't be reloaded")]
+    ReloadWhileSuspended,
+    #[error("The {0:?} compiler backend needs its cargo feature turned on")]
+    CompilerNotEnabled(CompilerBackend),
 }
@@ -0,0 +1,88 @@
+//! Checks that running out of instructions is told apart from other failures whatever the budget
+//!
+//! Each failing script is run with a huge budget first to see how many instructions it takes to fail, then
+//! again with just enough and with too few. Needs the wasm32-unknown-unknown target to build scripts
+use wasm_runner::{compile, BudgetPolicy, MeteringBackend, TickErrorKind, VMConfig, WasmVM};
+
+const PANICKING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        panic!("boom");
+    }
+}
+"#;
+
+const TRAPPING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        std::process::abort();
+    }
+}
+"#;
+
+fn failure(wasm: &[u8], metering: MeteringBackend, per_tick: i32) -> (TickErrorKind, i32) {
+    let config = VMConfig::default()
+        .with_metering(metering)
+        .with_budget(BudgetPolicy::Fixed { per_tick });
+    let mut vm: WasmVM = WasmVM::from_wasm(wasm, config).unwrap();
+    let report = vm.run_tick(Vec::default());
+    let used = report.instructions;
+    let error = report
+        .into_result()
+        .err()
+        .unwrap_or_else(|| panic!("tick with {} instructions didn't fail", per_tick));
+    (error.kind, used)
+}
+
+fn check_classification(code: &str, expected: TickErrorKind) {
+    let wasm = compile(code.to_string()).expect("Compile script");
+    for metering in [MeteringBackend::Injector, MeteringBackend::Middleware] {
+        let (kind, used) = failure(&wasm, metering, 50_000_000);
+        assert_eq!(kind, expected, "{:?} with a huge budget", metering);
+
+        //Failing right at the end of the budget is still the script's own failure
+        let (kind, _) = failure(&wasm, metering, used + 1);
+        assert_eq!(
+            kind,
+            expected,
+            "{:?} with {} instructions",
+            metering,
+            used + 1
+        );
+
+        let (kind, _) = failure(&wasm, metering, used / 2);
+        assert_eq!(
+            kind,
+            TickErrorKind::OutOfInstructions,
+            "{:?} with {} instructions",
+            metering,
+            used / 2
+        );
+    }
+}
+
+#[test]
+fn panics_near_the_limit_are_panics() {
+    check_classification(PANICKING_SCRIPT, TickErrorKind::Panic);
+}
+
+#[test]
+fn traps_near_the_limit_are_traps() {
+    check_classification(TRAPPING_SCRIPT, TickErrorKind::Trap);
+}
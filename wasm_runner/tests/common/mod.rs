@@ -0,0 +1,126 @@
+//! Scripts and VM factories shared by the integration tests
+//!
+//! Scripts are compiled through cargo the first time a test asks for them and the wasm is reused by every
+//! other test in the same binary. Not every test binary uses everything in here
+#![allow(dead_code)]
+
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, OnceLock},
+};
+
+use script_api::Data;
+use wasm_runner::{compile, VMConfig, WasmVM};
+
+/// Counts its ticks and does a thousand loop iterations of work for every tick so far, so later ticks take
+/// longer. Emits the tick number and writes a running total that depends on every tick before it to the
+/// debug text, and keeps its tick count across reloads
+pub const COUNTING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    ticks: u32,
+    total: u64,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { ticks: 0, total: 0 }
+    }
+
+    pub fn run(&mut self) {
+        self.ticks += 1;
+        let mut total = std::hint::black_box(self.total);
+        for i in 0..(std::hint::black_box(self.ticks) as u64 * 1000) {
+            total = std::hint::black_box(total.wrapping_mul(31).wrapping_add(i));
+        }
+        self.total = total;
+        debug!("tick {} total {}", self.ticks, self.total);
+        emit(self.ticks);
+    }
+}
+
+impl ScriptState for Script {
+    fn save_state(&self) -> Vec<u8> {
+        self.ticks.to_le_bytes().to_vec()
+    }
+
+    fn load_state(state: &[u8]) -> Option<Self> {
+        Some(Self {
+            ticks: u32::from_le_bytes(state.try_into().ok()?),
+            total: 0,
+        })
+    }
+}
+
+reload_hooks!();
+"#;
+
+/// Never returns from run
+pub const SPINNING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        let mut x = std::hint::black_box(1u64);
+        loop {
+            x = std::hint::black_box(x.wrapping_add(1));
+        }
+    }
+}
+"#;
+
+/// Counts its ticks and holds on to another 256 KiB of memory every tick, emitting the tick number
+pub const GROWING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    ticks: u32,
+    blocks: Vec<Vec<u8>>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {
+            ticks: 0,
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn run(&mut self) {
+        self.ticks += 1;
+        self.blocks.push(vec![self.ticks as u8; 256 * 1024]);
+        emit(self.ticks);
+    }
+}
+"#;
+
+/// Compiles the script the first time it is asked for and hands back the same wasm every time after that
+pub fn wasm(code: &'static str) -> &'static [u8] {
+    static SCRIPTS: Mutex<BTreeMap<&str, &OnceLock<Vec<u8>>>> = Mutex::new(BTreeMap::new());
+    //Only the lookup is done under the lock, so different scripts can compile at the same time
+    let script: &OnceLock<Vec<u8>> = *SCRIPTS
+        .lock()
+        .unwrap()
+        .entry(code)
+        .or_insert_with(|| &*Box::leak(Box::default()));
+    script.get_or_init(|| compile(code.to_string()).expect("Compile script"))
+}
+
+pub fn counting_vm(config: VMConfig) -> WasmVM<Data, u32> {
+    WasmVM::from_wasm(wasm(COUNTING_SCRIPT), config).unwrap()
+}
+
+pub fn spinning_vm(config: VMConfig) -> WasmVM<Data, u32> {
+    WasmVM::from_wasm(wasm(SPINNING_SCRIPT), config).unwrap()
+}
+
+pub fn growing_vm(config: VMConfig) -> WasmVM<Data, u32> {
+    WasmVM::from_wasm(wasm(GROWING_SCRIPT), config).unwrap()
+}
@@ -0,0 +1,529 @@
+//! Runs modules with and without the injector's rewrite and checks that metering doesn't change what they do
+//!
+//! Every exported function is called once in export order. A metered run with a huge budget records how many
+//! instructions each call used, then the same module is run unmetered and with smaller budgets. With at least
+//! as many instructions as a call used it has to behave exactly like the unmetered module, with fewer it has
+//! to run out of instructions, and no run is allowed to hang. Every module is checked both with metering that
+//! calls out to the host when it runs out and with the default metering that traps
+use std::{fmt, sync::mpsc, thread, time::Duration};
+
+use arbitrary::Unstructured;
+use wasm_runner::{instrument, VMConfig, RESERVED_PREFIX};
+use wasmer::{
+    Extern, Function, Imports, Instance, Module, RuntimeError, Store, TrapCode, Type, Value,
+};
+
+/// Big enough that only modules that would spin for a long time run out
+const HUGE_BUDGET: i32 = 50_000_000;
+const SMALL_BUDGETS: [i32; 6] = [0, 1, 10, 100, 1_000, 10_000];
+const RANDOM_MODULES: u64 = 200;
+/// A run taking longer than this is treated as hung
+const RUN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Hand written modules for the cases random modules rarely hit
+const CORPUS: &[(&str, &str)] = &[
+    ("spin", r#"(module (func (export "spin") (loop br 0)))"#),
+    (
+        "recurse",
+        r#"(module (func $f (export "recurse") call $f))"#,
+    ),
+    (
+        "count_down",
+        r#"(module
+            (func (export "count_down") (result i32) (local i32)
+                i32.const 1000
+                local.set 0
+                (loop
+                    local.get 0
+                    i32.const 1
+                    i32.sub
+                    local.tee 0
+                    br_if 0)
+                local.get 0))"#,
+    ),
+    (
+        "nested_loops",
+        r#"(module
+            (global $total (mut i32) (i32.const 0))
+            (func (export "nested_loops") (result i32) (local i32 i32)
+                (loop $outer
+                    i32.const 0
+                    local.set 1
+                    (loop $inner
+                        global.get $total
+                        local.get 1
+                        i32.add
+                        global.set $total
+                        local.get 1
+                        i32.const 1
+                        i32.add
+                        local.tee 1
+                        i32.const 50
+                        i32.lt_u
+                        br_if $inner)
+                    local.get 0
+                    i32.const 1
+                    i32.add
+                    local.tee 0
+                    i32.const 50
+                    i32.lt_u
+                    br_if $outer)
+                global.get $total))"#,
+    ),
+    (
+        "branches",
+        r#"(module
+            (func $pick (param i32) (result i32)
+                (block $c (block $b (block $a
+                    local.get 0
+                    br_table $a $b $c)
+                    i32.const 10
+                    return)
+                    i32.const 20
+                    return)
+                i32.const 30)
+            (func (export "branches") (result i32)
+                i32.const 0
+                call $pick
+                i32.const 1
+                call $pick
+                i32.add
+                i32.const 7
+                call $pick
+                i32.add
+                (if (result i32) (i32.const 1)
+                    (then i32.const 1)
+                    (else i32.const 2))
+                i32.add))"#,
+    ),
+    (
+        "indirect",
+        r#"(module
+            (type $t (func (result i32)))
+            (table 2 funcref)
+            (elem (i32.const 0) $one $two)
+            (func $one (result i32) i32.const 1)
+            (func $two (result i32) i32.const 2)
+            (func (export "indirect") (result i32)
+                i32.const 0
+                call_indirect (type $t)
+                i32.const 1
+                call_indirect (type $t)
+                i32.add)
+            (func (export "bad_indirect") (result i32)
+                i32.const 5
+                call_indirect (type $t)))"#,
+    ),
+    (
+        "bulk_memory",
+        r#"(module
+            (memory (export "memory") 2)
+            (func (export "fill")
+                i32.const 0
+                i32.const 7
+                i32.const 65536
+                memory.fill)
+            (func (export "copy")
+                i32.const 65536
+                i32.const 0
+                i32.const 65536
+                memory.copy)
+            (func (export "out_of_bounds")
+                i32.const 0
+                i32.const 1
+                i32.const 200000
+                memory.fill))"#,
+    ),
+    (
+        "grow",
+        r#"(module
+            (memory (export "memory") 1 4)
+            (func (export "grow") (result i32)
+                i32.const 2
+                memory.grow
+                i32.const 8
+                memory.grow
+                i32.add))"#,
+    ),
+    (
+        "floats",
+        r#"(module
+            (func (export "floats") (result f64)
+                f64.const 1.5
+                f64.const 0
+                f64.div
+                f64.const 2
+                f64.sqrt
+                f64.mul))"#,
+    ),
+];
+
+/// Returned by the out of gas import so a run that ran out can be told apart from other traps. Without the
+/// import the module sets its gas exhausted global before trapping instead
+#[derive(Debug)]
+struct OutOfGas;
+
+impl fmt::Display for OutOfGas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "out of gas")
+    }
+}
+
+impl std::error::Error for OutOfGas {}
+
+/// How one call into a module ended
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Outcome {
+    Returned(Vec<String>),
+    Trapped(Option<TrapCode>),
+    OutOfGas,
+}
+
+/// Everything a run did, in export order
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Run {
+    calls: Vec<(String, Outcome)>,
+    /// Instructions each call used, only for metered runs
+    used: Vec<i32>,
+    /// Exported memories and globals after the last call
+    state: Vec<String>,
+}
+
+/// Random modules only use what the runner allows by default and have nothing to import
+#[derive(Debug)]
+struct DifferentialConfig;
+
+impl wasm_smith::Config for DifferentialConfig {
+    fn max_imports(&self) -> usize {
+        0
+    }
+
+    fn min_funcs(&self) -> usize {
+        1
+    }
+
+    fn max_funcs(&self) -> usize {
+        20
+    }
+
+    fn max_instructions(&self) -> usize {
+        200
+    }
+
+    fn max_memories(&self) -> usize {
+        1
+    }
+
+    fn max_memory_pages(&self, _is_64: bool) -> u64 {
+        16
+    }
+
+    fn export_everything(&self) -> bool {
+        true
+    }
+
+    // The start function would run before the budget is set
+    fn allow_start_export(&self) -> bool {
+        false
+    }
+
+    fn simd_enabled(&self) -> bool {
+        false
+    }
+
+    fn threads_enabled(&self) -> bool {
+        false
+    }
+}
+
+/// Metering through the out of gas import when interruptible and trapping otherwise, with no memory cap so
+/// memory.grow is left alone
+fn metered_config(interruptible: bool) -> VMConfig {
+    VMConfig {
+        max_memory_pages: None,
+        ..VMConfig::default()
+    }
+    .with_interrupts(interruptible)
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::F32(f) if f.is_nan() => "f32:nan".to_string(),
+        Value::F64(f) if f.is_nan() => "f64:nan".to_string(),
+        Value::F32(f) => format!("f32:{:#x}", f.to_bits()),
+        Value::F64(f) => format!("f64:{:#x}", f.to_bits()),
+        Value::FuncRef(f) => format!("funcref:{}", f.is_some()),
+        Value::ExternRef(e) => format!("externref:{}", e.is_some()),
+        other => format!("{:?}", other),
+    }
+}
+
+fn zero(ty: &Type) -> Value {
+    match ty {
+        Type::I32 => Value::I32(0),
+        Type::I64 => Value::I64(0),
+        Type::F32 => Value::F32(0.0),
+        Type::F64 => Value::F64(0.0),
+        Type::V128 => Value::V128(0),
+        Type::ExternRef => Value::ExternRef(None),
+        Type::FuncRef => Value::FuncRef(None),
+    }
+}
+
+/// Runs every exported function of the module, giving call i budgets[i] instructions when metered
+fn run(wasm: Vec<u8>, budgets: Vec<i32>) -> Result<Run, String> {
+    let mut store = Store::default();
+    let module = Module::new(&store, wasm).map_err(|e| e.to_string())?;
+    let out_of_gas_import = format!("{}out_of_gas", RESERVED_PREFIX);
+    let mut import_object = Imports::new();
+    if module
+        .imports()
+        .any(|import| import.module() == "env" && import.name() == out_of_gas_import)
+    {
+        let out_of_gas =
+            Function::new_typed(&mut store, |_cost: i32| -> Result<(), RuntimeError> {
+                Err(RuntimeError::user(Box::new(OutOfGas)))
+            });
+        import_object.define("env", &out_of_gas_import, out_of_gas);
+    }
+    let instance = Instance::new(&mut store, &module, &import_object).map_err(|e| e.to_string())?;
+
+    let exports: Vec<(String, Extern)> = instance
+        .exports
+        .iter()
+        .filter(|(name, _)| !name.starts_with(RESERVED_PREFIX))
+        .map(|(name, export)| (name.clone(), export.clone()))
+        .collect();
+    let reset_instructions = instance
+        .exports
+        .get_function(&format!("{}reset_instructions", RESERVED_PREFIX))
+        .ok()
+        .cloned();
+    let get_instructions = instance
+        .exports
+        .get_function(&format!("{}get_instructions", RESERVED_PREFIX))
+        .ok()
+        .cloned();
+    let gas_exhausted = instance
+        .exports
+        .get_global(&format!("{}gas_exhausted", RESERVED_PREFIX))
+        .ok()
+        .cloned();
+
+    let mut calls = vec![];
+    let mut used = vec![];
+    let functions = exports.iter().filter_map(|(name, export)| match export {
+        Extern::Function(function) => Some((name, function)),
+        _ => None,
+    });
+    for (index, (name, function)) in functions.enumerate() {
+        let budget = budgets.get(index).copied().unwrap_or(HUGE_BUDGET);
+        if let Some(reset_instructions) = &reset_instructions {
+            reset_instructions
+                .call(&mut store, &[Value::I32(budget)])
+                .map_err(|e| e.to_string())?;
+        }
+        if let Some(gas_exhausted) = &gas_exhausted {
+            gas_exhausted
+                .set(&mut store, Value::I32(0))
+                .map_err(|e| e.to_string())?;
+        }
+
+        let args: Vec<Value> = function.ty(&store).params().iter().map(zero).collect();
+        let outcome = match function.call(&mut store, &args) {
+            Ok(results) => Outcome::Returned(results.iter().map(describe).collect()),
+            Err(e) if e.is::<OutOfGas>() => Outcome::OutOfGas,
+            Err(_)
+                if gas_exhausted
+                    .as_ref()
+                    .is_some_and(|exhausted| exhausted.get(&mut store).i32() == Some(1)) =>
+            {
+                Outcome::OutOfGas
+            }
+            Err(e) => Outcome::Trapped(e.to_trap()),
+        };
+        calls.push((name.clone(), outcome));
+
+        if let Some(get_instructions) = &get_instructions {
+            let remaining = get_instructions
+                .call(&mut store, &[])
+                .map_err(|e| e.to_string())?[0]
+                .i32()
+                .unwrap_or_default();
+            used.push(budget - remaining);
+        }
+    }
+
+    let mut state = vec![];
+    for (name, export) in &exports {
+        match export {
+            Extern::Memory(memory) => {
+                let contents = memory
+                    .view(&store)
+                    .copy_to_vec()
+                    .map_err(|e| e.to_string())?;
+                state.push(format!("{}: {:x?}", name, fnv_hash(&contents)));
+            }
+            Extern::Global(global) => {
+                state.push(format!("{}: {}", name, describe(&global.get(&mut store))));
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Run { calls, used, state })
+}
+
+fn fnv_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Runs on another thread and fails the test if it doesn't finish in time
+fn run_with_timeout(wasm: &[u8], budgets: Vec<i32>) -> Result<Run, String> {
+    let (sender, receiver) = mpsc::channel();
+    let wasm = wasm.to_vec();
+    thread::spawn(move || {
+        let _ = sender.send(run(wasm, budgets));
+    });
+    receiver
+        .recv_timeout(RUN_TIMEOUT)
+        .expect("module run hung, metering let it run forever")
+}
+
+/// Runs the checks on one module, returning false if it had to spin too long for the unmetered run to be safe
+fn check_module(module: &str, wasm: &[u8], interruptible: bool) -> bool {
+    let name = &format!("{} (interruptible: {})", module, interruptible);
+    let instrumented = instrument(wasm, &metered_config(interruptible))
+        .unwrap_or_else(|e| panic!("{}: rewrite refused a valid module: {}", name, e));
+    wasmer::wasmparser::Validator::new()
+        .validate_all(&instrumented)
+        .unwrap_or_else(|e| panic!("{}: rewrite produced an invalid module: {}", name, e));
+
+    let huge = match run_with_timeout(&instrumented, vec![]) {
+        Ok(huge) => huge,
+        Err(e) => {
+            //Only fine if the original module can't be instantiated either
+            assert!(
+                run_with_timeout(wasm, vec![]).is_err(),
+                "{}: only the metered module failed to instantiate: {}",
+                name,
+                e
+            );
+            return true;
+        }
+    };
+
+    //Small budgets have to match the huge run while the call fits, and run out as soon as it doesn't.
+    //After the first call runs out the state differs, so later calls only have to finish
+    for budget in SMALL_BUDGETS {
+        let small = run_with_timeout(&instrumented, vec![budget; huge.calls.len()])
+            .unwrap_or_else(|e| panic!("{}: metered run failed: {}", name, e));
+        for ((call, used), (_, outcome)) in huge.calls.iter().zip(&huge.used).zip(&small.calls) {
+            let expected = if *used <= budget {
+                &call.1
+            } else {
+                &Outcome::OutOfGas
+            };
+            assert_eq!(
+                outcome, expected,
+                "{}: {} with a budget of {} (uses {})",
+                name, call.0, budget, used
+            );
+            if *outcome == Outcome::OutOfGas {
+                break;
+            }
+        }
+    }
+
+    if huge
+        .calls
+        .iter()
+        .any(|(_, outcome)| *outcome == Outcome::OutOfGas)
+    {
+        return false;
+    }
+
+    //Exactly the instructions each call used is enough for the whole run to be the same
+    let exact = run_with_timeout(&instrumented, huge.used.clone())
+        .unwrap_or_else(|e| panic!("{}: metered run failed: {}", name, e));
+    assert_eq!(exact, huge, "{}: run with exact budgets differs", name);
+
+    let unmetered = run_with_timeout(wasm, vec![])
+        .unwrap_or_else(|e| panic!("{}: unmetered run failed: {}", name, e));
+    assert_eq!(unmetered.calls, huge.calls, "{}: results differ", name);
+    assert_eq!(unmetered.state, huge.state, "{}: final state differs", name);
+    true
+}
+
+#[test]
+fn corpus_matches_unmetered() {
+    for (name, source) in CORPUS {
+        let wasm = wat::parse_str(source).expect("corpus module");
+        for interruptible in [true, false] {
+            check_module(name, &wasm, interruptible);
+        }
+    }
+}
+
+#[test]
+fn infinite_loops_always_run_out() {
+    for name in ["spin", "recurse"] {
+        let (_, source) = CORPUS.iter().find(|(module, _)| *module == name).unwrap();
+        let wasm = wat::parse_str(source).unwrap();
+        for interruptible in [true, false] {
+            let instrumented = instrument(&wasm, &metered_config(interruptible)).unwrap();
+            //Larger budgets would let the recursion overflow the stack first
+            for budget in &SMALL_BUDGETS[..5] {
+                let run = run_with_timeout(&instrumented, vec![*budget]).unwrap();
+                assert_eq!(
+                    run.calls[0].1,
+                    Outcome::OutOfGas,
+                    "{} with {} (interruptible: {})",
+                    name,
+                    budget,
+                    interruptible
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn random_modules_match_unmetered() {
+    //xorshift so every run of the test checks the same modules
+    let mut seed: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut compared = 0;
+    let mut generated = 0;
+
+    for index in 0..RANDOM_MODULES {
+        let bytes: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        let mut unstructured = Unstructured::new(&bytes);
+        let Ok(module) = wasm_smith::Module::new(DifferentialConfig, &mut unstructured) else {
+            continue;
+        };
+        generated += 1;
+        let name = format!("random module {}", index);
+        let wasm = module.to_bytes();
+        let checked = [true, false].map(|interruptible| check_module(&name, &wasm, interruptible));
+        if checked.iter().all(|compared| *compared) {
+            compared += 1;
+        }
+    }
+
+    //Make sure the test isn't passing by skipping everything
+    assert!(
+        compared * 2 >= generated,
+        "only {} of {} modules could be compared",
+        compared,
+        generated
+    );
+}
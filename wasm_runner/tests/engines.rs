@@ -0,0 +1,127 @@
+//! Runs the same scripts on every compiler backend turned on with cargo features and checks they agree
+//!
+//! Metering happens in the module before it is compiled, so the injector has to count exactly the same
+//! instructions whichever compiler built the script. Needs the wasm32-unknown-unknown target to build scripts
+mod common;
+
+use common::{spinning_vm, wasm, COUNTING_SCRIPT};
+use script_api::Data;
+use wasm_runner::{
+    BudgetPolicy, CompilerBackend, MeteringBackend, TickErrorKind, VMConfig, VMError, WasmVM,
+};
+
+const TICKS: usize = 5;
+
+///What a tick did, in a form that can be compared across backends
+#[derive(Debug, PartialEq)]
+struct TickOutcome {
+    actions: String,
+    instructions: i32,
+    debug_text: String,
+}
+
+fn run_ticks(backend: CompilerBackend, metering: MeteringBackend) -> Vec<TickOutcome> {
+    let config = VMConfig::default()
+        .with_compiler(backend)
+        .with_metering(metering);
+    let mut vm: WasmVM<Data, u32> = WasmVM::from_wasm(wasm(COUNTING_SCRIPT), config)
+        .unwrap_or_else(|e| panic!("{:?} failed to load the script: {}", backend, e));
+
+    (0..TICKS)
+        .map(|tick| {
+            let report = vm.run_tick(Vec::default());
+            let instructions = report.instructions;
+            let debug_text = report.debug_output.clone();
+            let actions = report
+                .into_result()
+                .unwrap_or_else(|e| panic!("{:?} failed tick {}: {}", backend, tick, e));
+            TickOutcome {
+                actions: format!("{:?}", actions),
+                instructions,
+                debug_text,
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn default_backend_is_enabled() {
+    assert!(CompilerBackend::default().is_enabled());
+}
+
+#[test]
+fn disabled_backends_are_refused() {
+    for backend in [
+        CompilerBackend::Cranelift,
+        CompilerBackend::Singlepass,
+        CompilerBackend::Llvm,
+    ] {
+        if backend.is_enabled() {
+            continue;
+        }
+        let config = VMConfig::default().with_compiler(backend);
+        let error = WasmVM::<Data, u32>::from_wasm(wasm(COUNTING_SCRIPT), config)
+            .err()
+            .expect("disabled backend loaded a script");
+        assert!(matches!(
+            error.downcast_ref::<VMError>(),
+            Some(VMError::CompilerNotEnabled(refused)) if *refused == backend
+        ));
+    }
+}
+
+#[test]
+fn backends_agree_with_injected_metering() {
+    let backends = CompilerBackend::enabled();
+    let expected = run_ticks(backends[0], MeteringBackend::Injector);
+    for &backend in &backends[1..] {
+        assert_eq!(
+            run_ticks(backend, MeteringBackend::Injector),
+            expected,
+            "{:?} and {:?} disagree",
+            backend,
+            backends[0]
+        );
+    }
+}
+
+#[test]
+fn backends_agree_with_middleware_metering() {
+    let backends = CompilerBackend::enabled();
+    let expected = run_ticks(backends[0], MeteringBackend::Middleware);
+    for &backend in &backends[1..] {
+        let outcomes = run_ticks(backend, MeteringBackend::Middleware);
+        //The middleware counts operators while compiling, which is the same for every backend
+        assert_eq!(
+            outcomes, expected,
+            "{:?} and {:?} disagree",
+            backend, backends[0]
+        );
+    }
+}
+
+#[test]
+fn every_backend_stops_a_spinning_script() {
+    for backend in CompilerBackend::enabled() {
+        for metering in [MeteringBackend::Injector, MeteringBackend::Middleware] {
+            let config = VMConfig::default()
+                .with_compiler(backend)
+                .with_metering(metering)
+                .with_budget(BudgetPolicy::Fixed { per_tick: 100_000 });
+            let mut vm = spinning_vm(config);
+            let error = vm
+                .run_tick(Vec::default())
+                .into_result()
+                .err()
+                .unwrap_or_else(|| panic!("{:?} with {:?} let the script spin", backend, metering));
+            assert_eq!(
+                error.kind,
+                TickErrorKind::OutOfInstructions,
+                "{:?} with {:?} failed with {}",
+                backend,
+                metering,
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Actions and inputs crossing between the runner and scripts, and what happens when the two sides disagree
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+use script_api::{Data, ScriptAction};
+use wasm_runner::{compile, BudgetPolicy, TickErrorKind, TickOutcome, VMConfig, WasmVM};
+
+/// Emits as many actions as the tick number times 1000, and fails on the third tick after emitting
+const EMITTING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    ticks: u32,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { ticks: 0 }
+    }
+
+    pub fn run(&mut self) {
+        self.ticks += 1;
+        for _ in 0..self.ticks * 1000 {
+            action_one();
+        }
+        if self.ticks == 3 {
+            panic!("failed after emitting");
+        }
+    }
+}
+"#;
+
+const STRING_INPUT_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        let inputs: Vec<String> = read_inputs();
+        for input in inputs {
+            emit(input);
+        }
+    }
+}
+"#;
+
+const BYTE_OUTPUT_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        emit(7u8);
+    }
+}
+"#;
+
+#[test]
+fn every_tick_gets_only_its_own_actions() {
+    let wasm = compile(EMITTING_SCRIPT.to_string()).expect("Compile script");
+    let config = VMConfig::default().with_budget(BudgetPolicy::Fixed {
+        per_tick: 500_000_000,
+    });
+    let mut vm: WasmVM = WasmVM::from_wasm(&wasm, config).unwrap();
+
+    for tick in 1..=2 {
+        let actions = vm.run_tick(Vec::default()).into_result().unwrap();
+        assert_eq!(actions.len(), tick * 1000);
+        assert!(actions
+            .iter()
+            .all(|action| matches!(action, ScriptAction::ActionOne)));
+    }
+
+    //The failed tick's actions are thrown away rather than carried into the next one
+    let report = vm.run_tick(Vec::default());
+    assert!(report.actions.is_empty());
+    assert!(
+        matches!(report.outcome, TickOutcome::Failed(ref error) if error.kind == TickErrorKind::Panic)
+    );
+    let actions = vm.run_tick(Vec::default()).into_result().unwrap();
+    assert_eq!(actions.len(), 4000);
+}
+
+#[test]
+fn inputs_the_script_cant_decode_are_malformed_input() {
+    let wasm = compile(STRING_INPUT_SCRIPT.to_string()).expect("Compile script");
+    let mut vm: WasmVM<u8, String> = WasmVM::from_wasm(&wasm, VMConfig::default()).unwrap();
+
+    let error = vm.run_tick(vec![200]).into_result().unwrap_err();
+    assert_eq!(error.kind, TickErrorKind::MalformedData);
+    assert!(error.message.contains("input"), "{}", error.message);
+
+    //The VM is still usable afterwards
+    assert_eq!(
+        vm.run_tick(vec![]).into_result().unwrap(),
+        Vec::<String>::new()
+    );
+}
+
+#[test]
+fn actions_the_host_cant_decode_are_malformed_output() {
+    let wasm = compile(BYTE_OUTPUT_SCRIPT.to_string()).expect("Compile script");
+    let mut vm: WasmVM<Data, String> = WasmVM::from_wasm(&wasm, VMConfig::default()).unwrap();
+
+    for _ in 0..2 {
+        let error = vm.run_tick(vec![]).into_result().unwrap_err();
+        assert_eq!(error.kind, TickErrorKind::MalformedData);
+        assert!(error.message.contains("output"), "{}", error.message);
+    }
+
+    //The same actions read as the type the script emitted are fine
+    let mut vm: WasmVM<Data, u8> = WasmVM::from_wasm(&wasm, VMConfig::default()).unwrap();
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![7]);
+}
@@ -0,0 +1,110 @@
+//! Interrupting ticks from the host
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+mod common;
+
+use common::{counting_vm, spinning_vm, wasm};
+use wasm_runner::{
+    BudgetPolicy, HostFunctions, InterruptHandle, TickErrorKind, TickOutcome, VMConfig, WasmVM,
+};
+
+/// Has the host interrupt it and then finishes the tick straight away
+const SELF_INTERRUPTING_SCRIPT: &str = r#"
+use script_api::*;
+
+host_function!(fn interrupt_me());
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        interrupt_me();
+        emit(1u32);
+    }
+}
+"#;
+
+fn interruptible_config(per_tick: i32) -> VMConfig {
+    VMConfig::default()
+        .with_interrupts(true)
+        .with_interrupt_check_interval(1_000)
+        .with_budget(BudgetPolicy::Fixed { per_tick })
+}
+
+fn failure_kind(outcome: &TickOutcome) -> Option<TickErrorKind> {
+    match outcome {
+        TickOutcome::Failed(error) => Some(error.kind),
+        _ => None,
+    }
+}
+
+#[test]
+fn interrupt_between_ticks_stops_the_next_tick() {
+    let mut vm = counting_vm(interruptible_config(1_000_000));
+    assert_eq!(vm.run_tick(Vec::default()).outcome, TickOutcome::Completed);
+
+    vm.interrupt_handle().interrupt();
+    let report = vm.run_tick(Vec::default());
+    assert_eq!(
+        failure_kind(&report.outcome),
+        Some(TickErrorKind::Interrupted)
+    );
+    assert_eq!(report.instructions, 0);
+
+    //The interrupt only stops one tick
+    assert_eq!(vm.run_tick(Vec::default()).outcome, TickOutcome::Completed);
+}
+
+#[test]
+fn interrupt_stops_a_running_tick() {
+    let mut vm = spinning_vm(interruptible_config(i32::MAX));
+    let handle = vm.interrupt_handle();
+    let interrupter = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(50));
+        handle.interrupt();
+    });
+    let report = vm.run_tick(Vec::default());
+    interrupter.join().unwrap();
+    assert_eq!(
+        failure_kind(&report.outcome),
+        Some(TickErrorKind::Interrupted)
+    );
+}
+
+#[test]
+fn interrupt_a_tick_misses_stops_the_next_tick() {
+    let handle: Arc<Mutex<Option<InterruptHandle>>> = Arc::default();
+    let mut host_functions = HostFunctions::default();
+    let host_handle = handle.clone();
+    host_functions.register("host", "interrupt_me", &[], &[], move |_, _| {
+        if let Some(handle) = &*host_handle.lock().unwrap() {
+            handle.interrupt();
+        }
+        Ok(vec![])
+    });
+    //The whole tick fits in one slice, so the script never checks for the interrupt
+    let config = interruptible_config(1_000_000)
+        .with_interrupt_check_interval(1_000_000)
+        .with_host_functions(host_functions);
+    let mut vm: WasmVM<u32, u32> =
+        WasmVM::from_wasm(wasm(SELF_INTERRUPTING_SCRIPT), config).unwrap();
+    *handle.lock().unwrap() = Some(vm.interrupt_handle());
+
+    assert_eq!(vm.run_tick(Vec::default()).outcome, TickOutcome::Completed);
+    let report = vm.run_tick(Vec::default());
+    assert_eq!(
+        failure_kind(&report.outcome),
+        Some(TickErrorKind::Interrupted)
+    );
+    assert_eq!(report.instructions, 0);
+}
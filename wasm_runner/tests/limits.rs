@@ -0,0 +1,198 @@
+//! Scripts going over the limits on what they can use, and the errors they fail with
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+mod common;
+
+use common::wasm;
+use wasm_runner::{
+    HostFunctions, ResourceLimits, TickErrorKind, TickOutcome, VMConfig, WasmVM,
+    DEFAULT_MEMORY_LIMIT_PAGES, WASM_PAGE_SIZE,
+};
+
+/// Holds on to another block of memory for every input, each input is the block's size in KiB
+const HOARDING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    blocks: Vec<Vec<u8>>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { blocks: Vec::new() }
+    }
+
+    pub fn run(&mut self) {
+        let inputs: Vec<u32> = read_inputs();
+        for kib in inputs {
+            self.blocks.push(vec![1; kib as usize * 1024]);
+        }
+        emit(self.blocks.len() as u32);
+    }
+}
+"#;
+
+/// Takes four inputs: how many actions to emit, lines of debug text to write, host calls to make and KiB of
+/// memory to allocate for the tick
+const BUSY_SCRIPT: &str = r#"
+use script_api::*;
+
+host_function!(fn ping());
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        let inputs: Vec<u32> = read_inputs();
+        for action in 0..inputs[0] {
+            emit(action);
+        }
+        for line in 0..inputs[1] {
+            debug!("line {}", line);
+        }
+        for _ in 0..inputs[2] {
+            ping();
+        }
+        std::hint::black_box(vec![1u8; inputs[3] as usize * 1024]);
+    }
+}
+"#;
+
+fn hoarding_vm(config: VMConfig) -> WasmVM<u32, u32> {
+    WasmVM::from_wasm(wasm(HOARDING_SCRIPT), config).unwrap()
+}
+
+fn busy_vm(limits: ResourceLimits) -> WasmVM<u32, u32> {
+    let mut host_functions = HostFunctions::default();
+    host_functions.register("host", "ping", &[], &[], |_, _| Ok(vec![]));
+    let config = VMConfig::default()
+        .with_resource_limits(limits)
+        .with_host_functions(host_functions);
+    WasmVM::from_wasm(wasm(BUSY_SCRIPT), config).unwrap()
+}
+
+/// Runs a tick of the busy script under the limits that has to pass, then one that has to fail with the
+/// kind and message given
+fn check_limit(
+    limits: ResourceLimits,
+    under: Vec<u32>,
+    over: Vec<u32>,
+    kind: TickErrorKind,
+    message: &str,
+) {
+    let mut vm = busy_vm(limits);
+    let report = vm.run_tick(under);
+    assert!(report.is_ok(), "{:?}", report.outcome);
+
+    let report = vm.run_tick(over);
+    assert_eq!(
+        failure_kind(&report.outcome),
+        Some(kind),
+        "{:?}",
+        report.outcome
+    );
+    let TickOutcome::Failed(error) = &report.outcome else {
+        unreachable!()
+    };
+    assert!(error.message.contains(message), "{}", error.message);
+    assert_eq!(vm.last_tick_usage().limits, limits);
+}
+
+fn failure_kind(outcome: &TickOutcome) -> Option<TickErrorKind> {
+    match outcome {
+        TickOutcome::Failed(error) => Some(error.kind),
+        _ => None,
+    }
+}
+
+#[test]
+fn scripts_are_capped_by_default() {
+    let vm = hoarding_vm(VMConfig::default());
+    assert_eq!(vm.memory_limit_pages(), Some(DEFAULT_MEMORY_LIMIT_PAGES));
+}
+
+#[test]
+fn growing_past_the_memory_limit_fails() {
+    let limit_bytes = 4 * 1024 * 1024;
+    let mut vm = hoarding_vm(VMConfig::default().with_memory_limit_bytes(limit_bytes));
+    assert_eq!(
+        vm.memory_limit_pages(),
+        Some((limit_bytes / WASM_PAGE_SIZE) as u32)
+    );
+
+    //Well under the limit
+    assert_eq!(vm.run_tick(vec![512]).into_result().unwrap(), vec![1]);
+
+    let report = vm.run_tick(vec![8 * 1024]);
+    assert_eq!(
+        failure_kind(&report.outcome),
+        Some(TickErrorKind::MemoryLimit),
+        "{:?}",
+        report.outcome
+    );
+    let TickOutcome::Failed(error) = &report.outcome else {
+        unreachable!()
+    };
+    assert!(error.message.contains("more memory"), "{}", error.message);
+    assert!(vm.memory_usage() <= limit_bytes);
+}
+
+#[test]
+fn emitting_too_many_actions_fails() {
+    check_limit(
+        ResourceLimits {
+            actions: Some(10),
+            ..ResourceLimits::default()
+        },
+        vec![10, 0, 0, 0],
+        vec![11, 0, 0, 0],
+        TickErrorKind::ResourceLimit,
+        "more actions",
+    );
+}
+
+#[test]
+fn writing_too_much_debug_text_fails() {
+    check_limit(
+        ResourceLimits {
+            debug_text_bytes: Some(100),
+            ..ResourceLimits::default()
+        },
+        vec![0, 5, 0, 0],
+        vec![0, 50, 0, 0],
+        TickErrorKind::ResourceLimit,
+        "more debug text",
+    );
+}
+
+#[test]
+fn making_too_many_host_calls_fails() {
+    check_limit(
+        ResourceLimits {
+            host_calls: Some(10),
+            ..ResourceLimits::default()
+        },
+        vec![0, 0, 10, 0],
+        vec![0, 0, 11, 0],
+        TickErrorKind::ResourceLimit,
+        "more host calls",
+    );
+}
+
+#[test]
+fn growing_memory_too_fast_fails() {
+    check_limit(
+        ResourceLimits {
+            memory_grow_bytes: Some(1024 * 1024),
+            ..ResourceLimits::default()
+        },
+        vec![0, 0, 0, 64],
+        vec![0, 0, 0, 4 * 1024],
+        TickErrorKind::MemoryLimit,
+        "grew its memory",
+    );
+}
@@ -0,0 +1,87 @@
+//! Checks the injector and the middleware agree on what instructions used and running out mean
+//!
+//! The two backends charge the same cost for each instruction but check it at different points, so the
+//! exact counts can differ. Needs the wasm32-unknown-unknown target to build scripts
+mod common;
+
+use common::{counting_vm, spinning_vm};
+use wasm_runner::{BudgetPolicy, MeteringBackend, TickErrorKind, VMConfig};
+
+const BACKENDS: [MeteringBackend; 2] = [MeteringBackend::Injector, MeteringBackend::Middleware];
+
+fn config(metering: MeteringBackend, per_tick: i32) -> VMConfig {
+    VMConfig::default()
+        .with_metering(metering)
+        .with_budget(BudgetPolicy::Fixed { per_tick })
+}
+
+///Instructions each of the first few ticks of the counting script used
+fn counting_ticks(metering: MeteringBackend, per_tick: i32) -> Vec<i32> {
+    let mut vm = counting_vm(config(metering, per_tick));
+    (0..3)
+        .map(|_| {
+            let report = vm.run_tick(Vec::default());
+            assert!(report.is_ok(), "{:?}: {:?}", metering, report.outcome);
+            assert_eq!(report.instructions, vm.last_tick_usage().instructions);
+            assert_eq!(report.instructions, vm.get_instructions_used().unwrap());
+            report.instructions
+        })
+        .collect()
+}
+
+#[test]
+fn instructions_used_dont_depend_on_the_allowance() {
+    for metering in BACKENDS {
+        let small = counting_ticks(metering, 1_000_000);
+        let large = counting_ticks(metering, 50_000_000);
+        assert_eq!(small, large, "{:?}", metering);
+        //The script does more work every tick
+        assert!(
+            small.windows(2).all(|pair| pair[0] < pair[1]),
+            "{:?}: {:?}",
+            metering,
+            small
+        );
+    }
+}
+
+#[test]
+fn running_out_uses_the_whole_allowance() {
+    let per_tick = 100_000;
+    for metering in BACKENDS {
+        let mut vm = spinning_vm(config(metering, per_tick));
+        for _ in 0..2 {
+            let report = vm.run_tick(Vec::default());
+            let error = report.clone().into_result().unwrap_err();
+            assert_eq!(
+                error.kind,
+                TickErrorKind::OutOfInstructions,
+                "{:?}",
+                metering
+            );
+            assert_eq!(report.instructions, per_tick, "{:?}", metering);
+            assert_eq!(
+                vm.get_instructions_used().unwrap(),
+                per_tick,
+                "{:?}",
+                metering
+            );
+        }
+    }
+}
+
+#[test]
+fn backends_count_about_the_same() {
+    let injector = counting_ticks(MeteringBackend::Injector, 1_000_000);
+    let middleware = counting_ticks(MeteringBackend::Middleware, 1_000_000);
+    for (injected, metered) in injector.iter().zip(&middleware) {
+        //The injector charges a whole block when it is entered, even if it branches out part way through,
+        //and the middleware also counts the functions the runner adds to the module
+        assert!(
+            *injected < metered * 2 && *metered < injected * 2,
+            "injector {:?} middleware {:?}",
+            injector,
+            middleware
+        );
+    }
+}
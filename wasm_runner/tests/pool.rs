@@ -0,0 +1,178 @@
+//! Running many scripts through a ScriptPool
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+mod common;
+
+use common::wasm;
+use wasm_runner::{
+    BudgetPolicy, HostFunctions, ScriptId, ScriptPool, ScriptTemplate, TickErrorKind, TickOutcome,
+    VMConfig, WasmVM,
+};
+use wasmer::Type;
+
+/// Tells the host each input it gets, spins for a while on it and emits a running total.
+/// An input of 0 makes it panic
+const WORKING_SCRIPT: &str = r#"
+use script_api::*;
+
+host_function!(fn record(value: i32));
+
+pub struct Script {
+    total: u32,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { total: 0 }
+    }
+
+    pub fn run(&mut self) {
+        let inputs: Vec<u32> = read_inputs();
+        for input in inputs {
+            if input == 0 {
+                panic!("asked to fail");
+            }
+            record(input as i32);
+            let mut spin = std::hint::black_box(0u32);
+            for i in 0..input * 1000 {
+                spin = std::hint::black_box(spin.wrapping_add(i));
+            }
+            self.total = self.total.wrapping_add(input).wrapping_add(spin % 7);
+        }
+        emit(self.total);
+    }
+}
+"#;
+
+/// Everything about a tick that should be the same whatever thread ran it
+type Outcomes = Vec<(ScriptId, Vec<u32>, i32, TickOutcome)>;
+
+fn template(budget: BudgetPolicy, recorded: &Arc<Mutex<Vec<i32>>>) -> Arc<ScriptTemplate> {
+    let recorded = recorded.clone();
+    let mut host_functions = HostFunctions::default();
+    host_functions.register("host", "record", &[Type::I32], &[], move |_, args| {
+        recorded
+            .lock()
+            .unwrap()
+            .push(args[0].i32().unwrap_or_default());
+        Ok(vec![])
+    });
+    let config = VMConfig::default()
+        .with_budget(budget)
+        .with_host_functions(host_functions);
+    ScriptTemplate::from_wasm(wasm(WORKING_SCRIPT), config).unwrap()
+}
+
+fn pool(threads: usize, recorded: &Arc<Mutex<Vec<i32>>>) -> ScriptPool<u32, u32> {
+    let normal = template(BudgetPolicy::default(), recorded);
+    let small = template(BudgetPolicy::Fixed { per_tick: 50_000 }, recorded);
+
+    let mut pool = ScriptPool::new(threads);
+    for (template, priority) in [
+        (&normal, 0),
+        (&normal, 5),
+        (&small, 1),
+        (&normal, -3),
+        (&normal, 5),
+        (&normal, 2),
+    ] {
+        let vm: WasmVM<u32, u32> = WasmVM::from_template(template).unwrap();
+        pool.insert(vm, priority);
+    }
+    pool
+}
+
+/// Script 1 fails on the second tick and script 2 asks for more work than its budget allows
+fn inputs(pool: &ScriptPool<u32, u32>, tick: u32) -> BTreeMap<ScriptId, Vec<u32>> {
+    pool.ids()
+        .enumerate()
+        .map(|(index, id)| {
+            let input = match (index, tick) {
+                (1, 1) => vec![0],
+                (2, _) => vec![100],
+                _ => vec![index as u32 + tick + 1, tick + 1],
+            };
+            (id, input)
+        })
+        .collect()
+}
+
+fn run(threads: usize) -> Vec<Outcomes> {
+    let recorded = Arc::new(Mutex::new(vec![]));
+    let mut pool = pool(threads, &recorded);
+    (0..4)
+        .map(|tick| {
+            let inputs = inputs(&pool, tick);
+            pool.run_tick(inputs)
+                .into_iter()
+                .map(|result| {
+                    let report = result.report;
+                    (
+                        result.id,
+                        report.actions,
+                        report.instructions,
+                        report.outcome,
+                    )
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn thread_count_does_not_change_results() {
+    let single = run(1);
+    assert_eq!(run(8), single);
+
+    let kinds: Vec<Option<TickErrorKind>> = single[1]
+        .iter()
+        .map(|(_, _, _, outcome)| match outcome {
+            TickOutcome::Failed(error) => Some(error.kind),
+            _ => None,
+        })
+        .collect();
+    //Only the failing and starved scripts fail, the rest carry on
+    assert_eq!(
+        kinds,
+        vec![
+            None,
+            Some(TickErrorKind::Panic),
+            Some(TickErrorKind::OutOfInstructions),
+            None,
+            None,
+            None,
+        ]
+    );
+    for tick in &single {
+        assert_eq!(tick.len(), 6);
+        assert!(tick.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    }
+    //The script that failed runs normally again on the next tick
+    assert_eq!(single[2][1].3, TickOutcome::Completed);
+}
+
+#[test]
+fn higher_priority_scripts_start_first() {
+    let recorded = Arc::new(Mutex::new(vec![]));
+    let mut pool = pool(1, &recorded);
+    let inputs: BTreeMap<ScriptId, Vec<u32>> = pool
+        .ids()
+        .enumerate()
+        .map(|(index, id)| (id, vec![index as u32 + 1]))
+        .collect();
+    pool.run_tick(inputs.clone());
+    //Priorities 5, 5, 2, 1, 0, -3 with ties in id order
+    assert_eq!(*recorded.lock().unwrap(), vec![2, 5, 6, 3, 1, 4]);
+
+    //Changing a priority changes the order from the next tick
+    recorded.lock().unwrap().clear();
+    let last = pool.ids().last().unwrap();
+    assert!(pool.set_priority(last, 10));
+    pool.run_tick(inputs);
+    assert_eq!(*recorded.lock().unwrap(), vec![6, 2, 5, 3, 1, 4]);
+}
@@ -0,0 +1,141 @@
+//! Preemptible scripts pausing when they run out of instructions and picking up again on later ticks
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts and wasm-opt for the asyncify pass
+mod common;
+
+use common::wasm;
+use wasm_runner::{compile, BudgetPolicy, TickErrorKind, TickOutcome, VMConfig, WasmVM};
+
+/// Takes a recursion depth and a loop count as inputs, recurses that deep and then loops that many times
+/// at the bottom, emitting a total of the depths and loop counters once it gets back out
+const DESCENDING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        let inputs: Vec<u32> = read_inputs();
+        emit(descend(inputs[0], inputs[1] as u64));
+    }
+}
+
+fn descend(depth: u32, spin: u64) -> u64 {
+    if depth == 0 {
+        let mut total = 0u64;
+        for i in 0..std::hint::black_box(spin) {
+            total = std::hint::black_box(total.wrapping_add(i));
+        }
+        return total;
+    }
+    std::hint::black_box(descend(std::hint::black_box(depth - 1), spin)).wrapping_add(depth as u64)
+}
+"#;
+
+/// More ticks than any of the scripts here should need
+const MAX_TICKS: usize = 1_000;
+
+fn preemptible_vm(per_tick: i32) -> WasmVM<u32, u64> {
+    let config = VMConfig::default()
+        .with_preemption(true)
+        .with_budget(BudgetPolicy::Fixed { per_tick });
+    WasmVM::from_wasm(wasm(DESCENDING_SCRIPT), config).unwrap()
+}
+
+/// What the descending script emits for its inputs
+fn descent_total(depth: u32, spin: u64) -> u64 {
+    (0..spin).sum::<u64>() + (1..=depth as u64).sum::<u64>()
+}
+
+/// Runs ticks until the script stops pausing, checking every paused tick on the way, and returns how many
+/// ticks it paused for and the last tick's outcome and actions
+fn run_to_completion(
+    vm: &mut WasmVM<u32, u64>,
+    inputs: Vec<u32>,
+) -> (usize, TickOutcome, Vec<u64>) {
+    for suspended in 0..MAX_TICKS {
+        let report = vm.run_tick(inputs.clone());
+        if report.outcome != TickOutcome::Suspended {
+            return (suspended, report.outcome, report.actions);
+        }
+        assert!(vm.is_suspended());
+        assert!(report.actions.is_empty(), "{:?}", report.actions);
+    }
+    panic!("script was still paused after {} ticks", MAX_TICKS);
+}
+
+fn failure_kind(outcome: &TickOutcome) -> Option<TickErrorKind> {
+    match outcome {
+        TickOutcome::Failed(error) => Some(error.kind),
+        _ => None,
+    }
+}
+
+#[test]
+fn loop_spans_several_ticks() {
+    let mut vm = preemptible_vm(100_000);
+    let (suspended, outcome, actions) = run_to_completion(&mut vm, vec![0, 200_000]);
+    assert!(suspended >= 2, "only paused for {} ticks", suspended);
+    assert_eq!(outcome, TickOutcome::Completed);
+    assert_eq!(actions, vec![descent_total(0, 200_000)]);
+    assert!(!vm.is_suspended());
+
+    //The next tick starts the script from the top again
+    let (_, outcome, actions) = run_to_completion(&mut vm, vec![3, 10]);
+    assert_eq!(outcome, TickOutcome::Completed);
+    assert_eq!(actions, vec![descent_total(3, 10)]);
+}
+
+#[test]
+fn trap_while_unwinding_resets_asyncify() {
+    let mut vm = preemptible_vm(100_000);
+
+    //Runs out deep enough in the recursion that the stack doesn't fit in the asyncify buffer
+    let report = vm.run_tick(vec![20_000, 0]);
+    assert!(
+        matches!(report.outcome, TickOutcome::Failed(_)),
+        "{:?}",
+        report.outcome
+    );
+    assert!(!vm.is_suspended());
+
+    //The failed unwind doesn't carry over into the next tick
+    let (suspended, outcome, actions) = run_to_completion(&mut vm, vec![0, 200_000]);
+    assert!(suspended >= 2, "only paused for {} ticks", suspended);
+    assert_eq!(outcome, TickOutcome::Completed);
+    assert_eq!(actions, vec![descent_total(0, 200_000)]);
+    assert!(!vm.is_suspended());
+}
+
+#[test]
+fn preemptible_block_over_the_budget_fails() {
+    //One straight line block of thousands of instructions, more than a whole tick allows
+    let mut code = String::from(
+        "use script_api::*;\npub struct Script {}\nimpl Script {\n    pub fn new() -> Self { Self {} }\n    pub fn run(&mut self) {\n        let mut acc = std::hint::black_box(1u64);\n",
+    );
+    for i in 0..3_000 {
+        code.push_str(&format!("        acc = acc.wrapping_mul(31) ^ {};\n", i));
+    }
+    code.push_str("        std::hint::black_box(acc);\n    }\n}\n");
+
+    let config = VMConfig::default()
+        .with_preemption(true)
+        .with_budget(BudgetPolicy::Fixed { per_tick: 5_000 });
+    let wasm = compile(code).expect("Compile script");
+    let mut vm: WasmVM = WasmVM::from_wasm(&wasm, config).unwrap();
+    for tick in 0..3 {
+        let report = vm.run_tick(Vec::default());
+        assert_eq!(
+            failure_kind(&report.outcome),
+            Some(TickErrorKind::OutOfInstructions),
+            "tick {} ended with {:?}",
+            tick,
+            report.outcome
+        );
+        assert!(!vm.is_suspended());
+    }
+}
@@ -0,0 +1,142 @@
+//! Reloading a running script with new code, with and without carrying its state over
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+mod common;
+
+use common::{counting_vm, growing_vm, wasm, COUNTING_SCRIPT, GROWING_SCRIPT};
+use script_api::Data;
+use wasm_runner::{VMConfig, VMError, WasmVM};
+
+/// The next version of common::COUNTING_SCRIPT, which counts in hundreds from where the old one was
+const HUNDREDS_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {
+    ticks: u32,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self { ticks: 0 }
+    }
+
+    pub fn run(&mut self) {
+        self.ticks += 1;
+        emit(self.ticks * 100);
+    }
+}
+
+impl ScriptState for Script {
+    fn save_state(&self) -> Vec<u8> {
+        self.ticks.to_le_bytes().to_vec()
+    }
+
+    fn load_state(state: &[u8]) -> Option<Self> {
+        Some(Self {
+            ticks: u32::from_le_bytes(state.try_into().ok()?),
+        })
+    }
+}
+
+reload_hooks!();
+"#;
+
+/// A version that can't make sense of the old one's state
+const REJECTING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        emit(0u32);
+    }
+}
+
+impl ScriptState for Script {
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(_state: &[u8]) -> Option<Self> {
+        None
+    }
+}
+
+reload_hooks!();
+"#;
+
+fn counted_to_three() -> WasmVM<Data, u32> {
+    let mut vm = counting_vm(VMConfig::default());
+    for tick in 1..=3 {
+        assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![tick]);
+    }
+    vm
+}
+
+#[test]
+fn state_is_carried_over() {
+    let mut vm = counted_to_three();
+    let old_hash = *vm.module_hash();
+
+    vm.reload_wasm(wasm(HUNDREDS_SCRIPT)).unwrap();
+    assert_ne!(*vm.module_hash(), old_hash);
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![400]);
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![500]);
+}
+
+#[test]
+fn rejected_state_keeps_the_old_script() {
+    let mut vm = counted_to_three();
+    let old_hash = *vm.module_hash();
+
+    let error = vm.reload_wasm(wasm(REJECTING_SCRIPT)).unwrap_err();
+    assert!(
+        matches!(
+            error.downcast_ref::<VMError>(),
+            Some(VMError::StateLoadFailed(_))
+        ),
+        "{}",
+        error
+    );
+
+    //Still the old script, with its count where it was
+    assert_eq!(*vm.module_hash(), old_hash);
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![4]);
+
+    //And it can still be reloaded with code that takes its state
+    vm.reload_wasm(wasm(HUNDREDS_SCRIPT)).unwrap();
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![500]);
+}
+
+#[test]
+fn state_with_nowhere_to_go_keeps_the_old_script() {
+    let mut vm = counted_to_three();
+    let old_hash = *vm.module_hash();
+
+    //The growing script has no hooks, so the count would be lost
+    let error = vm.reload_wasm(wasm(GROWING_SCRIPT)).unwrap_err();
+    match error.downcast_ref::<VMError>() {
+        Some(VMError::StateLoadFailed(reason)) => {
+            assert!(reason.contains("load_state"), "{}", reason)
+        }
+        _ => panic!("expected StateLoadFailed, got {}", error),
+    }
+    assert_eq!(*vm.module_hash(), old_hash);
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![4]);
+}
+
+#[test]
+fn scripts_without_state_start_over() {
+    let mut vm = growing_vm(VMConfig::default());
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![1]);
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![2]);
+
+    //Nothing was saved, so the counting script starts from Script::new
+    vm.reload_wasm(wasm(COUNTING_SCRIPT)).unwrap();
+    assert_eq!(vm.run_tick(vec![]).into_result().unwrap(), vec![1]);
+}
@@ -0,0 +1,80 @@
+//! Scripts reading how many instructions they have left with script_api::remaining_instructions
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+mod common;
+
+use common::wasm;
+use wasm_runner::{BudgetPolicy, MeteringBackend, VMConfig, WasmVM};
+
+/// Reads what it has left before and after a loop and emits both, as late in the tick as it can
+const CHECKING_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        let before = remaining_instructions();
+        let mut total = std::hint::black_box(0u64);
+        for i in 0..std::hint::black_box(20_000u64) {
+            total = std::hint::black_box(total.wrapping_add(i));
+        }
+        let after = remaining_instructions();
+        emit((before, after));
+    }
+}
+"#;
+
+const PER_TICK: i32 = 1_000_000;
+/// Most instructions the script can run after its last read, emitting and finishing the tick
+const TAIL_INSTRUCTIONS: i32 = 10_000;
+
+fn check_remaining(config: VMConfig) {
+    let config = config.with_budget(BudgetPolicy::Fixed { per_tick: PER_TICK });
+    let mut vm: WasmVM<u32, (u32, u32)> = WasmVM::from_wasm(wasm(CHECKING_SCRIPT), config).unwrap();
+    for _ in 0..2 {
+        let report = vm.run_tick(Vec::default());
+        assert!(report.is_ok(), "{:?}", report.outcome);
+        let (before, after) = report.actions[0];
+        let (before, after) = (before as i32, after as i32);
+
+        assert!(before <= PER_TICK, "{} left out of {}", before, PER_TICK);
+        //The loop alone is tens of thousands of instructions
+        assert!(before - after > 20_000, "{} then {}", before, after);
+
+        //What was left at the end of the loop matches what the tick used, apart from the last few instructions
+        let used_by_then = PER_TICK - after;
+        let used = vm.get_instructions_used().unwrap();
+        assert_eq!(used, report.instructions);
+        assert!(
+            used_by_then <= used && used - used_by_then < TAIL_INSTRUCTIONS,
+            "{} used by the last read, {} by the whole tick",
+            used_by_then,
+            used
+        );
+    }
+}
+
+#[test]
+fn remaining_instructions_drop_with_the_injector() {
+    check_remaining(VMConfig::default().with_metering(MeteringBackend::Injector));
+}
+
+#[test]
+fn remaining_instructions_drop_with_the_middleware() {
+    check_remaining(VMConfig::default().with_metering(MeteringBackend::Middleware));
+}
+
+#[test]
+fn remaining_instructions_count_what_is_held_back() {
+    //Instructions are handed out a slice at a time, the ones held back still count as remaining
+    check_remaining(
+        VMConfig::default()
+            .with_interrupts(true)
+            .with_interrupt_check_interval(1_000),
+    );
+}
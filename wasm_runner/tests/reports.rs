@@ -0,0 +1,104 @@
+//! What run_tick reports for each way a tick can end, and that reports survive being sent on as telemetry
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+mod common;
+
+use common::wasm;
+use wasm_runner::{TickErrorKind, TickOutcome, TickReport, VMConfig, WasmVM};
+
+/// Does whatever its input says
+const OBEDIENT_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        let inputs: Vec<String> = read_inputs();
+        for input in inputs {
+            match input.as_str() {
+                "panic" => panic!("told to panic"),
+                "spin" => loop {
+                    std::hint::black_box(());
+                },
+                _ => {
+                    debug!("got {}", input);
+                    emit(input.len() as u32);
+                }
+            }
+        }
+    }
+}
+"#;
+
+fn vm() -> WasmVM<String, u32> {
+    let config = VMConfig::default().with_buffer_limits(1000, 1024 * 1024);
+    WasmVM::from_wasm(wasm(OBEDIENT_SCRIPT), config).unwrap()
+}
+
+/// Sends the report through bincode and checks nothing was lost
+fn round_trip(report: &TickReport<u32>) {
+    let bytes = bincode::serialize(report).unwrap();
+    let decoded: TickReport<u32> = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(format!("{:?}", decoded), format!("{:?}", report));
+}
+
+fn failure_kind(report: &TickReport<u32>) -> TickErrorKind {
+    match &report.outcome {
+        TickOutcome::Failed(error) => error.kind,
+        outcome => panic!("tick didn't fail: {:?}", outcome),
+    }
+}
+
+#[test]
+fn successful_tick() {
+    let mut vm = vm();
+    let report = vm.run_tick(vec!["hello".to_string()]);
+    assert_eq!(report.outcome, TickOutcome::Completed);
+    assert!(report.is_ok());
+    assert_eq!(report.actions, vec![5]);
+    assert_eq!(report.debug_output, "got hello\n");
+    assert!(report.instructions > 0);
+    assert!(report.memory_pages > 0);
+    round_trip(&report);
+}
+
+#[test]
+fn panicking_tick() {
+    let mut vm = vm();
+    let report = vm.run_tick(vec!["panic".to_string()]);
+    assert_eq!(failure_kind(&report), TickErrorKind::Panic);
+    assert!(report.actions.is_empty());
+    let TickOutcome::Failed(error) = &report.outcome else {
+        unreachable!()
+    };
+    assert!(error.message.contains("told to panic"), "{}", error.message);
+    round_trip(&report);
+}
+
+#[test]
+fn spinning_tick() {
+    let mut vm = vm();
+    let report = vm.run_tick(vec!["spin".to_string()]);
+    assert_eq!(failure_kind(&report), TickErrorKind::OutOfInstructions);
+    assert!(report.instructions > 0);
+    round_trip(&report);
+}
+
+#[test]
+fn oversized_input() {
+    let mut vm = vm();
+    let report = vm.run_tick(vec!["x".repeat(2000)]);
+    assert_eq!(failure_kind(&report), TickErrorKind::ResourceLimit);
+    //Refused before the script ran
+    assert_eq!(report.instructions, 0);
+    round_trip(&report);
+
+    //The VM carries on with inputs that fit
+    let report = vm.run_tick(vec!["fits".to_string()]);
+    assert_eq!(report.into_result().unwrap(), vec![4]);
+}
@@ -0,0 +1,96 @@
+//! Snapshotting scripts between ticks and putting them back
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+mod common;
+
+use common::{counting_vm, growing_vm, wasm};
+use script_api::Data;
+use wasm_runner::{TickErrorKind, VMConfig, VMError, VmSnapshot, WasmVM};
+
+const OTHER_SCRIPT: &str = r#"
+use script_api::*;
+
+pub struct Script {}
+
+impl Script {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    pub fn run(&mut self) {
+        emit(0u32);
+    }
+}
+"#;
+
+/// Actions, instructions and debug text of the next few ticks
+fn run_ticks(vm: &mut WasmVM<Data, u32>, ticks: usize) -> Vec<(Vec<u32>, i32, String)> {
+    (0..ticks)
+        .map(|_| {
+            let report = vm.run_tick(vec![]);
+            assert!(report.is_ok(), "{:?}", report.outcome);
+            (report.actions, report.instructions, report.debug_output)
+        })
+        .collect()
+}
+
+#[test]
+fn restoring_replays_the_same_ticks() {
+    let mut vm = counting_vm(VMConfig::default());
+    run_ticks(&mut vm, 2);
+    let snapshot = vm.snapshot().unwrap();
+
+    let expected = run_ticks(&mut vm, 3);
+    vm.restore(&snapshot).unwrap();
+    assert_eq!(run_ticks(&mut vm, 3), expected);
+
+    //A fresh VM restored from the serialized snapshot carries on the same way too
+    let bytes = snapshot.to_bytes().unwrap();
+    let restored = VmSnapshot::from_bytes(&bytes).unwrap();
+    assert_eq!(restored, snapshot);
+    let mut other_vm = counting_vm(VMConfig::default());
+    other_vm.restore(&restored).unwrap();
+    assert_eq!(run_ticks(&mut other_vm, 3), expected);
+}
+
+#[test]
+fn snapshots_from_another_module_are_refused() {
+    let mut vm = counting_vm(VMConfig::default());
+    run_ticks(&mut vm, 1);
+    let mut other_vm: WasmVM<Data, u32> =
+        WasmVM::from_wasm(wasm(OTHER_SCRIPT), VMConfig::default()).unwrap();
+    let snapshot = other_vm.snapshot().unwrap();
+    assert_ne!(snapshot.module_hash(), vm.module_hash());
+
+    let error = vm.restore(&snapshot).unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<VMError>(),
+        Some(VMError::SnapshotModuleMismatch)
+    ));
+    //The refused snapshot left the script as it was
+    assert!(run_ticks(&mut vm, 1)[0].2.starts_with("tick 2 "));
+}
+
+#[test]
+fn restoring_after_memory_grew() {
+    let mut vm = growing_vm(VMConfig::default().with_interrupts(true));
+    let handle = vm.interrupt_handle();
+    run_ticks(&mut vm, 1);
+    let snapshot = vm.snapshot().unwrap();
+    let pages = vm.memory_pages();
+
+    let expected = run_ticks(&mut vm, 4);
+    assert!(vm.memory_pages() > pages);
+    vm.restore(&snapshot).unwrap();
+    assert_eq!(vm.memory_pages(), pages);
+    assert_eq!(vm.snapshot().unwrap(), snapshot);
+    assert_eq!(run_ticks(&mut vm, 4), expected);
+
+    //The script got a new instance but the handle given out before still reaches it
+    handle.interrupt();
+    let report = vm.run_tick(vec![]);
+    assert_eq!(
+        report.into_result().unwrap_err().kind,
+        TickErrorKind::Interrupted
+    );
+}
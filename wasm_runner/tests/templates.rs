@@ -0,0 +1,34 @@
+//! VMs made from one compiled ScriptTemplate share the module but nothing the script does
+//!
+//! Needs the wasm32-unknown-unknown target to build scripts
+use std::sync::Arc;
+
+mod common;
+
+use common::{wasm, GROWING_SCRIPT};
+use script_api::Data;
+use wasm_runner::{ScriptTemplate, VMConfig, WasmVM};
+
+#[test]
+fn vms_from_one_template_are_isolated() {
+    let template = ScriptTemplate::from_wasm(wasm(GROWING_SCRIPT), VMConfig::default()).unwrap();
+    let mut first: WasmVM<Data, u32> = WasmVM::from_template(&template).unwrap();
+    let mut second: WasmVM<Data, u32> = WasmVM::from_template(&template).unwrap();
+
+    assert!(Arc::ptr_eq(first.template(), second.template()));
+    assert_eq!(first.module_hash(), second.module_hash());
+    assert_eq!(first.module_hash(), template.module_hash());
+
+    let second_before = second.snapshot().unwrap();
+    let second_pages = second.memory_pages();
+    for tick in 1..=3 {
+        assert_eq!(first.run_tick(vec![]).into_result().unwrap(), vec![tick]);
+    }
+    assert!(first.memory_pages() > second_pages);
+
+    //Nothing the first VM did reached the second's memory or globals
+    assert_eq!(second.memory_pages(), second_pages);
+    assert_eq!(second.snapshot().unwrap(), second_before);
+    assert_eq!(second.run_tick(vec![]).into_result().unwrap(), vec![1]);
+    assert_eq!(first.run_tick(vec![]).into_result().unwrap(), vec![4]);
+}